@@ -1,7 +1,7 @@
 // @ts-check
 
 import loader from "./lib/wgpudev.js";
-import {run} from "./lib/wgpudev.js";
+import {run, stats} from "./lib/wgpudev.js";
 
 async function main() {
     await loader();
@@ -9,21 +9,32 @@ async function main() {
 
     const runButton = /** @type {HTMLButtonElement} */ (document.getElementById("run"));
     const isingButton = /** @type {HTMLButtonElement} */ (document.getElementById("ising"));
+    const statsButton = /** @type {HTMLButtonElement} */ (document.getElementById("stats"));
     const circuitTextArea = /** @type {HTMLTextAreaElement} */ (document.getElementById("circuit"));
+    const shotsInput = /** @type {HTMLInputElement} */ (document.getElementById("shots"));
     const outputCode = /** @type {HTMLElement} */ (document.getElementById("output"));
 
     runButton.addEventListener("click", async () => {
         // Get the circuit from the textarea
         const circuitText = circuitTextArea.value;
+        const shots = parseInt(shotsInput.value) || 0;
 
         // Start a performance timer
         const startTime = performance.now();
-        const result = await run(circuitText);
+        const result = await run(circuitText, shots > 0 ? shots : undefined);
         const endTime = performance.now();
         //console.log("Results are ", result)
         //console.log(`Circuit executed in ${(endTime - startTime)} milliseconds`);
 
         var output = `Runtime: ${(endTime - startTime).toFixed(2)} milliseconds\n\n`;
+        if (shots > 0) {
+            // Bitstrings and their counts, most frequent first
+            for (const [bits, count] of result) {
+                output += `${bits}: ${count}\n`;
+            }
+            outputCode.innerHTML = output;
+            return;
+        }
         for (const [entry_idx, probability] of result) {
             // Convert the bits in entry_idx to a binary string
             if (probability < 0.01) {
@@ -37,6 +48,10 @@ async function main() {
         outputCode.innerHTML = output;
     });
 
+    statsButton.addEventListener("click", () => {
+        outputCode.textContent = stats(circuitTextArea.value);
+    });
+
     isingButton.addEventListener("click", async () => {
         // Fetch the Ising circuit file from ./src/ising5x5.crc and load into the textarea
         const response = await fetch("./src/ising5x5.crc");
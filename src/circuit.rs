@@ -1,14 +1,64 @@
 #![allow(unused)]
 
 use wgpu::{Buffer, BufferDescriptor, BufferUsages, Device};
-use crate::shader_types::{Op};
+use crate::modifiers::{controlled_ops, inverse_ops};
+use crate::shader_types::{Complex32, Op};
 
 // Small helper enum for QIR arg parsing
 enum ParsedArg { U32(u32), F32(f32) }
 
+// Modifiers that may prefix a .crc op or block. `ctrl q` controls ops as defined here, phase included,
+// so `ctrl 0 rz (a) 1` is `cp (a) 0 1` rather than `crz (a) 0 1` (see Circuit::controlled).
+enum Modifier { Inv, Ctrl(u32) }
+
+// Apply modifiers to the ops they prefix, innermost (rightmost) first
+fn apply_modifiers(modifiers: &[Modifier], mut body: Vec<Op>) -> Result<Vec<Op>, String> {
+    for modifier in modifiers.iter().rev() {
+        body = match modifier {
+            Modifier::Inv => inverse_ops(&body)?,
+            Modifier::Ctrl(ctrl) => controlled_ops(&body, *ctrl)?,
+        };
+    }
+    Ok(body)
+}
+
+// Parse a complex number like "0.5", "-i", "2.5e-1j" or "0.5-0.5i"
+fn parse_complex(s: &str) -> Option<Complex32> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let Some(imag) = s.strip_suffix(['i', 'j']) else {
+        return s.parse::<f32>().ok().map(|re| Complex32::new(re, 0.0));
+    };
+    // Split before the sign of the imaginary part, skipping a leading sign and exponent signs
+    let split = imag.char_indices()
+        .rev()
+        .find(|&(idx, c)| idx > 0 && (c == '+' || c == '-') && !imag[..idx].ends_with(['e', 'E']))
+        .map(|(idx, _)| idx);
+    let (re, im) = match split {
+        Some(idx) => (imag[..idx].parse::<f32>().ok()?, &imag[idx..]),
+        None => (0.0, imag),
+    };
+    let im = match im {
+        "" | "+" => 1.0,
+        "-" => -1.0,
+        _ => im.parse::<f32>().ok()?,
+    };
+    Some(Complex32::new(re, im))
+}
+
+/// Largest deviation from unitarity Circuit::check_unitarity accepts by default, allowing for
+/// matrix entries written to a few decimal places
+pub const DEFAULT_UNITARITY_TOLERANCE: f32 = 1e-3;
+
 pub struct Circuit {
     pub qubit_count: i32,
     pub ops: Vec<Op>,
+    // Original label of each simulated qubit, if a pass has remapped them (e.g. passes::compact_qubits).
+    // Empty if simulated qubit i is still qubit i.
+    pub logical_qubits: Vec<u32>,
+    // Original label of each simulated qubit at the start of the circuit, if SWAPs inserted by
+    // passes::reorder_qubits move qubits during it (logical_qubits is the layout at the end).
+    // Empty if the circuit has no such SWAPs.
+    pub initial_logical_qubits: Vec<u32>,
 }
 
 // ***** The below string parsers largely written by GPT-5 converting from a Python version
@@ -23,8 +73,27 @@ impl Circuit {
     ///   "rx (1.234) 2"
     ///   "cz 0 1"
     ///   "rzz (0.125) 1 3"
+    ///   "swap 0 1"
     ///   "ccx 0 1 2"
+    ///   "mcx 0 !1 2 3"       (controls 0 and 2 on |1>, 1 on |0>, target 3)
+    ///   "mcrx (0.5) 0 1 2"   (also mcz, mcphase, mcry and mcrz)
+    ///   "cp (0.5) 0 1"       (also iswap, cy, ch, crx, cry, crz, rxx and ryy)
+    ///   "u3 (0.1, 0.2, 0.3) 0"
+    ///   "pauli_exp (0.3) XZZY 0 1 2 3"      (e^(-i * 0.3 * X0 Z1 Z2 Y3))
+    ///   "unitary [0.6, 0.8i; 0.8i, 0.6] 0"   (a 2x2 matrix, rows separated by ';')
+    ///   "unitary [1, 0, 0, 0; 0, 0, 1, 0; 0, 1, 0, 0; 0, 0, 0, -1] 0 1"   (4x4, q1 is the low bit)
+    /// Matrix entries are complex numbers like "0.5", "-0.5i" or "0.5-0.5i". Matrices aren't checked
+    /// for unitarity here, see Circuit::check_unitarity.
     /// Angle may also be attached to the op token, e.g. "rz(0.5) 1".
+    ///
+    /// An op may be prefixed by `inv` (its inverse) and `ctrl <qubit>` (controlled on the qubit)
+    /// modifiers, e.g. "ctrl 2 inv t 0". Modifiers may also prefix a block of ops on the following
+    /// lines, ending with a "}" line:
+    ///   "ctrl 3 inv {"
+    ///   "h 0"
+    ///   "cx 0 1"
+    ///   "}"
+    /// Measurement and reset ops can't be modified.
     pub fn from_str(src: &str) -> Result<Self, String> {
 
         // If the string include ` @__quantum__qis__`, delegate to QIR parsing.
@@ -36,32 +105,90 @@ impl Circuit {
 
         let mut ops_vec: Vec<Op> = Vec::new();
         let mut max_qubit: i64 = -1;
+        // Blocks opened by modifiers: the line opened on, its modifiers, and the ops so far
+        let mut blocks: Vec<(usize, Vec<Modifier>, Vec<Op>)> = Vec::new();
+        let mut result_count: u32 = 0;
 
         for (lineno, raw_line) in src.lines().enumerate() {
             let line = raw_line.trim();
             if line.is_empty() { continue; }
             // Allow comments starting with '#'
-            if let Some(idx) = line.find('#') { if idx == 0 { continue; } }
+            if line.starts_with('#') { continue; }
+
+            let mut modifiers: Vec<Modifier> = Vec::new();
+            let mut line = line;
+            loop {
+                let (head, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                let rest = rest.trim_start();
+                match head.to_ascii_lowercase().as_str() {
+                    "inv" => {
+                        modifiers.push(Modifier::Inv);
+                        line = rest;
+                    }
+                    "ctrl" => {
+                        let (qubit, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                        let ctrl = qubit.parse::<u32>()
+                            .map_err(|_| format!("Line {}: invalid control qubit: {}", lineno + 1, qubit))?;
+                        max_qubit = max_qubit.max(ctrl as i64);
+                        modifiers.push(Modifier::Ctrl(ctrl));
+                        line = rest.trim_start();
+                    }
+                    _ => break,
+                }
+            }
+            if line == "{" {
+                blocks.push((lineno, modifiers, Vec::new()));
+                continue;
+            }
+            if line == "}" {
+                let (open_lineno, block_modifiers, body) = blocks.pop()
+                    .filter(|_| modifiers.is_empty())
+                    .ok_or_else(|| format!("Line {}: unexpected '}}'", lineno + 1))?;
+                let block_ops = apply_modifiers(&block_modifiers, body)
+                    .map_err(|err| format!("Line {}: {}", open_lineno + 1, err))?;
+                blocks.last_mut().map_or(&mut ops_vec, |block| &mut block.2).extend(block_ops);
+                continue;
+            }
+            if line.is_empty() {
+                return Err(format!("Line {}: modifier without an operation", lineno + 1));
+            }
 
-            let mut parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.is_empty() { continue; }
+            // Extract an optional matrix in square brackets
+            let mut matrix: Vec<Complex32> = Vec::new();
+            let has_matrix = line.contains('[');
+            let without_matrix;
+            if let Some(open) = line.find('[') {
+                let close = line[open..].find(']').map(|c| open + c)
+                    .ok_or_else(|| format!("Line {}: malformed matrix: {}", lineno + 1, &line[open..]))?;
+                for value in line[open + 1..close].split([',', ';']).map(str::trim) {
+                    matrix.push(parse_complex(value).ok_or_else(|| format!(
+                        "Line {}: invalid matrix entry: {}", lineno + 1, value
+                    ))?);
+                }
+                without_matrix = format!("{} {}", &line[..open], &line[close + 1..]);
+                line = &without_matrix;
+            }
 
-            // Extract op name and optional inline angle like "rz(0.5)"
-            let mut name_token = parts[0].to_ascii_lowercase();
-            let mut angle: Option<f32> = None;
-
-            if let Some(open) = name_token.find('(') {
-                let close = name_token.rfind(')')
-                    .ok_or_else(|| format!("Line {}: invalid angle token: {}", lineno + 1, parts[0]))?;
-                let inner = &name_token[open + 1..close];
-                angle = Some(inner.parse::<f32>().map_err(|_| format!(
-                    "Line {}: invalid angle value: {}", lineno + 1, inner
-                ))?);
-                name_token.truncate(open);
-                // Replace the original part with the cleaned name (no angle)
-                parts[0] = &line[..line.find(parts[0]).unwrap_or(0)]; // placeholder; we won't use parts[0] again
+            // Extract an optional angle list in parentheses, either attached to the op name like
+            // "rz(0.5) 1" or space separated like "u3 (0.1, 0.2, 0.3) 0"
+            let mut angles: Vec<f32> = Vec::new();
+            let has_angles = line.contains('(');
+            let mut without_angles = line.to_string();
+            if let Some(open) = line.find('(') {
+                let close = line[open..].find(')').map(|c| open + c)
+                    .ok_or_else(|| format!("Line {}: malformed angle token: {}", lineno + 1, &line[open..]))?;
+                for value in line[open + 1..close].split(',').map(str::trim) {
+                    angles.push(value.parse::<f32>().map_err(|_| format!(
+                        "Line {}: invalid angle value: {}", lineno + 1, value
+                    ))?);
+                }
+                without_angles = format!("{} {}", &line[..open], &line[close + 1..]);
             }
 
+            let parts: Vec<&str> = without_angles.split_whitespace().collect();
+            if parts.is_empty() { continue; }
+            let name_token = parts[0].to_ascii_lowercase();
+
             let op_id = match name_token.as_str() {
                 "id" => ops::ID,
                 "reset" => ops::RESET,
@@ -81,45 +208,142 @@ impl Circuit {
                 "cx" => ops::CX,
                 "cz" => ops::CZ,
                 "rzz" => ops::RZZ,
+                "swap" => ops::SWAP,
                 "ccx" | "toffoli" => ops::CCX,
                 "mz" => ops::MZ,
                 "mresetz" => ops::MRESETZ,
+                "mcx" => ops::MCX,
+                "mcz" => ops::MCZ,
+                "mcphase" => ops::MCPHASE,
+                "mcrx" => ops::MCRX,
+                "mcry" => ops::MCRY,
+                "mcrz" => ops::MCRZ,
+                "iswap" => ops::ISWAP,
+                "cy" => ops::CY,
+                "ch" => ops::CH,
+                "crx" => ops::CRX,
+                "cry" => ops::CRY,
+                "crz" => ops::CRZ,
+                "cp" | "cphase" => ops::CPHASE,
+                "rxx" => ops::RXX,
+                "ryy" => ops::RYY,
+                "u3" | "u" => ops::U3,
+                "pauli_exp" => ops::PAULI_EXP,
+                "unitary" => match matrix.len() {
+                    4 => ops::MATRIX1Q,
+                    16 => ops::MATRIX2Q,
+                    n => return Err(format!(
+                        "Line {}: unitary takes a 2x2 or 4x4 matrix, got {} entries", lineno + 1, n
+                    )),
+                },
                 other => return Err(format!("Line {}: invalid operation: {}", lineno + 1, other)),
             };
 
-            // Determine if an angle is required or forbidden and figure out where qubit indices start.
-            let mut qubit_start_index = 1usize; // name at index 0
-            let angle_required = matches!(op_id, ops::RX | ops::RY | ops::RZ | ops::RZZ);
-            let angle_allowed = angle_required; // currently only these ops accept an angle
-
-            if angle_required && angle.is_none() {
-                // Accept a space-separated angle token like "(0.5)"
-                if parts.len() > 1 && parts[1].starts_with('(') {
-                    let token = parts[1];
-                    if !token.ends_with(')') {
-                        return Err(format!("Line {}: malformed angle token: {}", lineno + 1, token));
-                    }
-                    let inner = &token[1..token.len() - 1];
-                    angle = Some(inner.parse::<f32>().map_err(|_| format!(
-                        "Line {}: invalid angle value: {}", lineno + 1, inner
-                    ))?);
-                    qubit_start_index = 2; // name + angle
-                } else {
+            let is_matrix = matches!(op_id, ops::MATRIX1Q | ops::MATRIX2Q);
+            if has_matrix && !is_matrix {
+                return Err(format!("Line {}: {} does not take a matrix", lineno + 1, name_token));
+            }
+
+            // Check the op takes the angles given. Qubit indices follow the name.
+            let qubit_start_index = 1usize;
+            let angle_count = ops::angle_count(op_id);
+            let angle_required = angle_count > 0;
+            if angle_required && !has_angles {
+                return Err(format!(
+                    "Line {}: {} requires an angle argument in parentheses, e.g. {} (0.5) ...",
+                    lineno + 1,
+                    name_token,
+                    name_token
+                ));
+            } else if !angle_required && has_angles {
+                return Err(format!("Line {}: {} does not take an angle", lineno + 1, name_token));
+            } else if angles.len() != angle_count {
+                return Err(format!(
+                    "Line {}: {} takes {} angles, got {}", lineno + 1, name_token, angle_count, angles.len()
+                ));
+            }
+
+            // Pauli exponentials take a Pauli string, then a qubit for each of its letters
+            if op_id == ops::PAULI_EXP {
+                let Some((paulis, qubit_tokens)) = parts[qubit_start_index..].split_first() else {
+                    return Err(format!("Line {}: pauli_exp needs a Pauli string, e.g. pauli_exp (0.5) XZ 0 1", lineno + 1));
+                };
+                if paulis.len() != qubit_tokens.len() {
                     return Err(format!(
-                        "Line {}: {} requires an angle argument in parentheses, e.g. {} (0.5) ...",
-                        lineno + 1,
-                        name_token,
-                        name_token
+                        "Line {}: Pauli string {} has {} letters but {} qubits are given",
+                        lineno + 1, paulis, paulis.len(), qubit_tokens.len()
                     ));
                 }
-            } else if !angle_allowed && angle.is_some() {
-                return Err(format!("Line {}: {} does not take an angle", lineno + 1, name_token));
+                let (mut x_mask, mut z_mask, mut used) = (0u32, 0u32, 0u32);
+                for (pauli, token) in paulis.chars().zip(qubit_tokens) {
+                    let q = token.parse::<u32>()
+                        .map_err(|_| format!("Line {}: invalid qubit index: {}", lineno + 1, token))?;
+                    // The Pauli string is held in 32 bit masks
+                    if q >= 32 {
+                        return Err(format!("Line {}: pauli_exp qubits must be 0 to 31, got {}", lineno + 1, q));
+                    }
+                    if used & (1 << q) != 0 {
+                        return Err(format!("Line {}: qubit {} is used more than once", lineno + 1, q));
+                    }
+                    used |= 1 << q;
+                    max_qubit = max_qubit.max(q as i64);
+                    match pauli.to_ascii_uppercase() {
+                        'I' => {}
+                        'X' => x_mask |= 1 << q,
+                        'Y' => { x_mask |= 1 << q; z_mask |= 1 << q; }
+                        'Z' => z_mask |= 1 << q,
+                        other => return Err(format!("Line {}: invalid Pauli: {}", lineno + 1, other)),
+                    }
+                }
+                let op = Op::pauli_exp(x_mask, z_mask, angles[0]);
+                let modified = apply_modifiers(&modifiers, vec![op])
+                    .map_err(|err| format!("Line {}: {}", lineno + 1, err))?;
+                blocks.last_mut().map_or(&mut ops_vec, |block| &mut block.2).extend(modified);
+                continue;
+            }
+
+            // Multi-controlled ops take any number of controls before the target
+            if ops::is_multi_controlled(op_id) {
+                let qubit_tokens = &parts[qubit_start_index.min(parts.len())..];
+                let Some((target_token, control_tokens)) = qubit_tokens.split_last().filter(|(_, c)| !c.is_empty()) else {
+                    return Err(format!("Line {}: {} needs at least one control and a target", lineno + 1, name_token));
+                };
+                let target = target_token.parse::<u32>()
+                    .map_err(|_| format!("Line {}: invalid qubit index: {}", lineno + 1, target_token))?;
+                let (mut ctrl_mask, mut ctrl_values) = (0u32, 0u32);
+                for token in control_tokens {
+                    let (negative, index) = match token.strip_prefix('!') {
+                        Some(index) => (true, index),
+                        None => (false, *token),
+                    };
+                    let ctrl = index.parse::<u32>()
+                        .map_err(|_| format!("Line {}: invalid qubit index: {}", lineno + 1, token))?;
+                    // Controls are held in a 32 bit mask
+                    if ctrl >= 32 {
+                        return Err(format!(
+                            "Line {}: {} controls must be qubits 0 to 31, got {}", lineno + 1, name_token, ctrl
+                        ));
+                    }
+                    if ctrl == target || ctrl_mask & (1 << ctrl) != 0 {
+                        return Err(format!("Line {}: qubit {} is used more than once", lineno + 1, ctrl));
+                    }
+                    max_qubit = max_qubit.max(ctrl as i64);
+                    ctrl_mask |= 1 << ctrl;
+                    if !negative {
+                        ctrl_values |= 1 << ctrl;
+                    }
+                }
+                max_qubit = max_qubit.max(target as i64);
+                let op = Op::multi_controlled(op_id, ctrl_mask, ctrl_values, target, angles.first().copied().unwrap_or(0.0));
+                let modified = apply_modifiers(&modifiers, vec![op])
+                    .map_err(|err| format!("Line {}: {}", lineno + 1, err))?;
+                blocks.last_mut().map_or(&mut ops_vec, |block| &mut block.2).extend(modified);
+                continue;
             }
 
             // Count required qubits
             let required_qubits: usize = match op_id {
-                ops::CX | ops::CZ => 2,
-                ops::RZZ => 2,
+                op_id if ops::is_two_qubit(op_id) => 2,
                 ops::CCX => 3,
                 _ => 1,
             };
@@ -130,7 +354,7 @@ impl Circuit {
                     "Line {}: invalid argument count for '{}' (got {}, expected {})",
                     lineno + 1,
                     line,
-                    have_qubits + if angle.is_some() { 1 } else { 0 },
+                    have_qubits + if has_angles { 1 } else { 0 },
                     required_qubits + if angle_required { 1 } else { 0 }
                 ));
             }
@@ -158,32 +382,50 @@ impl Circuit {
                 max_qubit = max_qubit.max(q3 as i64);
             }
 
-            let op = Op {
-                op_id,
-                q1,
-                q2,
-                q3,
-                angle: angle.unwrap_or(0.0),
-                padding: [0; 236],
+            let op = match op_id {
+                ops::MATRIX1Q => Op::with_matrix_1q(q1, &std::array::from_fn(|idx| matrix[idx])),
+                ops::MATRIX2Q => {
+                    if q1 == q2 {
+                        return Err(format!("Line {}: qubit {} is used more than once", lineno + 1, q1));
+                    }
+                    Op::with_matrix_2q(q1, q2, &std::array::from_fn(|idx| matrix[idx]))
+                }
+                // Each measurement writes the next classical result
+                ops::MZ | ops::MRESETZ => {
+                    result_count += 1;
+                    Op::new(op_id, q1, result_count - 1, 0, 0.0)
+                }
+                _ => Op::with_angles(op_id, q1, q2, q3, &angles),
             };
-            ops_vec.push(op);
+            let modified = apply_modifiers(&modifiers, vec![op])
+                .map_err(|err| format!("Line {}: {}", lineno + 1, err))?;
+            blocks.last_mut().map_or(&mut ops_vec, |block| &mut block.2).extend(modified);
         }
-        ops_vec.push(Op {
-            op_id: ops::MEVERYZ, // Implicit measurement at the end of the circuit
-            q1: 0,
-            q2: 0,
-            q3: 0,
-            angle: 0.0,
-            padding: [0; 236],
-        });
+        if let Some((open_lineno, _, _)) = blocks.last() {
+            return Err(format!("Line {}: block is never closed with '}}'", open_lineno + 1));
+        }
+        // Implicit measurement at the end of the circuit
+        ops_vec.push(Op::new(ops::MEVERYZ, 0, 0, 0, 0.0));
 
         let qubit_count = if max_qubit >= 0 { (max_qubit as i32) + 1 } else { 0 };
-        Ok(Circuit { qubit_count, ops: ops_vec })
+        Ok(Circuit { qubit_count, ops: ops_vec, logical_qubits: Vec::new(), initial_logical_qubits: Vec::new() })
+    }
+
+    /// Check every explicit matrix op is unitary to within the tolerance (see Op::unitarity_error).
+    pub fn check_unitarity(&self, tolerance: f32) -> Result<(), String> {
+        for (idx, op) in self.ops.iter().enumerate() {
+            let error = op.unitarity_error();
+            if error > tolerance {
+                return Err(format!("Op {} ({}) is not unitary: M^dagger * M is off the identity by {}",
+                    idx, crate::shader_types::ops::name(op.op_id), error));
+            }
+        }
+        Ok(())
     }
 
     /// Parse a QIR (LLVM IR text) program and build a Circuit.
     /// Only a minimal subset of QIR is supported: selected QIS gates (sx, x, y, z, h, s, t, s_adj, t_adj,
-    /// rx, ry, rz, cz, cx, rzz, ccx, m) and RT calls related to initialization/output are ignored.
+    /// rx, ry, rz, cz, cx, rzz, swap, ccx, iswap, cy, ch, crx, cry, crz, cphase, rxx, ryy, u3, m) and RT calls related to initialization/output are ignored.
     /// The QIR must declare attributes #0 including base_profile and required_num_qubits/results, and the
     /// entry point must be `define void @...() #0 { ... }`.
     pub fn from_qir_str(qir: &str) -> Result<Self, String> {
@@ -231,8 +473,6 @@ impl Circuit {
         let mut max_qubit: i64 = -1;
         let mut saw_measure = false;
 
-        // Always start with a RESET sentinel op
-
         for raw in qir.lines() {
             let line = raw.trim();
             if line.is_empty() { continue; }
@@ -262,7 +502,7 @@ impl Circuit {
             // Extract name up to '(' (strip optional __body suffix)
             let paren_idx = after_cat.find('(').ok_or_else(|| format!("Invalid QIR call (no paren): {}", line))?;
             let mut name = after_cat[..paren_idx].to_string();
-            if let Some(pos) = name.rfind("__body") { if pos + 6 == name.len() { name.truncate(pos); } }
+            if let Some(stripped) = name.strip_suffix("__body") { name.truncate(stripped.len()); }
             // If the name accidentally ends with trailing underscores (rare), trim only if it ends with "__" explicitly
             if name.ends_with("__") { name.truncate(name.len().saturating_sub(2)); }
 
@@ -290,14 +530,17 @@ impl Circuit {
                     } else {
                         return Err(format!("Invalid QIR argument: {}", arg));
                     }
-                } else if arg.starts_with("double ") {
-                    let v = arg[7..].trim();
+                } else if let Some(v) = arg.strip_prefix("double ") {
+                    let v = v.trim();
                     let angle: f32 = v.parse::<f32>().map_err(|_| format!("Invalid QIR argument: {}", arg))?;
                     parsed_nums.push(Some(ParsedArg::F32(angle)));
-                } else if arg.starts_with("i64 ") {
-                    let v = arg[4..].trim();
+                } else if let Some(v) = arg.strip_prefix("i64 ") {
+                    let v = v.trim();
                     let val: u32 = v.parse::<u32>().map_err(|_| format!("Invalid QIR argument: {}", arg))?;
                     parsed_nums.push(Some(ParsedArg::U32(val)));
+                } else if arg == "%Qubit* null" || arg == "%Result* null" {
+                    // Null pointers are qubit or result 0
+                    parsed_nums.push(Some(ParsedArg::U32(0)));
                 } else if arg == "i8* null" {
                     parsed_nums.push(None);
                 } else {
@@ -313,6 +556,7 @@ impl Circuit {
 
             let op_id = match name.as_str() {
                 "id" => ops::ID,
+                "reset" => ops::RESET,
                 "x" => ops::X,
                 "y" => ops::Y,
                 "z" => ops::Z,
@@ -329,29 +573,49 @@ impl Circuit {
                 "cx" => ops::CX,
                 "cz" => ops::CZ,
                 "rzz" => ops::RZZ,
+                "swap" => ops::SWAP,
                 "ccx" => ops::CCX,
-                "m" => { saw_measure = true; ops::MZ }, // normalize m -> mz
+                "iswap" => ops::ISWAP,
+                "cy" => ops::CY,
+                "ch" => ops::CH,
+                "crx" => ops::CRX,
+                "cry" => ops::CRY,
+                "crz" => ops::CRZ,
+                "cphase" | "cp" => ops::CPHASE,
+                "rxx" => ops::RXX,
+                "ryy" => ops::RYY,
+                "u3" => ops::U3,
+                "m" | "mz" => { saw_measure = true; ops::MZ }, // normalize m -> mz
+                "mresetz" => { saw_measure = true; ops::MRESETZ },
                 other => return Err(format!("Unsupported QIR QIS op: {}", other)),
             };
 
             // Build op fields from parsed args
-            let mut angle_val: f32 = 0.0;
+            let mut angles: Vec<f32> = Vec::new();
             let mut q1: u32 = 0; let mut q2: u32 = 0; let mut q3: u32 = 0;
 
             match op_id {
                 ops::RX | ops::RY | ops::RZ => {
                     // Expect [angle, qubit]
                     if parsed_nums.len() < 2 { return Err(format!("{} expects angle and qubit", name)); }
-                    angle_val = match parsed_nums[0] { Some(ParsedArg::F32(a)) => a, _ => return Err(format!("{} first arg must be double", name)) };
+                    angles.push(match parsed_nums[0] { Some(ParsedArg::F32(a)) => a, _ => return Err(format!("{} first arg must be double", name)) });
                     q1 = match parsed_nums[1] { Some(ParsedArg::U32(n)) => n, _ => return Err(format!("{} second arg must be qubit", name)) };
                 }
-                ops::RZZ | ops::CX | ops::CZ => {
-                    // Expect two qubits (angles only for RZZ handled separately if present before)
-                    if op_id == ops::RZZ {
-                        if parsed_nums.len() < 3 { return Err("rzz expects angle and two qubits".to_string()); }
-                        angle_val = match parsed_nums[0] { Some(ParsedArg::F32(a)) => a, _ => return Err("rzz first arg must be double".to_string()) };
-                        q1 = match parsed_nums[1] { Some(ParsedArg::U32(n)) => n, _ => return Err("rzz second arg must be qubit".to_string()) };
-                        q2 = match parsed_nums[2] { Some(ParsedArg::U32(n)) => n, _ => return Err("rzz third arg must be qubit".to_string()) };
+                ops::U3 => {
+                    // Expect [theta, phi, lambda, qubit]
+                    if parsed_nums.len() < 4 { return Err("u3 expects three angles and a qubit".to_string()); }
+                    for arg in &parsed_nums[..3] {
+                        angles.push(match arg { Some(ParsedArg::F32(a)) => *a, _ => return Err("u3 first three args must be double".to_string()) });
+                    }
+                    q1 = match parsed_nums[3] { Some(ParsedArg::U32(n)) => n, _ => return Err("u3 fourth arg must be qubit".to_string()) };
+                }
+                op_id if ops::is_two_qubit(op_id) => {
+                    // Expect two qubits, after the angle for rotations
+                    if ops::angle_count(op_id) == 1 {
+                        if parsed_nums.len() < 3 { return Err(format!("{} expects angle and two qubits", name)); }
+                        angles.push(match parsed_nums[0] { Some(ParsedArg::F32(a)) => a, _ => return Err(format!("{} first arg must be double", name)) });
+                        q1 = match parsed_nums[1] { Some(ParsedArg::U32(n)) => n, _ => return Err(format!("{} second arg must be qubit", name)) };
+                        q2 = match parsed_nums[2] { Some(ParsedArg::U32(n)) => n, _ => return Err(format!("{} third arg must be qubit", name)) };
                     } else {
                         if parsed_nums.len() < 2 { return Err(format!("{} expects two qubits", name)); }
                         q1 = match parsed_nums[0] { Some(ParsedArg::U32(n)) => n, _ => return Err(format!("{} first arg must be qubit", name)) };
@@ -364,10 +628,11 @@ impl Circuit {
                     q2 = match parsed_nums[1] { Some(ParsedArg::U32(n)) => n, _ => return Err("ccx second arg must be qubit".to_string()) };
                     q3 = match parsed_nums[2] { Some(ParsedArg::U32(n)) => n, _ => return Err("ccx third arg must be qubit".to_string()) };
                 }
-                ops::MZ => {
-                    // m(%Qubit*, %Result*)
-                    if parsed_nums.is_empty() { return Err("m expects qubit".to_string()); }
-                    q1 = match parsed_nums[0] { Some(ParsedArg::U32(n)) => n, _ => return Err("m first arg must be qubit".to_string()) };
+                ops::MZ | ops::MRESETZ => {
+                    // m(%Qubit*, %Result*), with the result index in q2
+                    if parsed_nums.len() < 2 { return Err(format!("{} expects qubit and result", name)); }
+                    q1 = match parsed_nums[0] { Some(ParsedArg::U32(n)) => n, _ => return Err(format!("{} first arg must be qubit", name)) };
+                    q2 = match parsed_nums[1] { Some(ParsedArg::U32(n)) => n, _ => return Err(format!("{} second arg must be result", name)) };
                 }
                 _ => {
                     // Single-qubit ops: take first qubit
@@ -377,10 +642,12 @@ impl Circuit {
             }
 
             max_qubit = max_qubit.max(q1 as i64);
-            max_qubit = max_qubit.max(q2 as i64);
+            if !ops::is_measurement(op_id) {
+                max_qubit = max_qubit.max(q2 as i64);
+            }
             max_qubit = max_qubit.max(q3 as i64);
 
-            ops_vec.push(Op { op_id, q1, q2, q3, angle: angle_val, padding: [0; 236] });
+            ops_vec.push(Op::with_angles(op_id, q1, q2, q3, &angles));
         }
 
         if in_entry {
@@ -388,7 +655,7 @@ impl Circuit {
         }
 
         // If no explicit measurements were found, add an implicit measure-every-z at end
-        ops_vec.push(Op { op_id: ops::MEVERYZ, q1: 0, q2: 0, q3: 0, angle: 0.0, padding: [0; 236] });
+        ops_vec.push(Op::new(ops::MEVERYZ, 0, 0, 0, 0.0));
 
         // Determine qubit count from declared and observed
         let inferred_qubits = if max_qubit >= 0 { (max_qubit as i32) + 1 } else { 0 };
@@ -396,7 +663,64 @@ impl Circuit {
         // declared_results currently unused; could be used in the future for output shape validation
         let _ = declared_results;
 
-        Ok(Circuit { qubit_count, ops: ops_vec })
+        Ok(Circuit { qubit_count, ops: ops_vec, logical_qubits: Vec::new(), initial_logical_qubits: Vec::new() })
+    }
+
+    /// Number of classical results the circuit's measurements write (see ops::MZ)
+    pub fn result_count(&self) -> usize {
+        self.ops.iter()
+            .filter(|op| crate::shader_types::ops::is_measurement(op.op_id))
+            .map(|op| op.q2 as usize + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// A circuit on the same qubits as this one (keeping any remapping), with different ops.
+    pub fn with_ops(&self, ops: Vec<Op>) -> Circuit {
+        Circuit {
+            qubit_count: self.qubit_count,
+            ops,
+            logical_qubits: self.logical_qubits.clone(),
+            initial_logical_qubits: self.initial_logical_qubits.clone(),
+        }
+    }
+
+    /// The original label of a simulated qubit
+    pub fn logical_qubit(&self, qubit: u32) -> u32 {
+        self.logical_qubits.get(qubit as usize).copied().unwrap_or(qubit)
+    }
+
+    /// The original label of a simulated qubit at the start of the circuit (see passes::reorder_qubits)
+    pub fn initial_logical_qubit(&self, qubit: u32) -> u32 {
+        self.initial_logical_qubits.get(qubit as usize).copied().unwrap_or_else(|| self.logical_qubit(qubit))
+    }
+
+    /// The original label of each simulated qubit at the end of the circuit, the layout results
+    /// and state vectors are read back in
+    pub fn layout(&self) -> Vec<u32> {
+        (0..self.qubit_count as u32).map(|q| self.logical_qubit(q)).collect()
+    }
+
+    /// The original label of each simulated qubit at the start of the circuit, the layout an
+    /// InitialState is given in
+    pub fn initial_layout(&self) -> Vec<u32> {
+        (0..self.qubit_count as u32).map(|q| self.initial_logical_qubit(q)).collect()
+    }
+
+    /// The simulated qubit with an original label, or None if the circuit doesn't simulate it
+    /// (e.g. an idle qubit dropped by passes::compact_qubits, which stays in |0>)
+    pub fn simulated_qubit(&self, logical: u32) -> Option<u32> {
+        (0..self.qubit_count as u32).find(|&q| self.logical_qubit(q) == logical)
+    }
+
+    /// Map a state vector index of the simulation to the basis state index over the original qubit labels.
+    pub fn logical_index(&self, idx: u32) -> u32 {
+        if self.logical_qubits.is_empty() {
+            return idx;
+        }
+        (0..self.qubit_count as u32)
+            .filter(|q| idx & (1 << q) != 0)
+            .fold(0, |acc, q| acc | (1 << self.logical_qubit(q)))
     }
 
     pub fn create_ops_buffers(&self, device: &Device) -> (Buffer, Buffer) {
@@ -0,0 +1,557 @@
+#![allow(unused)]
+
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4};
+
+use crate::circuit::Circuit;
+use crate::hamiltonian::PauliSum;
+use crate::initial_state::InitialState;
+use crate::noise::NoiseModel;
+use crate::rng;
+use crate::run_config::{ResultSelection, RunConfig};
+use crate::sampling::{marginal_qubits, shot_bitstring, sort_results, state_bitstring, Counts, Readout, RunOutput};
+use crate::shader_types::{ops, Complex32, Op, Result};
+
+/// State vector simulator running on the host.
+///
+/// Ops follow the same conventions as the shader (e.g. RZ is diag(1, e^(i * angle)), so global
+/// phase differs from the textbook definition), so results can be compared directly with the GPU.
+/// Useful for small circuits, testing, and where no adapter is available.
+/// Measurement outcomes are sampled with the same random numbers as GpuContext, so both backends
+/// give the same outcomes for the same seed.
+pub struct CpuSimulator {
+    circuit: Circuit,
+    state: Vec<Complex32>,
+    config: RunConfig,
+    initial_state: InitialState,
+    // Number of runs so far, so each run samples different outcomes
+    shot: u64,
+}
+
+impl CpuSimulator {
+    pub fn new(circuit: Circuit) -> Self {
+        let state = vec![Complex32::ZERO; 1usize << circuit.qubit_count];
+        CpuSimulator { circuit, state, config: RunConfig::default(), initial_state: InitialState::default(), shot: 0 }
+    }
+
+    /// Use the config for the following runs, starting again from the first shot
+    pub fn set_config(&mut self, config: RunConfig) {
+        self.config = config;
+        self.shot = 0;
+    }
+
+    /// Start the following runs from the given state rather than |0...0>
+    pub fn set_initial_state(&mut self, initial_state: InitialState) -> std::result::Result<(), String> {
+        initial_state.check(self.circuit.qubit_count as u32)?;
+        self.initial_state = initial_state;
+        Ok(())
+    }
+
+    /// Apply the noise model in the following runs, replacing any set before. Each run then
+    /// follows one trajectory, and each shot of `sample` its own.
+    pub fn set_noise_model(&mut self, noise: &NoiseModel) -> std::result::Result<(), String> {
+        noise.check()?;
+        self.circuit = noise.apply(&self.circuit)?;
+        Ok(())
+    }
+
+    pub fn state(&self) -> &[Complex32] {
+        &self.state
+    }
+
+    /// The expectation value of a Pauli sum, e.g. the energy of a Hamiltonian, in the state left by the last run
+    pub fn expectation(&self, hamiltonian: &PauliSum) -> f64 {
+        hamiltonian.terms.iter()
+            .filter_map(|term| term.on_circuit_qubits(&self.circuit))
+            .map(|term| term.coefficient as f64 * pauli_expectation(&self.state, term.x_mask, term.z_mask))
+            .sum()
+    }
+
+    /// The probability of each value of the given qubits (by original label), summing over the
+    /// others, in the state left by the last run. Bit i of each index is the value of qubits[i].
+    pub fn marginal_probabilities(&self, qubits: &[u32]) -> Vec<f32> {
+        let simulated = marginal_qubits(&self.circuit, qubits);
+        let mut marginal = vec![0.0f64; 1 << qubits.len()];
+        for (idx, entry) in self.state.iter().enumerate() {
+            let bin = simulated.iter().enumerate()
+                .filter(|(_, q)| q.is_some_and(|q| idx & (1 << q) != 0))
+                .fold(0, |bin, (i, _)| bin | (1 << i));
+            marginal[bin] += entry.norm_sqr() as f64;
+        }
+        marginal.into_iter().map(|p| p as f32).collect()
+    }
+
+    /// Run the circuit from the initial state and return the entries selected by the final MEVERYZ op (see
+    /// ResultSelection), likeliest first. Entry indices are over the circuit's original qubit labels.
+    pub fn run(&mut self) -> Vec<Result> {
+        self.run_with_outcomes().results
+    }
+
+    /// Run the circuit as for `run`, also returning how many results didn't fit and the classical
+    /// results written by its measurements.
+    pub fn run_with_outcomes(&mut self) -> RunOutput {
+        let shot = self.start_shot();
+
+        let mut output = RunOutput { outcomes: vec![0; self.circuit.result_count()], ..Default::default() };
+        // Measurements, resets and noise each take the next random number of the shot
+        let mut draw = 0;
+        for op in &self.circuit.ops {
+            match op.op_id {
+                ops::MEVERYZ => {
+                    let (mut results, overflow) = select_results(&self.state, &self.config.results);
+                    for result in results.iter_mut() {
+                        result.entry_idx = self.circuit.logical_index(result.entry_idx);
+                    }
+                    sort_results(&mut results);
+                    output.results = results;
+                    output.overflow = overflow;
+                }
+                ops::RESET | ops::MZ | ops::MRESETZ => {
+                    let random = rng::uniform(self.config.seed, shot, draw);
+                    draw += 1;
+                    let outcome = measure(&mut self.state, op.q1, random, op.op_id != ops::MZ);
+                    if ops::is_measurement(op.op_id) {
+                        output.outcomes[op.q2 as usize] = outcome;
+                    }
+                }
+                op_id if ops::is_noise(op_id) => {
+                    apply_noise(&mut self.state, op, rng::uniform(self.config.seed, shot, draw));
+                    draw += 1;
+                }
+                _ => apply_op(&mut self.state, op),
+            }
+        }
+        output
+    }
+
+    /// Sample `shots` shots, counting the bitstrings of the measured qubits (see Readout).
+    pub fn sample(&mut self, shots: usize) -> Counts {
+        let mut counts = Counts::new();
+        match Readout::for_circuit(&self.circuit) {
+            Readout::PerShot { stop, bits } => {
+                // The final state is sampled with the draw after the measurements'
+                let draws = self.circuit.ops[..stop].iter().filter(|op| ops::is_stochastic(op.op_id)).count();
+                for _ in 0..shots {
+                    let output = self.run_with_outcomes();
+                    let entry_idx = if bits.is_empty() {
+                        0
+                    } else {
+                        let random = rng::uniform(self.config.seed, self.shot - 1, draws as u64);
+                        sample_cdf(&state_cdf(&self.state), random)
+                    };
+                    *counts.entry(shot_bitstring(&output.outcomes, entry_idx as u32, &bits)).or_default() += 1;
+                }
+            }
+            Readout::Trajectories { stop, bits } => {
+                // Each shot follows its own trajectory, then samples the state it leaves with the
+                // draw after the noise's
+                for _ in 0..shots {
+                    let shot = self.start_shot();
+                    let mut draw = 0;
+                    for op in &self.circuit.ops[..stop] {
+                        if ops::is_noise(op.op_id) {
+                            apply_noise(&mut self.state, op, rng::uniform(self.config.seed, shot, draw));
+                            draw += 1;
+                        } else {
+                            apply_op(&mut self.state, op);
+                        }
+                    }
+                    let cdf = state_cdf(&self.state);
+                    let entry_idx = sample_cdf(&cdf, rng::uniform(self.config.seed, shot, draw));
+                    *counts.entry(state_bitstring(entry_idx as u32, &bits)).or_default() += 1;
+                }
+            }
+            Readout::FinalState { stop, bits } => {
+                let shot = self.start_shot();
+                for op in &self.circuit.ops[..stop] {
+                    apply_op(&mut self.state, op);
+                }
+                let cdf = state_cdf(&self.state);
+                for i in 0..shots {
+                    let entry_idx = sample_cdf(&cdf, rng::uniform(self.config.seed, shot, i as u64));
+                    *counts.entry(state_bitstring(entry_idx as u32, &bits)).or_default() += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    // Reset to the initial state for the next shot, returning its number
+    fn start_shot(&mut self) -> u64 {
+        match &self.initial_state {
+            InitialState::Basis(idx) => {
+                self.state.fill(Complex32::ZERO);
+                self.state[*idx] = Complex32::ONE;
+            }
+            initial_state => {
+                let amplitudes = initial_state.amplitudes(0..self.state.len());
+                self.state.copy_from_slice(&amplitudes);
+            }
+        }
+        self.shot += 1;
+        self.shot - 1
+    }
+}
+
+// Running totals of the probabilities of the basis states
+fn state_cdf(state: &[Complex32]) -> Vec<f32> {
+    state.iter()
+        .scan(0.0, |sum, amp| {
+            *sum += amp.norm_sqr();
+            Some(*sum)
+        })
+        .collect()
+}
+
+// The basis state whose share of the CDF holds `random` (uniform in [0, 1))
+fn sample_cdf(cdf: &[f32], random: f32) -> usize {
+    let target = random * cdf.last().copied().unwrap_or(0.0);
+    cdf.partition_point(|&sum| sum <= target).min(cdf.len() - 1)
+}
+
+/// Apply one Kraus operator of a noise op's channel, chosen with `random` (uniform in [0, 1)), and
+/// renormalize. The damping channels jump with probability angle * P(1), projecting onto |1> (and
+/// for amplitude damping moving it to |0>), and otherwise scale |1> by sqrt(1 - angle).
+pub fn apply_noise(state: &mut [Complex32], op: &Op, random: f32) {
+    let p = op.angle;
+    let bit = 1usize << op.q1;
+    if ops::is_damping(op.op_id) {
+        let p1: f32 = state.iter().enumerate().filter(|(i, _)| i & bit != 0).map(|(_, amp)| amp.norm_sqr()).sum();
+        let total: f32 = state.iter().map(|amp| amp.norm_sqr()).sum();
+        let jump = random < p * p1 / total;
+        let probability = if jump { p * p1 } else { total - p * p1 };
+        let scale = 1.0 / probability.sqrt();
+        let decay = if jump { p.sqrt() } else { (1.0 - p).sqrt() } * scale;
+        for i in (0..state.len()).filter(|i| i & bit == 0) {
+            let amp1 = state[i | bit].scale(decay);
+            (state[i], state[i | bit]) = match (jump, op.op_id) {
+                (false, _) => (state[i].scale(scale), amp1),
+                (true, ops::AMPLITUDE_DAMP) => (amp1, Complex32::ZERO),
+                (true, _) => (Complex32::ZERO, amp1),
+            };
+        }
+        return;
+    }
+
+    // Depolarizing picks X, Y or Z with probability angle / 3 each
+    let pauli = match op.op_id {
+        ops::BIT_FLIP if random < p => ops::X,
+        ops::PHASE_FLIP if random < p => ops::Z,
+        ops::DEPOLARIZE if 3.0 * random < p => ops::X,
+        ops::DEPOLARIZE if 3.0 * random < 2.0 * p => ops::Y,
+        ops::DEPOLARIZE if random < p => ops::Z,
+        _ => return,
+    };
+    apply_op(state, &Op::new(pauli, op.q1, 0, 0, 0.0));
+}
+
+/// Measure the qubit in the Z basis, returning 1 if `random` (uniform in [0, 1)) is below the
+/// probability of |1>. Collapses the state to the outcome and renormalizes, and then if `reset`
+/// is set, flips the qubit back to |0>.
+pub fn measure(state: &mut [Complex32], qubit: u32, random: f32, reset: bool) -> u32 {
+    let bit = 1usize << qubit;
+    let (mut p0, mut p1) = (0.0f32, 0.0f32);
+    for (i, amp) in state.iter().enumerate() {
+        if i & bit == 0 {
+            p0 += amp.norm_sqr();
+        } else {
+            p1 += amp.norm_sqr();
+        }
+    }
+    let outcome = u32::from(random < p1 / (p0 + p1));
+    let scale = 1.0 / if outcome == 1 { p1 } else { p0 }.sqrt();
+    for i in (0..state.len()).filter(|i| i & bit == 0) {
+        let amp = if outcome == 1 { state[i | bit] } else { state[i] }.scale(scale);
+        if outcome == 1 && !reset {
+            (state[i], state[i | bit]) = (Complex32::ZERO, amp);
+        } else {
+            (state[i], state[i | bit]) = (amp, Complex32::ZERO);
+        }
+    }
+    outcome
+}
+
+/// Returns the columns of the unitary applied by the circuit, skipping any non-unitary ops.
+/// This runs the circuit once per basis state, so only use it on small circuits.
+pub fn circuit_unitary(circuit: &Circuit) -> Vec<Vec<Complex32>> {
+    let size = 1usize << circuit.qubit_count;
+    (0..size)
+        .map(|col| {
+            let mut state = vec![Complex32::ZERO; size];
+            state[col] = Complex32::ONE;
+            for op in &circuit.ops {
+                apply_op(&mut state, op);
+            }
+            state
+        })
+        .collect()
+}
+
+/// Returns the 2x2 matrix (row major) for a single qubit gate, or None if the op isn't one.
+pub fn matrix_1q(op: &Op) -> Option<[Complex32; 4]> {
+    let c = Complex32::new;
+    let (cos, sin) = ((op.angle / 2.0).cos(), (op.angle / 2.0).sin());
+    let m = match op.op_id {
+        ops::ID => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(1.0, 0.0)],
+        ops::X => [c(0.0, 0.0), c(1.0, 0.0), c(1.0, 0.0), c(0.0, 0.0)],
+        ops::Y => [c(0.0, 0.0), c(0.0, -1.0), c(0.0, 1.0), c(0.0, 0.0)],
+        ops::Z => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(-1.0, 0.0)],
+        ops::H => [
+            c(FRAC_1_SQRT_2, 0.0), c(FRAC_1_SQRT_2, 0.0),
+            c(FRAC_1_SQRT_2, 0.0), c(-FRAC_1_SQRT_2, 0.0),
+        ],
+        ops::S => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(0.0, 1.0)],
+        ops::S_ADJ => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(0.0, -1.0)],
+        ops::T => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), Complex32::from_phase(FRAC_PI_4)],
+        ops::T_ADJ => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), Complex32::from_phase(-FRAC_PI_4)],
+        ops::SX => [c(0.5, 0.5), c(0.5, -0.5), c(0.5, -0.5), c(0.5, 0.5)],
+        ops::SX_ADJ => [c(0.5, -0.5), c(0.5, 0.5), c(0.5, 0.5), c(0.5, -0.5)],
+        ops::RX => [c(cos, 0.0), c(0.0, -sin), c(0.0, -sin), c(cos, 0.0)],
+        ops::RY => [c(cos, 0.0), c(-sin, 0.0), c(sin, 0.0), c(cos, 0.0)],
+        ops::RZ => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), Complex32::from_phase(op.angle)],
+        ops::U3 => {
+            let (phi, lambda) = (op.angle_at(1), op.angle_at(2));
+            [
+                c(cos, 0.0), Complex32::from_phase(lambda).scale(-sin),
+                Complex32::from_phase(phi).scale(sin), Complex32::from_phase(phi + lambda).scale(cos),
+            ]
+        }
+        ops::MATRIX1Q => std::array::from_fn(|idx| op.matrix_entry(idx)),
+        _ => return None,
+    };
+    Some(m)
+}
+
+/// Returns (control mask, control values, target, 2x2 matrix) for a controlled single qubit gate
+/// (multi-controlled ops, CY, CH, CRX, CRY, CRZ and CPHASE), or None if the op isn't one.
+pub fn controlled_1q_matrix(op: &Op) -> Option<(u32, u32, u32, [Complex32; 4])> {
+    let c = Complex32::new;
+    let (cos, sin) = ((op.angle / 2.0).cos(), (op.angle / 2.0).sin());
+    let m = match op.op_id {
+        ops::MCX => [c(0.0, 0.0), c(1.0, 0.0), c(1.0, 0.0), c(0.0, 0.0)],
+        ops::MCZ => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(-1.0, 0.0)],
+        ops::CY => [c(0.0, 0.0), c(0.0, -1.0), c(0.0, 1.0), c(0.0, 0.0)],
+        ops::CH => [
+            c(FRAC_1_SQRT_2, 0.0), c(FRAC_1_SQRT_2, 0.0),
+            c(FRAC_1_SQRT_2, 0.0), c(-FRAC_1_SQRT_2, 0.0),
+        ],
+        ops::MCPHASE | ops::CPHASE => [c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), Complex32::from_phase(op.angle)],
+        ops::MCRX | ops::CRX => [c(cos, 0.0), c(0.0, -sin), c(0.0, -sin), c(cos, 0.0)],
+        ops::MCRY | ops::CRY => [c(cos, 0.0), c(-sin, 0.0), c(sin, 0.0), c(cos, 0.0)],
+        ops::MCRZ | ops::CRZ => [
+            Complex32::from_phase(-op.angle / 2.0), c(0.0, 0.0),
+            c(0.0, 0.0), Complex32::from_phase(op.angle / 2.0),
+        ],
+        _ => return None,
+    };
+    if ops::is_multi_controlled(op.op_id) {
+        let (ctrl_mask, ctrl_values) = op.controls();
+        Some((ctrl_mask, ctrl_values, op.q1, m))
+    } else {
+        Some((1 << op.q1, 1 << op.q1, op.q2, m))
+    }
+}
+
+/// Returns the 4x4 matrix (row major) for ISWAP, RXX or RYY, or None if the op isn't one.
+/// Basis states are ordered with q1 as the low bit, i.e. |q2 q1>.
+pub fn matrix_2q(op: &Op) -> Option<[Complex32; 16]> {
+    let z = Complex32::ZERO;
+    let (cos, sin) = (Complex32::new((op.angle / 2.0).cos(), 0.0), (op.angle / 2.0).sin());
+    let m = match op.op_id {
+        ops::ISWAP => [
+            Complex32::ONE, z, z, z,
+            z, z, Complex32::I, z,
+            z, Complex32::I, z, z,
+            z, z, z, Complex32::ONE,
+        ],
+        ops::RXX => {
+            let s = Complex32::new(0.0, -sin);
+            [cos, z, z, s, z, cos, s, z, z, s, cos, z, s, z, z, cos]
+        }
+        ops::RYY => {
+            let (s_even, s_odd) = (Complex32::new(0.0, sin), Complex32::new(0.0, -sin));
+            [cos, z, z, s_even, z, cos, s_odd, z, z, s_odd, cos, z, s_even, z, z, cos]
+        }
+        ops::MATRIX2Q => std::array::from_fn(|idx| op.matrix_entry(idx)),
+        _ => return None,
+    };
+    Some(m)
+}
+
+/// Apply a single op to the state vector. Reset and measurement ops are ignored (see
+/// CpuSimulator::run_with_outcomes and measure).
+pub fn apply_op(state: &mut [Complex32], op: &Op) {
+    if let Some(m) = matrix_1q(op) {
+        apply_1q_matrix(state, op.q1, &m);
+        return;
+    }
+    if let Some((ctrl_mask, ctrl_values, target, m)) = controlled_1q_matrix(op) {
+        apply_controlled_1q_matrix(state, ctrl_mask, ctrl_values, target, &m);
+        return;
+    }
+    if let Some(m) = matrix_2q(op) {
+        apply_2q_matrix(state, op.q1, op.q2, &m);
+        return;
+    }
+
+    match op.op_id {
+        ops::CX => apply_controlled_flip(state, 1 << op.q1, 1 << op.q2),
+        ops::CCX => apply_controlled_flip(state, (1 << op.q1) | (1 << op.q2), 1 << op.q3),
+        ops::CZ => {
+            let mask = (1usize << op.q1) | (1usize << op.q2);
+            for (i, entry) in state.iter_mut().enumerate() {
+                if i & mask == mask {
+                    *entry = -*entry;
+                }
+            }
+        }
+        ops::RZZ => apply_parity_phase(state, (1 << op.q1) | (1 << op.q2), op.angle),
+        ops::SWAP => {
+            let (a, b) = (1usize << op.q1, 1usize << op.q2);
+            for i in 0..state.len() {
+                if i & a != 0 && i & b == 0 {
+                    state.swap(i, i ^ a ^ b);
+                }
+            }
+        }
+        ops::DIAGONAL => {
+            for t in 0..op.term_count() {
+                let (mask, angle) = op.term(t);
+                apply_parity_phase(state, mask, angle);
+            }
+        }
+        ops::PERMUTATION => apply_permutation(state, op),
+        ops::PAULI_EXP => {
+            let (x_mask, z_mask) = op.pauli_masks();
+            apply_pauli_exp(state, x_mask, z_mask, op.angle);
+        }
+        _ => {
+            // Resets and measurements need a random outcome, see CpuSimulator::run_with_outcomes
+        }
+    }
+}
+
+pub fn apply_1q_matrix(state: &mut [Complex32], qubit: u32, m: &[Complex32; 4]) {
+    apply_controlled_1q_matrix(state, 0, 0, qubit, m);
+}
+
+/// Apply m to the target qubit of every basis state where the control bits match the values
+pub fn apply_controlled_1q_matrix(state: &mut [Complex32], ctrl_mask: u32, ctrl_values: u32, target: u32, m: &[Complex32; 4]) {
+    let stride = 1usize << target;
+    let (ctrl_mask, ctrl_values) = (ctrl_mask as usize, ctrl_values as usize);
+    for i in 0..state.len() {
+        if i & stride != 0 || i & ctrl_mask != ctrl_values {
+            continue;
+        }
+        let (a0, a1) = (state[i], state[i | stride]);
+        state[i] = m[0] * a0 + m[1] * a1;
+        state[i | stride] = m[2] * a0 + m[3] * a1;
+    }
+}
+
+pub fn apply_2q_matrix(state: &mut [Complex32], q1: u32, q2: u32, m: &[Complex32; 16]) {
+    let (b1, b2) = (1usize << q1, 1usize << q2);
+    for i in 0..state.len() {
+        if i & (b1 | b2) != 0 {
+            continue;
+        }
+        let idx = [i, i | b1, i | b2, i | b1 | b2];
+        let amps = idx.map(|j| state[j]);
+        for (row, &j) in idx.iter().enumerate() {
+            state[j] = (0..4).fold(Complex32::ZERO, |acc, col| acc + m[4 * row + col] * amps[col]);
+        }
+    }
+}
+
+// Toggle the flip bits of every basis state with all the control bits set (X, CX, CCX)
+fn apply_controlled_flip(state: &mut [Complex32], ctrl: u32, flip: u32) {
+    let (ctrl, flip) = (ctrl as usize, flip as usize);
+    let high_flip_bit = 1usize << (usize::BITS - 1 - flip.leading_zeros());
+    for i in 0..state.len() {
+        // Visit each pair once, from the member with the highest flip bit clear
+        if i & ctrl == ctrl && i & high_flip_bit == 0 {
+            state.swap(i, i ^ flip);
+        }
+    }
+}
+
+// Multiply every entry with odd parity over the mask by e^(i * angle)
+fn apply_parity_phase(state: &mut [Complex32], mask: u32, angle: f32) {
+    let phase = Complex32::from_phase(angle);
+    for (i, entry) in state.iter_mut().enumerate() {
+        if (i as u32 & mask).count_ones() & 1 == 1 {
+            *entry = *entry * phase;
+        }
+    }
+}
+
+// e^(-i * angle * P) = cos(angle) - i * sin(angle) * P, where P|b> = i^(Y count) * (-1)^(Z parity of b) |b ^ x_mask>
+fn apply_pauli_exp(state: &mut [Complex32], x_mask: u32, z_mask: u32, angle: f32) {
+    let (x_mask, z_mask) = (x_mask as usize, z_mask as usize);
+    let y_phase = [Complex32::ONE, Complex32::I, -Complex32::ONE, -Complex32::I][(x_mask & z_mask).count_ones() as usize % 4];
+    let cos = Complex32::new(angle.cos(), 0.0);
+    let coeff = Complex32::new(0.0, -angle.sin()) * y_phase;
+    let sign = |b: usize| if (b & z_mask).count_ones() % 2 == 1 { -1.0 } else { 1.0 };
+
+    // Visit each pair (b, b ^ x_mask) once, from the entry without the lowest X bit
+    let pivot = x_mask & x_mask.wrapping_neg();
+    for b in (0..state.len()).filter(|b| b & pivot == 0) {
+        if x_mask == 0 {
+            state[b] = (cos + coeff.scale(sign(b))) * state[b];
+            continue;
+        }
+        let c = b ^ x_mask;
+        let (old_b, old_c) = (state[b], state[c]);
+        state[b] = cos * old_b + coeff.scale(sign(c)) * old_c;
+        state[c] = cos * old_c + coeff.scale(sign(b)) * old_b;
+    }
+}
+
+/// <psi|P|psi> for the Pauli string with the given masks (see PauliTerm), summing
+/// conj(psi[b ^ x_mask]) * i^(Y count) * (-1)^(Z parity of b) * psi[b] over all b
+pub fn pauli_expectation(state: &[Complex32], x_mask: u32, z_mask: u32) -> f64 {
+    let (x_mask, z_mask) = (x_mask as usize, z_mask as usize);
+    let (mut re, mut im) = (0.0f64, 0.0f64);
+    for (b, entry) in state.iter().enumerate() {
+        let term = state[b ^ x_mask].conj() * *entry;
+        let sign = if (b & z_mask).count_ones() % 2 == 1 { -1.0 } else { 1.0 };
+        re += sign * term.re as f64;
+        im += sign * term.im as f64;
+    }
+    // The sum is real once multiplied by the phase of the Y count
+    match (x_mask & z_mask).count_ones() % 4 {
+        0 => re,
+        1 => -im,
+        2 => -re,
+        _ => im,
+    }
+}
+
+fn apply_permutation(state: &mut [Complex32], op: &Op) {
+    let mut permuted = vec![Complex32::ZERO; state.len()];
+    for (i, entry) in state.iter().enumerate() {
+        let mut dest = i as u32;
+        for t in 0..op.term_count() {
+            let (ctrl, flip) = op.mask_pair(t);
+            if dest & ctrl == ctrl {
+                dest ^= flip;
+            }
+        }
+        permuted[dest as usize] = *entry;
+    }
+    state.copy_from_slice(&permuted);
+}
+
+// The entries above the threshold, keeping the likeliest up to the capacity (ties going to the
+// lower index, as on the GPU), and how many more there were
+fn select_results(state: &[Complex32], selection: &ResultSelection) -> (Vec<Result>, usize) {
+    let mut results: Vec<Result> = state
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| Result { entry_idx: i as u32, probability: entry.norm_sqr() })
+        .filter(|result| result.probability > selection.threshold())
+        .collect();
+    let overflow = results.len().saturating_sub(selection.capacity());
+    sort_results(&mut results);
+    results.truncate(selection.capacity());
+    (results, overflow)
+}
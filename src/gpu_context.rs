@@ -1,16 +1,46 @@
 #![allow(unused)]
 
 use crate::circuit::Circuit;
-use crate::shader_types::{Result, Op};
+use crate::hamiltonian::{PauliSum, PauliTerm};
+use crate::initial_state::InitialState;
+use crate::noise::NoiseModel;
+use crate::rng;
+use crate::run_config::RunConfig;
+use crate::sampling::{marginal_qubits, shot_bitstring, sort_results, state_bitstring, Counts, Readout, RunOutput};
+use crate::shader_types::{ops, Complex32, Measurement, Result, Op, RunParams, MAX_QUBITS_PER_THREAD};
 
 use futures::FutureExt;
+use std::cell::Cell;
 use std::num::NonZeroU64;
+use std::ops::Range;
 use wgpu::{
     Adapter, BindGroup, BindGroupLayout, Buffer, BufferDescriptor, BufferUsages, ComputePipeline, Device, Limits, Queue, ShaderModule
 };
 
 const DO_CAPTURE: bool = true;
 
+// Shots sampled per dispatch, and the workgroup size of the sampling kernel (see shader.wgsl)
+pub(crate) const SAMPLE_BATCH: usize = 1 << 16;
+const SAMPLE_THREADS: usize = 32;
+// Workgroup size of the single workgroup reductions (see shader.wgsl)
+const REDUCE_THREADS: usize = 32;
+
+// Words of the result selection counters, and digit passes over the probability part of the keys (see shader.wgsl)
+const COUNTER_WORDS: usize = 272;
+const PROBABILITY_DIGITS: u32 = 4;
+
+// Pauli terms per submission when computing expectation values, each passed as an op
+pub(crate) const TERM_BATCH: usize = 1024;
+
+// Largest copy through the staging buffer when reading back the state vector, and largest write
+// when uploading an initial state
+const STATE_CHUNK_BYTES: u64 = 1 << 24;
+
+/// wgpu limits buffers to 1GB, which is 2^30 bytes.
+/// As we need 8 bytes (2^3) per complex number, we can only support up to 2^27 state vector entries.
+/// See https://github.com/gfx-rs/wgpu/issues/2337#issuecomment-1549935712
+pub const MAX_QUBITS: i32 = 27;
+
 pub struct GpuContext {
     device: Device,
     queue: Queue,
@@ -21,24 +51,67 @@ pub struct GpuContext {
     entries_per_thread: i32,
     threads_per_workgroup: i32,
     workgroup_count: i32,
+    config: RunConfig,
+    initial_state: InitialState,
+    // Number of runs so far, so each run samples different measurement outcomes
+    shot: Cell<u64>,
+    // Whether the last run left the state in the scratch buffer
+    state_swapped: Cell<bool>,
 }
 
 struct GpuResources {
     pipeline: ComputePipeline,
+    // The first two of the three dispatches for each mid-circuit measurement (see shader.wgsl)
+    measure_pipeline: ComputePipeline,
+    sample_pipeline: ComputePipeline,
+    // Selecting the results reported by the final MEVERYZ op
+    count_candidates_pipeline: ComputePipeline,
+    start_selection_pipeline: ComputePipeline,
+    histogram_digits_pipeline: ComputePipeline,
+    select_digit_pipeline: ComputePipeline,
+    collect_results_pipeline: ComputePipeline,
+    // Shot sampling from the final state
+    scan_pipeline: ComputePipeline,
+    sample_shots_pipeline: ComputePipeline,
+    // Expectation values of Pauli strings
+    expectation_pipeline: ComputePipeline,
+    reduce_expectation_pipeline: ComputePipeline,
+    // Marginal distributions over some of the qubits
+    marginal_pipeline: ComputePipeline,
+    reduce_marginal_pipeline: ComputePipeline,
     state_vector_buffer: Buffer,
+    // Target for ops that can't run in place. Swapped with state_vector_buffer after each such op.
+    scratch_buffer: Buffer,
+    // Copied into the state vector at the start of each run. For a basis state, just its amplitude of 1.
+    initial_state_buffer: Buffer,
     ops_upload_buffer: Buffer,
     ops_buffer: Buffer,
     results_buffer: Buffer,
+    counters_buffer: Buffer,
     download_buffer: Buffer,
+    // The number of results written and the number above the threshold
+    counters_download_buffer: Buffer,
+    // The outcome of each mid-circuit measurement, reset or noise channel
+    measurements_buffer: Buffer,
+    measurements_download_buffer: Buffer,
+    measurement_count: usize,
+    // Sampled basis states for a batch of shots
+    samples_buffer: Buffer,
+    samples_download_buffer: Buffer,
+    run_params_buffer: Buffer,
+    // Ops for queries of the state after a run, e.g. a batch of Pauli terms
+    query_buffer: Buffer,
     bind_group: BindGroup,
+    // Same as bind_group, but with the state vector and scratch buffers swapped
+    swapped_bind_group: BindGroup,
+    // Same as bind_group and swapped_bind_group, but binding the query buffer in place of the ops
+    query_bind_group: BindGroup,
+    swapped_query_bind_group: BindGroup,
 }
 
 impl GpuContext {
     pub async fn new(circuit: Circuit) -> Self {
-        if circuit.qubit_count > 27 {
-            // wgpu limits buffers to 1GB, which is 2^30 bytes.
-            // As we need 8 bytes (2^3) per complex number, we can only support up to 2^27 state vector entries.
-            // See https://github.com/gfx-rs/wgpu/issues/2337#issuecomment-1549935712
+        if circuit.qubit_count > MAX_QUBITS {
             panic!("Qubit count too high: {}", circuit.qubit_count);
         }
 
@@ -139,6 +212,61 @@ impl GpuContext {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    // StateVector output buffer for ops that can't run in place
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    // Per thread probability sums for measurements
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    // Mid-circuit measurement outcomes
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    // Shot samples
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    // Run parameters (seed, shot and sample batch)
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
 
@@ -152,7 +280,75 @@ impl GpuContext {
             entries_per_thread,
             threads_per_workgroup,
             workgroup_count,
+            config: RunConfig::default(),
+            initial_state: InitialState::default(),
+            shot: Cell::new(0),
+            state_swapped: Cell::new(false),
+        }
+    }
+
+    /// Use the config for the following runs, starting again from the first shot
+    pub fn set_config(&mut self, config: RunConfig) {
+        // The results buffer is sized for the capacity
+        let resize = self.resources.is_some() && config.results.capacity() != self.config.results.capacity();
+        self.config = config;
+        self.shot.set(0);
+        if resize {
+            self.create_resources();
+        }
+    }
+
+    /// Start the following runs from the given state rather than |0...0>
+    pub fn set_initial_state(&mut self, initial_state: InitialState) -> std::result::Result<(), String> {
+        initial_state.check(self.circuit.qubit_count as u32)?;
+        self.initial_state = initial_state;
+        if let Some(mut resources) = self.resources.take() {
+            resources.initial_state_buffer = self.create_initial_state_buffer();
+            self.resources = Some(resources);
+        }
+        Ok(())
+    }
+
+    /// Apply the noise model in the following runs, replacing any set before. Each run then
+    /// follows one trajectory, and each shot of `sample` its own.
+    pub fn set_noise_model(&mut self, noise: &NoiseModel) -> std::result::Result<(), String> {
+        noise.check()?;
+        self.circuit = noise.apply(&self.circuit)?;
+        if self.resources.is_some() {
+            self.create_resources();
+        }
+        Ok(())
+    }
+
+    // Upload the initial state. A basis state only needs the amplitude of 1 copied to its entry,
+    // while other states are written a chunk at a time into a full size buffer.
+    fn create_initial_state_buffer(&self) -> Buffer {
+        let entry_bytes = std::mem::size_of::<Complex32>() as u64;
+        if let InitialState::Basis(_) = self.initial_state {
+            let buffer = self.device.create_buffer(&BufferDescriptor {
+                label: Some("Initial State Buffer"),
+                size: entry_bytes,
+                usage: BufferUsages::MAP_WRITE | BufferUsages::COPY_SRC,
+                mapped_at_creation: true,
+            });
+            buffer.slice(..).get_mapped_range_mut().copy_from_slice(bytemuck::bytes_of(&Complex32::ONE));
+            buffer.unmap();
+            return buffer;
+        }
+
+        let entry_count = 1usize << self.circuit.qubit_count;
+        let buffer = self.device.create_buffer(&BufferDescriptor {
+            label: Some("Initial State Buffer"),
+            size: entry_count as u64 * entry_bytes,
+            usage: BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let chunk_entries = (STATE_CHUNK_BYTES / entry_bytes) as usize;
+        for start in (0..entry_count).step_by(chunk_entries) {
+            let amplitudes = self.initial_state.amplitudes(start..entry_count.min(start + chunk_entries));
+            self.queue.write_buffer(&buffer, start as u64 * entry_bytes, bytemuck::cast_slice(&amplitudes));
         }
+        buffer
     }
 
     pub fn get_params(qubit_count: i32) -> (i32, i32, i32) {
@@ -162,25 +358,25 @@ impl GpuContext {
         
         if qubit_count < MAX_QUBITS_PER_THREAD {
             // All qubits fit in one thread
-            return (
+            (
                 1 << qubit_count,    // Output states to process per thread
                 1,                   // Threads per workgroup
                 1                    // Workgroup count
-            );
+            )
         } else if qubit_count <= MAX_QUBITS_PER_THREADGROUP {
             // All qubits fit in one threadgroup
-            return (
+            (
                 1 << MAX_QUBITS_PER_THREAD,
                 1 << (qubit_count - MAX_QUBITS_PER_THREAD),
                 1
-            );
+            )
         } else if qubit_count <= 30 {
             // Then add more threadgroups
-            return (
+            (
                 1 << MAX_QUBITS_PER_THREAD,
                 1 << (MAX_QUBITS_PER_THREADGROUP - MAX_QUBITS_PER_THREAD),
                 1 << (qubit_count - MAX_QUBITS_PER_THREADGROUP)
-            );
+            )
         } else {
             panic!("Qubit count too high: {}", qubit_count);
         }
@@ -194,22 +390,44 @@ impl GpuContext {
             "Op struct must be 256 bytes for WebGPU dynamic buffer alignment"
         );
         let state_vector_entries: u64 = 2u64.pow(self.circuit.qubit_count as u32);
-        let result_buffer_size_bytes: u64 = std::mem::size_of::<Result>() as u64 * 100;
+        // Bindings can't be empty, so keep at least one result
+        let result_buffer_size_bytes: u64 = std::mem::size_of::<Result>() as u64 * self.config.results.capacity().max(1) as u64;
 
+        let state_vector_size: u64 = state_vector_entries * 2 * std::mem::size_of::<f32>() as u64; // 2 floats per complex entry
         let state_vector_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("StateVector Buffer"),
-            size: state_vector_entries * 2 * std::mem::size_of::<f32>() as u64, // 2 floats per complex entry
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            size: state_vector_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
+        // Permutation ops gather into a second full size state vector. As that doubles the memory
+        // needed, only allocate it if the circuit has any, else bind a single entry placeholder.
+        let needs_scratch = self.circuit.ops.iter().any(|op| op.op_id == ops::PERMUTATION);
+        let scratch_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("StateVector Scratch Buffer"),
+            size: if needs_scratch { state_vector_size } else { 2 * std::mem::size_of::<f32>() as u64 },
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let initial_state_buffer = self.create_initial_state_buffer();
+
+        // Number the measurements, resets and noise, so the shader knows which random number and outcome is each one's
+        let mut measurement_count = 0;
+        for op in self.circuit.ops.iter_mut().filter(|op| ops::is_stochastic(op.op_id)) {
+            op.q3 = measurement_count;
+            measurement_count += 1;
+        }
+        let measurement_count = measurement_count as usize;
+
         // Initialize ops buffer from the circuit using bytemuck
         let (ops_upload_buffer, ops_buffer) = self.circuit.create_ops_buffers(&self.device);
 
         let results_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Results Buffer"),
             size: result_buffer_size_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
@@ -220,54 +438,136 @@ impl GpuContext {
             mapped_at_creation: false,
         });
 
-        let result_idx_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Result Index Buffer"),
-            size: std::mem::size_of::<u32>() as u64,
+        let counters_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Counters Buffer"),
+            size: (COUNTER_WORDS * std::mem::size_of::<u32>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let counters_download_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Counters Download Buffer"),
+            size: 2 * std::mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        // One probability sum per thread
+        let thread_count = (self.threads_per_workgroup * self.workgroup_count) as u64;
+        let partials_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Measurement Partials Buffer"),
+            size: thread_count * std::mem::size_of::<[f32; 2]>() as u64,
             usage: wgpu::BufferUsages::STORAGE,
             mapped_at_creation: false,
         });
 
-        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("StateVector Bind Group"),
-            layout: &self.bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: state_vector_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    // Bind a 256-byte slice; dynamic offsets will move this window
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &ops_buffer,
-                        offset: 0,
-                        size: Some(NonZeroU64::new(256).unwrap()),
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: results_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: result_idx_buffer.as_entire_binding(),
-                },
-            ],
+        // Bindings can't be empty, so keep at least one entry
+        let measurements_size = (measurement_count.max(1) * std::mem::size_of::<Measurement>()) as u64;
+        let measurements_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Measurements Buffer"),
+            size: measurements_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let measurements_download_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Measurements Download Buffer"),
+            size: measurements_size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
         });
 
-        let pipeline = self
-            .device
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: Some("StateVector Pipeline"),
-                layout: Some(&self.device.create_pipeline_layout(
-                    &wgpu::PipelineLayoutDescriptor {
-                        label: Some("StateVector pipeline Layout"),
-                        bind_group_layouts: &[&self.bind_group_layout],
-                        push_constant_ranges: &[],
+        let samples_size = (SAMPLE_BATCH * std::mem::size_of::<u32>()) as u64;
+        let samples_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Samples Buffer"),
+            size: samples_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let samples_download_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Samples Download Buffer"),
+            size: samples_size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let run_params_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Run Params Buffer"),
+            size: std::mem::size_of::<RunParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let query_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Query Buffer"),
+            size: (TERM_BATCH * std::mem::size_of::<Op>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let create_bind_group = |state_in: &Buffer, state_out: &Buffer, ops: &Buffer| {
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("StateVector Bind Group"),
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: state_in.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        // Bind a 256-byte slice; dynamic offsets will move this window
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: ops,
+                            offset: 0,
+                            size: Some(NonZeroU64::new(256).unwrap()),
+                        }),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: results_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: counters_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: state_out.as_entire_binding(),
                     },
-                )),
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: partials_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: measurements_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: samples_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: run_params_buffer.as_entire_binding(),
+                    },
+                ],
+            })
+        };
+        let bind_group = create_bind_group(&state_vector_buffer, &scratch_buffer, &ops_buffer);
+        let swapped_bind_group = create_bind_group(&scratch_buffer, &state_vector_buffer, &ops_buffer);
+        let query_bind_group = create_bind_group(&state_vector_buffer, &scratch_buffer, &query_buffer);
+        let swapped_query_bind_group = create_bind_group(&scratch_buffer, &state_vector_buffer, &query_buffer);
+
+        let pipeline_layout = self.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("StateVector pipeline Layout"),
+            bind_group_layouts: &[&self.bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |label: &str, entry_point: &str| {
+            self.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
                 module: &self.shader_module,
-                entry_point: Some("run_statevector_ops"),
+                entry_point: Some(entry_point),
                 // When creating the pipeline, override the workgroup size based on the qubit count.
                 compilation_options: wgpu::PipelineCompilationOptions {
                     constants: &[
@@ -277,91 +577,495 @@ impl GpuContext {
                     ..Default::default()
                 },
                 cache: None,
-            });
+            })
+        };
+        let pipeline = create_pipeline("StateVector Pipeline", "run_statevector_ops");
+        let measure_pipeline = create_pipeline("Measure Probabilities Pipeline", "measure_probabilities");
+        let sample_pipeline = create_pipeline("Sample Measurement Pipeline", "sample_measurement");
+        let count_candidates_pipeline = create_pipeline("Count Candidates Pipeline", "count_candidates");
+        let start_selection_pipeline = create_pipeline("Start Selection Pipeline", "start_selection");
+        let histogram_digits_pipeline = create_pipeline("Histogram Digits Pipeline", "histogram_digits");
+        let select_digit_pipeline = create_pipeline("Select Digit Pipeline", "select_digit");
+        let collect_results_pipeline = create_pipeline("Collect Results Pipeline", "collect_results");
+        let scan_pipeline = create_pipeline("Scan Chunk Sums Pipeline", "scan_chunk_sums");
+        let sample_shots_pipeline = create_pipeline("Sample Shots Pipeline", "sample_shots");
+        let expectation_pipeline = create_pipeline("Pauli Expectation Pipeline", "pauli_expectation_partials");
+        let reduce_expectation_pipeline = create_pipeline("Reduce Expectation Pipeline", "reduce_expectation");
+        let marginal_pipeline = create_pipeline("Marginal Partials Pipeline", "marginal_partials");
+        let reduce_marginal_pipeline = create_pipeline("Reduce Marginal Pipeline", "reduce_marginal");
 
         self.resources = Some(GpuResources {
             pipeline,
+            measure_pipeline,
+            sample_pipeline,
+            count_candidates_pipeline,
+            start_selection_pipeline,
+            histogram_digits_pipeline,
+            select_digit_pipeline,
+            collect_results_pipeline,
+            scan_pipeline,
+            sample_shots_pipeline,
+            expectation_pipeline,
+            reduce_expectation_pipeline,
+            marginal_pipeline,
+            reduce_marginal_pipeline,
             state_vector_buffer,
+            scratch_buffer,
+            initial_state_buffer,
             ops_upload_buffer,
             ops_buffer,
             results_buffer,
+            counters_buffer,
             download_buffer,
+            counters_download_buffer,
+            measurements_buffer,
+            measurements_download_buffer,
+            measurement_count,
+            samples_buffer,
+            samples_download_buffer,
+            run_params_buffer,
+            query_buffer,
             bind_group,
+            swapped_bind_group,
+            query_bind_group,
+            swapped_query_bind_group,
         });
     }
 
+    /// Run the circuit from the initial state and return the entries selected by the final MEVERYZ op (see
+    /// ResultSelection), likeliest first. Entry indices are over the circuit's original qubit labels.
     pub async fn run(&self) -> Vec<Result> {
-        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
-
-        // Initialize the first entry of the state vector to |0> (the rest are already zeroed)
-        let state_init_buffer = self.device.create_buffer(&BufferDescriptor {
-            label: Some("State init buffer"),
-            size: std::mem::size_of::<f32>() as u64 * 2,
-            usage: BufferUsages::MAP_WRITE | BufferUsages::COPY_SRC,
-            mapped_at_creation: true,
-        });
+        self.run_with_outcomes().await.results
+    }
 
-        // Upload the ops data and unmap
-        let entry_0: [f32; 2] = [1.0, 0.0]; // Initial state |0>
-        state_init_buffer
-            .slice(..)
-            .get_mapped_range_mut()
-            .copy_from_slice(bytemuck::cast_slice(&entry_0));
-        state_init_buffer.unmap();
+    /// Run the circuit as for `run`, also returning how many results didn't fit and the classical
+    /// results written by its measurements.
+    pub async fn run_with_outcomes(&self) -> RunOutput {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
 
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("StateVector Command Encoder"),
             });
+        self.encode_ops(&mut encoder, self.circuit.ops.len());
 
-        // Copy the upload buffers into the state vector and ops buffers on the GPU
+        // Copy the results to the download buffers
         encoder.copy_buffer_to_buffer(
-            &state_init_buffer, 0, &resources.state_vector_buffer, 0, state_init_buffer.size()
+            &resources.results_buffer,
+            0,
+            &resources.download_buffer,
+            0,
+            resources.download_buffer.size(),
+        );
+        encoder.copy_buffer_to_buffer(
+            &resources.counters_buffer,
+            0,
+            &resources.counters_download_buffer,
+            0,
+            resources.counters_download_buffer.size(),
         );
 
+        let command_buffer = encoder.finish();
+        self.queue.submit([command_buffer]);
+
+        let mut results: Vec<Result> = self.read_buffer(&resources.download_buffer).await;
+        let counters: Vec<u32> = self.read_buffer(&resources.counters_download_buffer).await;
+        let (written, candidates) = (counters[0] as usize, counters[1] as usize);
+        results.truncate(written.min(results.len()));
+        let outcomes = self.read_outcomes().await;
+
+        if DO_CAPTURE {
+            unsafe {
+                self.device.stop_graphics_debugger_capture();
+            }
+        }
+
+        // Report entries over the original qubit labels if the circuit was remapped
+        for result in results.iter_mut() {
+            result.entry_idx = self.circuit.logical_index(result.entry_idx);
+        }
+        sort_results(&mut results);
+        RunOutput { overflow: candidates - results.len(), results, outcomes }
+    }
+
+    // The classical results written by the measurements of the last run, later ones overwriting earlier
+    async fn read_outcomes(&self) -> Vec<u32> {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Measurements Readback Encoder"),
+        });
         encoder.copy_buffer_to_buffer(
-            &resources.ops_upload_buffer,
+            &resources.measurements_buffer,
             0,
-            &resources.ops_buffer,
+            &resources.measurements_download_buffer,
             0,
-            resources.ops_buffer.size(),
+            resources.measurements_download_buffer.size(),
         );
+        self.queue.submit([encoder.finish()]);
+
+        let measured: Vec<Measurement> = self.read_buffer(&resources.measurements_download_buffer).await;
+        let mut outcomes = vec![0; self.circuit.result_count()];
+        for op in self.circuit.ops.iter().filter(|op| ops::is_measurement(op.op_id)) {
+            outcomes[op.q2 as usize] = measured[op.q3 as usize].outcome;
+        }
+        outcomes
+    }
 
+    /// Sample `shots` shots, counting the bitstrings of the measured qubits (see Readout).
+    ///
+    /// Where the state can be sampled directly, the circuit runs once and each shot searches a
+    /// CDF of the final state on the GPU. Otherwise the circuit runs again for each shot.
+    pub async fn sample(&self, shots: usize) -> Counts {
+        let mut counts = Counts::new();
+        let (stop, bits) = match Readout::for_circuit(&self.circuit) {
+            Readout::PerShot { stop, bits } => {
+                // The final state is sampled with the draw after the measurements'
+                let draws = self.circuit.ops[..stop].iter().filter(|op| ops::is_stochastic(op.op_id)).count();
+                for _ in 0..shots {
+                    let (shot, swapped) = self.build_cdf(stop);
+                    let entry_idx =
+                        if bits.is_empty() { 0 } else { self.sample_cdf(shot, swapped, draws as u32, 1).await[0] };
+                    let outcomes = self.read_outcomes().await;
+                    *counts.entry(shot_bitstring(&outcomes, entry_idx, &bits)).or_default() += 1;
+                }
+                return counts;
+            }
+            Readout::Trajectories { stop, bits } => {
+                // Each shot follows its own trajectory, then samples the state it leaves with the
+                // draw after the noise's
+                let first_draw = self.circuit.ops[..stop].iter().filter(|op| ops::is_stochastic(op.op_id)).count();
+                for _ in 0..shots {
+                    let (shot, swapped) = self.build_cdf(stop);
+                    let samples = self.sample_cdf(shot, swapped, first_draw as u32, 1).await;
+                    *counts.entry(state_bitstring(samples[0], &bits)).or_default() += 1;
+                }
+                return counts;
+            }
+            Readout::FinalState { stop, bits } => (stop, bits),
+        };
+
+        // Run the circuit once, then search the CDF of its state for a batch of shots at a time
+        let (shot, swapped) = self.build_cdf(stop);
+        for batch_start in (0..shots).step_by(SAMPLE_BATCH) {
+            let batch_len = SAMPLE_BATCH.min(shots - batch_start);
+            let samples = self.sample_cdf(shot, swapped, batch_start as u32, batch_len).await;
+            for &entry_idx in &samples {
+                *counts.entry(state_bitstring(entry_idx, &bits)).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    // Run up to the measurements, then sum the probabilities of each thread's chunk of the state
+    // and scan the sums into a CDF over the chunks. Returns the shot number and whether the state
+    // ended in the scratch buffer.
+    fn build_cdf(&self, stop: usize) -> (u64, bool) {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Sampling Command Encoder"),
+        });
+        let (shot, swapped) = self.encode_ops(&mut encoder, stop);
+        let bind_group = if swapped { &resources.swapped_bind_group } else { &resources.bind_group };
         let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("StateVector Compute Pass"),
+            label: Some("CDF Compute Pass"),
             timestamp_writes: None,
         });
+        compute_pass.set_bind_group(0, bind_group, &[0]);
+        compute_pass.set_pipeline(&resources.measure_pipeline);
+        compute_pass.dispatch_workgroups(self.workgroup_count as u32, 1, 1);
+        compute_pass.set_pipeline(&resources.scan_pipeline);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+        drop(compute_pass);
+        self.queue.submit([encoder.finish()]);
+        (shot, swapped)
+    }
 
-        compute_pass.set_pipeline(&resources.pipeline);
+    // Sample `count` basis states from the CDF left by build_cdf, using the shot's random draws
+    // from `first_draw` on
+    async fn sample_cdf(&self, shot: u64, swapped: bool, first_draw: u32, count: usize) -> Vec<u32> {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        self.write_run_params(shot, first_draw, count as u32);
 
-        let op_count = self.circuit.ops.len() as u32;
-        let workgroup_count: u32 = self.workgroup_count as u32;
-        for i in 0..op_count {
-            let op_offset: u32 = i * 256; // Each op is 256 bytes (aligned)
-            compute_pass.set_bind_group(0, &resources.bind_group, &[op_offset]);
-            compute_pass.dispatch_workgroups(workgroup_count, 1, 1);
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Sampling Command Encoder"),
+        });
+        let bind_group = if swapped { &resources.swapped_bind_group } else { &resources.bind_group };
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Sampling Compute Pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_bind_group(0, bind_group, &[0]);
+        compute_pass.set_pipeline(&resources.sample_shots_pipeline);
+        compute_pass.dispatch_workgroups(count.div_ceil(SAMPLE_THREADS) as u32, 1, 1);
+        drop(compute_pass);
+        // Only copy and map the samples drawn, as trajectories draw a single sample per run. Mapped
+        // ranges must be a multiple of 8 bytes.
+        let bytes = ((count * std::mem::size_of::<u32>()) as u64).next_multiple_of(wgpu::MAP_ALIGNMENT);
+        encoder.copy_buffer_to_buffer(&resources.samples_buffer, 0, &resources.samples_download_buffer, 0, bytes);
+        self.queue.submit([encoder.finish()]);
+
+        let mut samples: Vec<u32> = self.read_buffer_prefix(&resources.samples_download_buffer, bytes).await;
+        samples.truncate(count);
+        samples
+    }
+
+    /// The expectation value of a Pauli sum, e.g. the energy of a Hamiltonian, in the state left by
+    /// the last run. Each term is reduced on the GPU, so only one number per term is read back.
+    pub async fn expectation(&self, hamiltonian: &PauliSum) -> f64 {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let terms: Vec<PauliTerm> =
+            hamiltonian.terms.iter().filter_map(|term| term.on_circuit_qubits(&self.circuit)).collect();
+        let bind_group =
+            if self.state_swapped.get() { &resources.swapped_query_bind_group } else { &resources.query_bind_group };
+
+        let mut total = 0.0;
+        for batch in terms.chunks(TERM_BATCH) {
+            let term_ops: Vec<Op> = batch.iter().enumerate().map(|(i, term)| {
+                let mut op = Op::pauli_exp(term.x_mask, term.z_mask, 0.0);
+                op.q3 = i as u32;
+                op
+            }).collect();
+            self.queue.write_buffer(&resources.query_buffer, 0, bytemuck::cast_slice(&term_ops));
+
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Expectation Command Encoder"),
+            });
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Expectation Compute Pass"),
+                timestamp_writes: None,
+            });
+            for i in 0..batch.len() {
+                compute_pass.set_bind_group(0, bind_group, &[i as u32 * 256]);
+                compute_pass.set_pipeline(&resources.expectation_pipeline);
+                compute_pass.dispatch_workgroups(self.workgroup_count as u32, 1, 1);
+                compute_pass.set_pipeline(&resources.reduce_expectation_pipeline);
+                compute_pass.dispatch_workgroups(1, 1, 1);
+            }
+            drop(compute_pass);
+            encoder.copy_buffer_to_buffer(
+                &resources.samples_buffer,
+                0,
+                &resources.samples_download_buffer,
+                0,
+                (batch.len() * std::mem::size_of::<f32>()) as u64,
+            );
+            self.queue.submit([encoder.finish()]);
+
+            let values: Vec<f32> = self.read_buffer(&resources.samples_download_buffer).await;
+            total += batch.iter().zip(&values).map(|(term, &value)| term.coefficient as f64 * value as f64).sum::<f64>();
         }
+        total
+    }
 
-        drop(compute_pass);
+    /// The probability of each value of the given qubits (by original label), summing over the
+    /// others, in the state left by the last run. Bit i of each index is the value of qubits[i].
+    /// The sums are reduced on the GPU, so only the 2^qubits.len() probabilities are read back.
+    pub async fn marginal_probabilities(&self, qubits: &[u32]) -> Vec<f32> {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let simulated = marginal_qubits(&self.circuit, qubits);
+        let kept_mask: u32 = simulated.iter().flatten().fold(0, |mask, q| mask | (1 << q));
+        let rest_bits = self.circuit.qubit_count as u32 - kept_mask.count_ones();
+        let bin_count = 1usize << kept_mask.count_ones();
+        let batch_bins = bin_count.min(SAMPLE_BATCH);
+        let bind_group =
+            if self.state_swapped.get() { &resources.swapped_query_bind_group } else { &resources.query_bind_group };
 
-        // Copy the results to the download buffer
+        // Sum over the simulated kept qubits in ascending order, a batch of bins at a time
+        let mut sorted_marginal: Vec<f32> = Vec::with_capacity(bin_count);
+        for bin_start in (0..bin_count).step_by(batch_bins) {
+            // The kernels only read the kept qubits and the range of bins
+            let mut op = Op::new(ops::ID, bin_start as u32, batch_bins as u32, 0, 0.0);
+            op.data[0] = kept_mask;
+            self.queue.write_buffer(&resources.query_buffer, 0, bytemuck::bytes_of(&op));
+            let thread_count = (batch_bins << rest_bits).div_ceil(1 << MAX_QUBITS_PER_THREAD);
+
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Marginal Command Encoder"),
+            });
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Marginal Compute Pass"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_bind_group(0, bind_group, &[0]);
+            compute_pass.set_pipeline(&resources.marginal_pipeline);
+            compute_pass.dispatch_workgroups(thread_count.div_ceil(self.threads_per_workgroup as usize) as u32, 1, 1);
+            compute_pass.set_pipeline(&resources.reduce_marginal_pipeline);
+            compute_pass.dispatch_workgroups(batch_bins.div_ceil(REDUCE_THREADS) as u32, 1, 1);
+            drop(compute_pass);
+            encoder.copy_buffer_to_buffer(
+                &resources.samples_buffer,
+                0,
+                &resources.samples_download_buffer,
+                0,
+                (batch_bins * std::mem::size_of::<f32>()) as u64,
+            );
+            self.queue.submit([encoder.finish()]);
+
+            let probabilities: Vec<f32> = self.read_buffer(&resources.samples_download_buffer).await;
+            sorted_marginal.extend_from_slice(&probabilities[..batch_bins]);
+        }
+
+        // Then move each qubit's bit to where it was asked for. Qubits that aren't simulated are always 0.
+        let ranks: Vec<Option<u32>> =
+            simulated.iter().map(|q| q.map(|q| (kept_mask & ((1 << q) - 1)).count_ones())).collect();
+        let mut marginal = vec![0.0; 1 << qubits.len()];
+        for (sorted_bin, &probability) in sorted_marginal.iter().enumerate() {
+            let bin = ranks.iter().enumerate()
+                .filter(|(_, rank)| rank.is_some_and(|rank| sorted_bin & (1 << rank) != 0))
+                .fold(0, |bin, (i, _)| bin | (1 << i));
+            marginal[bin] = probability;
+        }
+        marginal
+    }
+
+    /// The amplitudes left by the last run, indexed by the simulated qubits (see Circuit::logical_index).
+    /// After `sample`, this is the state before any measurements at the end of the circuit.
+    pub async fn state_vector(&self) -> Vec<Complex32> {
+        self.state_vector_range(0..1 << self.circuit.qubit_count).await
+    }
+
+    /// The amplitudes at a range of indices of the state left by the last run
+    pub async fn state_vector_range(&self, indices: Range<usize>) -> Vec<Complex32> {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let entry_count = 1usize << self.circuit.qubit_count;
+        assert!(
+            indices.start <= indices.end && indices.end <= entry_count,
+            "Index range {:?} is out of bounds for {} entries", indices, entry_count
+        );
+        let source = if self.state_swapped.get() { &resources.scratch_buffer } else { &resources.state_vector_buffer };
+
+        // Copy through a staging buffer a chunk at a time, so large states don't need a second
+        // buffer of the same size
+        let entry_bytes = std::mem::size_of::<Complex32>() as u64;
+        let (start, end) = (indices.start as u64 * entry_bytes, indices.end as u64 * entry_bytes);
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("StateVector Staging Buffer"),
+            size: STATE_CHUNK_BYTES.min(end - start).max(entry_bytes),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut amplitudes = Vec::with_capacity(indices.len());
+        for offset in (start..end).step_by(STATE_CHUNK_BYTES as usize) {
+            let size = STATE_CHUNK_BYTES.min(end - offset);
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("StateVector Readback Encoder"),
+            });
+            encoder.copy_buffer_to_buffer(source, offset, &staging_buffer, 0, size);
+            self.queue.submit([encoder.finish()]);
+
+            let chunk: Vec<Complex32> = self.read_buffer(&staging_buffer).await;
+            amplitudes.extend_from_slice(&chunk[..(size / entry_bytes) as usize]);
+        }
+        amplitudes
+    }
+
+    // Encode a run of the first `op_count` ops from the initial state, returning the shot number and whether
+    // the current state ended in the scratch buffer
+    fn encode_ops(&self, encoder: &mut wgpu::CommandEncoder, op_count: usize) -> (u64, bool) {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let shot = self.shot.get();
+        self.shot.set(shot + 1);
+        self.write_run_params(shot, 0, 0);
+
+        // Start from the initial state, replacing anything left from a previous run
+        let initial_state_buffer = &resources.initial_state_buffer;
+        if let InitialState::Basis(idx) = self.initial_state {
+            encoder.clear_buffer(&resources.state_vector_buffer, 0, None);
+            let offset = idx as u64 * std::mem::size_of::<Complex32>() as u64;
+            encoder.copy_buffer_to_buffer(
+                initial_state_buffer, 0, &resources.state_vector_buffer, offset, initial_state_buffer.size()
+            );
+        } else {
+            encoder.copy_buffer_to_buffer(
+                initial_state_buffer, 0, &resources.state_vector_buffer, 0, initial_state_buffer.size()
+            );
+        }
+        encoder.clear_buffer(&resources.results_buffer, 0, None);
+        encoder.clear_buffer(&resources.counters_buffer, 0, None);
+
+        // Copy the ops into the buffer the shader reads
         encoder.copy_buffer_to_buffer(
-            &resources.results_buffer,
+            &resources.ops_upload_buffer,
             0,
-            &resources.download_buffer,
+            &resources.ops_buffer,
             0,
-            resources.download_buffer.size(),
+            resources.ops_buffer.size(),
         );
 
-        let command_buffer = encoder.finish();
-        self.queue.submit([command_buffer]);
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("StateVector Compute Pass"),
+            timestamp_writes: None,
+        });
+
+        let workgroup_count: u32 = self.workgroup_count as u32;
+        let mut swapped = false;
+        for (i, op) in self.circuit.ops[..op_count].iter().enumerate() {
+            let op_offset: u32 = i as u32 * 256; // Each op is 256 bytes (aligned)
+            let bind_group = if swapped { &resources.swapped_bind_group } else { &resources.bind_group };
+            compute_pass.set_bind_group(0, bind_group, &[op_offset]);
+            // Sample the outcome before the main pipeline collapses the state to it
+            if op.op_id == ops::MEVERYZ {
+                // Select the results to report (see shader.wgsl), a pass for each digit of the keys
+                compute_pass.set_pipeline(&resources.count_candidates_pipeline);
+                compute_pass.dispatch_workgroups(workgroup_count, 1, 1);
+                compute_pass.set_pipeline(&resources.start_selection_pipeline);
+                compute_pass.dispatch_workgroups(1, 1, 1);
+                let index_digits = (self.circuit.qubit_count as u32).div_ceil(8);
+                for _ in 0..PROBABILITY_DIGITS + index_digits {
+                    compute_pass.set_pipeline(&resources.histogram_digits_pipeline);
+                    compute_pass.dispatch_workgroups(workgroup_count, 1, 1);
+                    compute_pass.set_pipeline(&resources.select_digit_pipeline);
+                    compute_pass.dispatch_workgroups(1, 1, 1);
+                }
+                compute_pass.set_pipeline(&resources.collect_results_pipeline);
+                compute_pass.dispatch_workgroups(workgroup_count, 1, 1);
+                continue;
+            }
+            if ops::collapses_state(op.op_id) || ops::is_damping(op.op_id) {
+                compute_pass.set_pipeline(&resources.measure_pipeline);
+                compute_pass.dispatch_workgroups(workgroup_count, 1, 1);
+                compute_pass.set_pipeline(&resources.sample_pipeline);
+                compute_pass.dispatch_workgroups(1, 1, 1);
+            }
+            compute_pass.set_pipeline(&resources.pipeline);
+            compute_pass.dispatch_workgroups(workgroup_count, 1, 1);
+            // The permuted state was written to the other buffer, which is now the current state
+            if op.op_id == ops::PERMUTATION {
+                swapped = !swapped;
+            }
+        }
+        self.state_swapped.set(swapped);
+        (shot, swapped)
+    }
+
+    // Set the run params for the next submission
+    fn write_run_params(&self, shot: u64, sample_offset: u32, sample_count: u32) {
+        let resources: &GpuResources = self.resources.as_ref().expect("Resources not initialized");
+        let params = RunParams {
+            seed: [self.config.seed as u32, (self.config.seed >> 32) as u32],
+            shot: [shot as u32, (shot >> 32) as u32],
+            sample_offset,
+            sample_count,
+            result_threshold: self.config.results.threshold(),
+            result_capacity: self.config.results.capacity() as u32,
+        };
+        self.queue.write_buffer(&resources.run_params_buffer, 0, bytemuck::bytes_of(&params));
+    }
+
+    // Wait for the submitted work, then copy out the contents of a download buffer
+    async fn read_buffer<T: bytemuck::Pod>(&self, buffer: &Buffer) -> Vec<T> {
+        self.read_buffer_prefix(buffer, buffer.size()).await
+    }
 
+    // Read the first `bytes` bytes of a buffer
+    async fn read_buffer_prefix<T: bytemuck::Pod>(&self, buffer: &Buffer, bytes: u64) -> Vec<T> {
         // Fetching the actual results is a real pain. For details, see:
         // https://github.com/gfx-rs/wgpu/blob/v26/examples/features/src/repeated_compute/mod.rs#L74
 
         // Cross-platform readback: async map + native poll
-        let buffer_slice = resources.download_buffer.slice(..);
+        let buffer_slice = buffer.slice(..bytes);
 
         let (sender, receiver) = futures::channel::oneshot::channel();
 
@@ -376,16 +1080,9 @@ impl GpuContext {
 
         // Read, copy out, and unmap.
         let data = buffer_slice.get_mapped_range();
-        let results: Vec<Result> = bytemuck::cast_slice(&data).to_vec();
+        let contents: Vec<T> = bytemuck::cast_slice(&data).to_vec();
         drop(data);
-        resources.download_buffer.unmap();
-
-        if DO_CAPTURE {
-            unsafe {
-                self.device.stop_graphics_debugger_capture();
-            }
-        }
-
-        results
+        buffer.unmap();
+        contents
     }
 }
@@ -0,0 +1,398 @@
+#![allow(unused)]
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::circuit::Circuit;
+use crate::shader_types::{ops, Complex32, Op};
+use crate::transpile::decompose_pauli_exp;
+
+// Hamiltonians as weighted sums of Pauli strings, and Trotter/Suzuki circuits simulating them.
+
+/// A real multiple of a Pauli string, with the same masks as a PAULI_EXP op: X on qubits only in
+/// the X mask, Z on qubits only in the Z mask, and Y on qubits in both.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PauliTerm {
+    pub coefficient: f32,
+    pub x_mask: u32,
+    pub z_mask: u32,
+}
+
+impl PauliTerm {
+    /// Parse a sparse Pauli label like "X0 Z1 Y3", or "I" (or an empty label) for the identity.
+    pub fn from_label(coefficient: f32, label: &str) -> Result<Self, String> {
+        let mut term = PauliTerm { coefficient, x_mask: 0, z_mask: 0 };
+        for token in label.split_whitespace() {
+            if token.eq_ignore_ascii_case("I") {
+                continue;
+            }
+            let mut chars = token.chars();
+            let pauli = chars.next().unwrap().to_ascii_uppercase();
+            let q = chars.as_str().parse::<u32>()
+                .ok()
+                .filter(|&q| q < 32)
+                .ok_or_else(|| format!("Invalid Pauli '{}', expected a letter and a qubit like X3", token))?;
+            let bit = 1u32 << q;
+            if (term.x_mask | term.z_mask) & bit != 0 {
+                return Err(format!("Qubit {} appears more than once in '{}'", q, label));
+            }
+            match pauli {
+                'X' => term.x_mask |= bit,
+                'Y' => { term.x_mask |= bit; term.z_mask |= bit; }
+                'Z' => term.z_mask |= bit,
+                'I' => {}
+                other => return Err(format!("Invalid Pauli '{}' in '{}'", other, label)),
+            }
+        }
+        Ok(term)
+    }
+
+    /// The label in the format from_label parses, e.g. "X0 Z1 Y3"
+    pub fn label(&self) -> String {
+        let qubits = self.x_mask | self.z_mask;
+        if qubits == 0 {
+            return "I".to_string();
+        }
+        (0..32)
+            .filter(|q| qubits & (1 << q) != 0)
+            .map(|q| {
+                let pauli = match (self.x_mask & (1 << q) != 0, self.z_mask & (1 << q) != 0) {
+                    (true, true) => 'Y',
+                    (true, false) => 'X',
+                    _ => 'Z',
+                };
+                format!("{}{}", pauli, q)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.x_mask | self.z_mask == 0
+    }
+
+    /// The term over the simulated qubits of a circuit whose qubits were compacted or reordered
+    /// (see Circuit::logical_qubit). Qubits the circuit doesn't simulate stay in |0>, so a Z on one
+    /// is dropped, and an X or Y on one makes the expectation 0, giving None.
+    pub fn on_circuit_qubits(&self, circuit: &Circuit) -> Option<PauliTerm> {
+        let mut term = PauliTerm { coefficient: self.coefficient, x_mask: 0, z_mask: 0 };
+        for q in (0..32).filter(|q| (self.x_mask | self.z_mask) & (1 << q) != 0) {
+            match circuit.simulated_qubit(q) {
+                Some(s) => {
+                    term.x_mask |= ((self.x_mask >> q) & 1) << s;
+                    term.z_mask |= ((self.z_mask >> q) & 1) << s;
+                }
+                None if self.x_mask & (1 << q) != 0 => return None,
+                None => {}
+            }
+        }
+        Some(term)
+    }
+
+    /// True if the two Pauli strings commute (ignoring the coefficients)
+    pub fn commutes_with(&self, other: &PauliTerm) -> bool {
+        ((self.x_mask & other.z_mask) ^ (self.z_mask & other.x_mask)).count_ones().is_multiple_of(2)
+    }
+}
+
+/// A weighted sum of Pauli strings, e.g. a Hamiltonian.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PauliSum {
+    pub terms: Vec<PauliTerm>,
+}
+
+impl PauliSum {
+    /// Parse a Pauli sum from text or JSON.
+    ///
+    /// The text format has one term per line, a coefficient then a sparse Pauli label:
+    ///   "-1.0 Z0 Z1"
+    ///   "-0.5 X0"
+    /// with '#' comments. The JSON format is an object from labels to coefficients:
+    ///   {"Z0 Z1": -1.0, "X0": -0.5}
+    pub fn from_str(src: &str) -> Result<Self, String> {
+        if src.trim_start().starts_with('{') {
+            return Self::from_json_str(src);
+        }
+        let mut terms = Vec::new();
+        for (lineno, raw_line) in src.lines().enumerate() {
+            let line = raw_line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (coefficient, label) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let coefficient = coefficient.parse::<f32>()
+                .map_err(|_| format!("Line {}: invalid coefficient: {}", lineno + 1, coefficient))?;
+            terms.push(PauliTerm::from_label(coefficient, label).map_err(|err| format!("Line {}: {}", lineno + 1, err))?);
+        }
+        Ok(PauliSum { terms })
+    }
+
+    /// Parse a JSON object from Pauli labels to coefficients, e.g. {"Z0 Z1": -1.0, "X0": -0.5}
+    pub fn from_json_str(src: &str) -> Result<Self, String> {
+        let mut parser = JsonParser { src: src.as_bytes(), pos: 0 };
+        let mut terms = Vec::new();
+        parser.expect(b'{')?;
+        if !parser.eat(b'}') {
+            loop {
+                let label = parser.string()?;
+                parser.expect(b':')?;
+                let coefficient = parser.number()?;
+                terms.push(PauliTerm::from_label(coefficient, &label)?);
+                if parser.eat(b'}') {
+                    break;
+                }
+                parser.expect(b',')?;
+            }
+        }
+        parser.skip_whitespace();
+        if parser.pos != parser.src.len() {
+            return Err(format!("Unexpected text after the JSON object at byte {}", parser.pos));
+        }
+        Ok(PauliSum { terms })
+    }
+
+    /// Number of qubits up to the highest one any term acts on
+    pub fn qubit_count(&self) -> u32 {
+        let used = self.terms.iter().fold(0u32, |acc, t| acc | t.x_mask | t.z_mask);
+        32 - used.leading_zeros()
+    }
+
+    /// Sum of the absolute values of the coefficients, which bounds the norm of the operator
+    pub fn one_norm(&self) -> f64 {
+        self.terms.iter().map(|t| t.coefficient.abs() as f64).sum()
+    }
+
+    /// Build a circuit approximating e^(-i * H * time) with the given number of Trotter steps.
+    ///
+    /// Order 1 applies e^(-i * c * P * dt) for each term in turn. Order 2 is the symmetric (Strang)
+    /// splitting, and order 4 Suzuki's recursion of five order 2 steps. Each exponential is a
+    /// PAULI_EXP op, and neighbouring exponentials of the same string are merged. Identity terms
+    /// only add a global phase, so are dropped.
+    pub fn trotter_circuit(&self, time: f32, steps: usize, order: u32) -> Result<TrotterCircuit, String> {
+        if steps == 0 {
+            return Err("Trotter step count must be at least 1".to_string());
+        }
+        let dt = time as f64 / steps as f64;
+        let stages = match order {
+            1 => vec![(dt, false)],
+            2 => strang(dt),
+            4 => {
+                let p = 1.0 / (4.0 - 4f64.powf(1.0 / 3.0));
+                [p, p, 1.0 - 4.0 * p, p, p].iter().flat_map(|&weight| strang(weight * dt)).collect()
+            }
+            other => return Err(format!("Unsupported Trotter order {}, expected 1, 2 or 4", other)),
+        };
+
+        let terms: Vec<&PauliTerm> = self.terms.iter().filter(|t| !t.is_identity()).collect();
+        let mut ops_out: Vec<Op> = Vec::new();
+        for _ in 0..steps {
+            for &(duration, reversed) in &stages {
+                let mut push = |term: &PauliTerm| {
+                    let angle = (term.coefficient as f64 * duration) as f32;
+                    match ops_out.last_mut() {
+                        Some(last) if last.pauli_masks() == (term.x_mask, term.z_mask) => last.angle += angle,
+                        _ => ops_out.push(Op::pauli_exp(term.x_mask, term.z_mask, angle)),
+                    }
+                };
+                if reversed {
+                    terms.iter().rev().for_each(|t| push(t));
+                } else {
+                    terms.iter().for_each(|t| push(t));
+                }
+            }
+        }
+
+        let exponentials = ops_out.len();
+        let (mut cx_count, mut single_qubit_count) = (0, 0);
+        for op in &ops_out {
+            let (gates, _) = decompose_pauli_exp(op).unwrap();
+            cx_count += gates.iter().filter(|g| g.op_id == ops::CX).count();
+            single_qubit_count += gates.iter().filter(|g| g.op_id != ops::CX).count();
+        }
+        ops_out.push(Op::new(ops::MEVERYZ, 0, 0, 0, 0.0));
+
+        let circuit = Circuit { qubit_count: self.qubit_count() as i32, ops: ops_out, logical_qubits: Vec::new(), initial_logical_qubits: Vec::new() };
+        Ok(TrotterCircuit {
+            circuit,
+            order,
+            steps,
+            error_bound: steps as f64 * self.step_error_bound(dt, order),
+            exponentials,
+            cx_count,
+            single_qubit_count,
+        })
+    }
+
+    // Bound on the spectral norm of S(dt) - e^(-i * H * dt) for a single step.
+    //
+    // Orders 1 and 2 use the commutator bounds of Childs et al, "Theory of Trotter Error" (2021),
+    // with each nested commutator's norm bounded by the 1-norm of its Pauli expansion. Order 4 uses
+    // the Taylor remainder: both products agree up to dt^4, and the remaining terms of each are at
+    // most x^5 / 5! * e^x, where x is the total norm of the exponents.
+    fn step_error_bound(&self, dt: f64, order: u32) -> f64 {
+        let terms: Vec<PauliTerm> = self.terms.iter().copied().filter(|t| !t.is_identity()).collect();
+        match order {
+            1 => {
+                let mut sum = 0.0;
+                for (j, a) in terms.iter().enumerate() {
+                    for b in &terms[j + 1..] {
+                        sum += one_norm(&commutator(&[*a], &[*b]));
+                    }
+                }
+                sum * dt * dt / 2.0
+            }
+            2 => {
+                let (mut outer, mut inner) = (0.0, 0.0);
+                for (j, a) in terms.iter().enumerate() {
+                    let rest = &terms[j + 1..];
+                    let first = commutator(rest, &[*a]);
+                    outer += one_norm(&commutator_sums(&as_sum(rest), &first));
+                    inner += one_norm(&commutator_sums(&as_sum(&[*a]), &commutator(&[*a], rest)));
+                }
+                dt.powi(3) * (outer / 12.0 + inner / 24.0)
+            }
+            _ => {
+                let p = 1.0 / (4.0 - 4f64.powf(1.0 / 3.0));
+                let x = (4.0 * p + (1.0 - 4.0 * p).abs()) * self.one_norm() * dt;
+                2.0 * x.powi(5) / 120.0 * x.exp()
+            }
+        }
+    }
+}
+
+// The two half steps of a Strang splitting, as (duration, reversed term order)
+fn strang(dt: f64) -> Vec<(f64, bool)> {
+    vec![(dt / 2.0, false), (dt / 2.0, true)]
+}
+
+// Pauli expansions with complex coefficients, keyed by (X mask, Z mask)
+type PauliExpansion = BTreeMap<(u32, u32), Complex32>;
+
+fn as_sum(terms: &[PauliTerm]) -> PauliExpansion {
+    let mut sum = PauliExpansion::new();
+    for t in terms {
+        let entry = sum.entry((t.x_mask, t.z_mask)).or_insert(Complex32::ZERO);
+        *entry = *entry + Complex32::new(t.coefficient, 0.0);
+    }
+    sum
+}
+
+fn commutator(a: &[PauliTerm], b: &[PauliTerm]) -> PauliExpansion {
+    commutator_sums(&as_sum(a), &as_sum(b))
+}
+
+// [A, B] = AB - BA. Commuting strings cancel, and anticommuting ones give 2AB.
+fn commutator_sums(a: &PauliExpansion, b: &PauliExpansion) -> PauliExpansion {
+    let mut out = PauliExpansion::new();
+    for (&(ax, az), &ca) in a {
+        for (&(bx, bz), &cb) in b {
+            if ((ax & bz) ^ (az & bx)).count_ones().is_multiple_of(2) {
+                continue;
+            }
+            let (phase, key) = pauli_product((ax, az), (bx, bz));
+            let entry = out.entry(key).or_insert(Complex32::ZERO);
+            *entry = *entry + (ca * cb * phase).scale(2.0);
+        }
+    }
+    out
+}
+
+// P * Q = phase * R for Pauli strings, using the per-qubit exponents of i from Aaronson and
+// Gottesman's stabilizer simulation (X * Y = iZ, Y * Z = iX, Z * X = iY)
+fn pauli_product((ax, az): (u32, u32), (bx, bz): (u32, u32)) -> (Complex32, (u32, u32)) {
+    let mut exponent: i32 = 0;
+    for q in 0..32 {
+        let bit = |mask: u32| ((mask >> q) & 1) as i32;
+        let (x1, z1, x2, z2) = (bit(ax), bit(az), bit(bx), bit(bz));
+        exponent += match (x1, z1) {
+            (0, 0) => 0,
+            (1, 1) => z2 - x2,
+            (1, 0) => z2 * (2 * x2 - 1),
+            _ => x2 * (1 - 2 * z2),
+        };
+    }
+    let phase = [Complex32::ONE, Complex32::I, -Complex32::ONE, -Complex32::I][exponent.rem_euclid(4) as usize];
+    (phase, (ax ^ bx, az ^ bz))
+}
+
+fn one_norm(sum: &PauliExpansion) -> f64 {
+    sum.values().map(|c| c.abs() as f64).sum()
+}
+
+/// A Trotter circuit and the resources it needs
+pub struct TrotterCircuit {
+    pub circuit: Circuit,
+    pub order: u32,
+    pub steps: usize,
+    /// Upper bound on the spectral norm distance between the circuit's unitary and e^(-i * H * time)
+    pub error_bound: f64,
+    /// PAULI_EXP ops in the circuit
+    pub exponentials: usize,
+    /// Gates once each exponential is decomposed into basis changes, a CX ladder and an RZ
+    pub cx_count: usize,
+    pub single_qubit_count: usize,
+}
+
+impl fmt::Display for TrotterCircuit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Order:             {}", self.order)?;
+        writeln!(f, "Steps:             {}", self.steps)?;
+        writeln!(f, "Error bound:       {:e}", self.error_bound)?;
+        writeln!(f, "Exponentials:      {}", self.exponentials)?;
+        writeln!(f, "  CX gates:        {}", self.cx_count)?;
+        write!(f, "  1q gates:        {}", self.single_qubit_count)
+    }
+}
+
+// Just enough of a JSON parser for an object of numbers
+struct JsonParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.src.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_whitespace();
+        if self.src.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' at byte {} of the JSON", c as char, self.pos))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        while self.src.get(self.pos).is_some_and(|&c| c != b'"' && c != b'\\') {
+            self.pos += 1;
+        }
+        let value = String::from_utf8_lossy(&self.src[start..self.pos]).into_owned();
+        self.expect(b'"').map_err(|_| format!("Unterminated or escaped string at byte {} of the JSON", start))?;
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<f32, String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.src.get(self.pos).is_some_and(|&c| c.is_ascii_digit() || b"+-.eE".contains(&c)) {
+            self.pos += 1;
+        }
+        let text = String::from_utf8_lossy(&self.src[start..self.pos]);
+        text.parse::<f32>().map_err(|_| format!("Invalid number '{}' at byte {} of the JSON", text, start))
+    }
+}
@@ -0,0 +1,114 @@
+#![allow(unused)]
+
+use std::ops::Range;
+
+use crate::shader_types::Complex32;
+
+// States a run can start from other than |0...0>, shared by the GPU and CPU backends.
+
+/// How far the squared norm of an initial state may be from 1
+pub const NORMALIZATION_TOLERANCE: f64 = 1e-4;
+
+/// The state a run starts from. Qubits are the simulated qubits at the start of the run (the
+/// circuit's own qubits, unless it was compacted or reordered; see Circuit::initial_layout).
+#[derive(Clone, Debug, PartialEq)]
+pub enum InitialState {
+    /// A computational basis state, with bit i the value of qubit i
+    Basis(usize),
+    /// A product of single qubit states a|0> + b|1>, given as [a, b] for each qubit in order
+    Product(Vec<[Complex32; 2]>),
+    /// A full state vector, e.g. one read back from another run
+    Amplitudes(Vec<Complex32>),
+}
+
+impl Default for InitialState {
+    fn default() -> Self {
+        InitialState::Basis(0)
+    }
+}
+
+impl InitialState {
+    /// Check the state is normalized and fits a register of `qubit_count` qubits
+    pub fn check(&self, qubit_count: u32) -> Result<(), String> {
+        let entry_count = 1usize << qubit_count;
+        let check_norm = |norm: f64, what: &str| {
+            // A NaN norm (from NaN amplitudes) fails every comparison, so is checked for explicitly
+            if norm.is_nan() || (norm - 1.0).abs() > NORMALIZATION_TOLERANCE {
+                return Err(format!("{} has squared norm {}, expected 1", what, norm));
+            }
+            Ok(())
+        };
+        match self {
+            InitialState::Basis(idx) if *idx >= entry_count => {
+                Err(format!("Basis state {} is out of range for {} qubits", idx, qubit_count))
+            }
+            InitialState::Basis(_) => Ok(()),
+            InitialState::Product(states) => {
+                if states.len() != qubit_count as usize {
+                    return Err(format!("Product state has {} qubits, expected {}", states.len(), qubit_count));
+                }
+                for (q, [a, b]) in states.iter().enumerate() {
+                    check_norm(a.norm_sqr() as f64 + b.norm_sqr() as f64, &format!("The state of qubit {}", q))?;
+                }
+                Ok(())
+            }
+            InitialState::Amplitudes(amplitudes) => {
+                if amplitudes.len() != entry_count {
+                    return Err(format!(
+                        "State vector has {} amplitudes, expected {} for {} qubits", amplitudes.len(), entry_count, qubit_count
+                    ));
+                }
+                check_norm(amplitudes.iter().map(|amp| amp.norm_sqr() as f64).sum(), "The state vector")
+            }
+        }
+    }
+
+    /// The state with its qubits moved from one layout to another, each a list of qubit labels by
+    /// position: the qubit at position i moves to the position of label from[i] in `to`. E.g. a
+    /// state vector read back from a run of a circuit reordered with SWAPs is in its final layout,
+    /// and is relabeled from circuit.layout() to circuit.initial_layout() to start another run.
+    pub fn relabel(&self, from: &[u32], to: &[u32]) -> Result<InitialState, String> {
+        if from.len() != to.len() {
+            return Err(format!("Layouts have {} and {} qubits", from.len(), to.len()));
+        }
+        self.check(from.len() as u32)?;
+        // target[i] is the position qubit i moves to
+        let target = from.iter()
+            .map(|label| to.iter().position(|l| l == label)
+                .ok_or_else(|| format!("Qubit {} is not in the layout {:?}", label, to)))
+            .collect::<Result<Vec<usize>, String>>()?;
+        let move_bits = |idx: usize| target.iter().enumerate().fold(0, |acc, (q, &t)| acc | (((idx >> q) & 1) << t));
+        Ok(match self {
+            InitialState::Basis(idx) => InitialState::Basis(move_bits(*idx)),
+            InitialState::Product(states) => {
+                let mut moved = states.clone();
+                for (q, &t) in target.iter().enumerate() {
+                    moved[t] = states[q];
+                }
+                InitialState::Product(moved)
+            }
+            InitialState::Amplitudes(amplitudes) => {
+                let mut moved = vec![Complex32::ZERO; amplitudes.len()];
+                for (idx, amp) in amplitudes.iter().enumerate() {
+                    moved[move_bits(idx)] = *amp;
+                }
+                InitialState::Amplitudes(moved)
+            }
+        })
+    }
+
+    /// The amplitudes at a range of indices of the state
+    pub fn amplitudes(&self, indices: Range<usize>) -> Vec<Complex32> {
+        match self {
+            InitialState::Basis(idx) => {
+                indices.map(|i| if i == *idx { Complex32::ONE } else { Complex32::ZERO }).collect()
+            }
+            InitialState::Product(states) => indices
+                .map(|i| {
+                    states.iter().enumerate().fold(Complex32::ONE, |amp, (q, state)| amp * state[(i >> q) & 1])
+                })
+                .collect(),
+            InitialState::Amplitudes(amplitudes) => amplitudes[indices].to_vec(),
+        }
+    }
+}
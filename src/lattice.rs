@@ -0,0 +1,235 @@
+#![allow(unused)]
+
+use crate::hamiltonian::{PauliSum, PauliTerm, TrotterCircuit};
+
+// Spin models on common lattices, as Hamiltonians, Trotter circuits and observables to measure.
+
+/// Lattice geometries. Sites are numbered row by row.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Lattice {
+    Chain(u32),
+    /// (rows, columns)
+    Square(u32, u32),
+    /// A square lattice plus the diagonal from each site to the site down and to the right
+    Triangular(u32, u32),
+    /// Hexagons of (rows, columns) sites in a brick wall layout, with an extra qubit on every
+    /// edge. The rows * columns hexagon sites come first, then the edge qubits.
+    HeavyHex(u32, u32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Boundary {
+    Open,
+    Periodic,
+}
+
+/// The coupling on each edge, and the field on each site
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interaction {
+    /// ZZ couplings in a transverse X field
+    Ising,
+    /// XX + YY + ZZ couplings in a Z field
+    Heisenberg,
+    /// XX + YY couplings in a Z field
+    XY,
+}
+
+/// H = -J * sum of the couplings over the edges - h * sum of the fields over the sites.
+///
+/// With J < 0 the couplings are antiferromagnetic. ising5x5.crc is the Ising model on an open 5x5
+/// square lattice with J = -1, h = 0.7 and dt = 0.8.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpinModel {
+    pub lattice: Lattice,
+    pub boundary: Boundary,
+    pub interaction: Interaction,
+    pub j: f32,
+    pub h: f32,
+}
+
+/// A named operator whose expectation value is worth measuring
+pub struct Observable {
+    pub name: String,
+    pub operator: PauliSum,
+}
+
+// Pauli masks are u32, so the largest lattice has a qubit for each bit
+const MAX_QUBITS: u32 = 32;
+
+impl SpinModel {
+    /// The number of qubits, or an error if there are more than the Pauli masks can hold
+    pub fn qubit_count(&self) -> Result<u32, String> {
+        let sites = match self.lattice {
+            Lattice::Chain(n) => Some(n),
+            Lattice::Square(rows, cols) | Lattice::Triangular(rows, cols) | Lattice::HeavyHex(rows, cols) => {
+                rows.checked_mul(cols)
+            }
+        };
+        let sites = sites
+            .filter(|&sites| sites <= MAX_QUBITS)
+            .ok_or_else(|| format!("The lattice has too many qubits, at most {} are supported", MAX_QUBITS))?;
+        // The heavy-hex edges are only listed once the number of sites is known to be small
+        let count = match self.lattice {
+            Lattice::HeavyHex(rows, cols) => sites + hex_edges(rows, cols, self.boundary).len() as u32,
+            _ => sites,
+        };
+        if count > MAX_QUBITS {
+            return Err(format!("The lattice has {} qubits, but at most {} are supported", count, MAX_QUBITS));
+        }
+        Ok(count)
+    }
+
+    /// The coupled pairs of qubits, ordered in layers of edges that share no qubits
+    pub fn edges(&self) -> Result<Vec<(u32, u32)>, String> {
+        self.qubit_count()?;
+        let periodic = self.boundary == Boundary::Periodic;
+        let mut edges = Vec::new();
+        // Wrapping around a dimension of 2 or less would repeat an edge (or couple a site to itself)
+        let mut link = |a: u32, b: u32| {
+            let edge = (a.min(b), a.max(b));
+            if a != b && !edges.contains(&edge) {
+                edges.push(edge);
+            }
+        };
+        match self.lattice {
+            Lattice::Chain(n) => {
+                for i in 0..n.saturating_sub(1) {
+                    link(i, i + 1);
+                }
+                if periodic && n > 2 {
+                    link(n - 1, 0);
+                }
+            }
+            Lattice::Square(rows, cols) | Lattice::Triangular(rows, cols) => {
+                let triangular = matches!(self.lattice, Lattice::Triangular(..));
+                let site = |r: u32, c: u32| r * cols + c;
+                for r in 0..rows {
+                    for c in 0..cols {
+                        let right = (c + 1 < cols || (periodic && cols > 2)).then(|| (c + 1) % cols);
+                        let down = (r + 1 < rows || (periodic && rows > 2)).then(|| (r + 1) % rows);
+                        if let Some(c2) = right {
+                            link(site(r, c), site(r, c2));
+                        }
+                        if let Some(r2) = down {
+                            link(site(r, c), site(r2, c));
+                        }
+                        if let (true, Some(r2), Some(c2)) = (triangular, down, right) {
+                            link(site(r, c), site(r2, c2));
+                        }
+                    }
+                }
+            }
+            Lattice::HeavyHex(rows, cols) => {
+                if periodic && !(rows.is_multiple_of(2) && cols.is_multiple_of(2)) {
+                    return Err("A periodic heavy-hex lattice needs an even number of rows and columns".to_string());
+                }
+                // Each hexagon edge becomes two edges through its own qubit
+                for (mid, (a, b)) in (rows * cols..).zip(hex_edges(rows, cols, self.boundary)) {
+                    link(a, mid);
+                    link(mid, b);
+                }
+            }
+        }
+        Ok(color_edges(edges))
+    }
+
+    pub fn hamiltonian(&self) -> Result<PauliSum, String> {
+        let edges = self.edges()?;
+        let (field, couplings): (char, &[char]) = match self.interaction {
+            Interaction::Ising => ('X', &['Z']),
+            Interaction::Heisenberg => ('Z', &['X', 'Y', 'Z']),
+            Interaction::XY => ('Z', &['X', 'Y']),
+        };
+
+        let mut terms = Vec::new();
+        if self.h != 0.0 {
+            for q in 0..self.qubit_count()? {
+                terms.push(PauliTerm::from_label(-self.h, &format!("{}{}", field, q))?);
+            }
+        }
+        for &pauli in couplings {
+            for &(a, b) in &edges {
+                terms.push(PauliTerm::from_label(-self.j, &format!("{}{} {}{}", pauli, a, pauli, b))?);
+            }
+        }
+        Ok(PauliSum { terms })
+    }
+
+    /// The Trotterized evolution for `steps` steps of `dt` (see PauliSum::trotter_circuit)
+    pub fn trotter_circuit(&self, dt: f32, steps: usize, order: u32) -> Result<TrotterCircuit, String> {
+        let mut trotter = self.hamiltonian()?.trotter_circuit(dt * steps as f32, steps, order)?;
+        // Keep every site, even if no term acts on it
+        trotter.circuit.qubit_count = self.qubit_count()? as i32;
+        Ok(trotter)
+    }
+
+    /// The energy, the average magnetization along X and Z, and the average nearest neighbour
+    /// correlation of each coupling
+    pub fn observables(&self) -> Result<Vec<Observable>, String> {
+        let n = self.qubit_count()?;
+        let edges = self.edges()?;
+        let average = |labels: Vec<String>| -> Result<PauliSum, String> {
+            let weight = 1.0 / labels.len().max(1) as f32;
+            let terms = labels.iter().map(|label| PauliTerm::from_label(weight, label)).collect::<Result<_, _>>()?;
+            Ok(PauliSum { terms })
+        };
+
+        let mut observables = vec![Observable { name: "energy".to_string(), operator: self.hamiltonian()? }];
+        for pauli in ['X', 'Z'] {
+            observables.push(Observable {
+                name: format!("magnetization_{}", pauli.to_ascii_lowercase()),
+                operator: average((0..n).map(|q| format!("{}{}", pauli, q)).collect())?,
+            });
+        }
+        let correlations: &[char] = match self.interaction {
+            Interaction::Ising => &['Z'],
+            Interaction::Heisenberg => &['X', 'Y', 'Z'],
+            Interaction::XY => &['X', 'Y'],
+        };
+        for &pauli in correlations {
+            let lower = pauli.to_ascii_lowercase();
+            observables.push(Observable {
+                name: format!("correlation_{}{}", lower, lower),
+                operator: average(edges.iter().map(|(a, b)| format!("{}{} {}{}", pauli, a, pauli, b)).collect())?,
+            });
+        }
+        Ok(observables)
+    }
+}
+
+// Edges of a hexagonal lattice in a brick wall layout: rows of sites joined left to right, with a
+// vertical edge down from each site whose row and column sum is even. Vertical edges alternate
+// columns from row to row, so wrapping them never repeats an edge.
+fn hex_edges(rows: u32, cols: u32, boundary: Boundary) -> Vec<(u32, u32)> {
+    let periodic = boundary == Boundary::Periodic && rows.is_multiple_of(2) && cols.is_multiple_of(2);
+    let site = |r: u32, c: u32| r * cols + c;
+    let mut edges = Vec::new();
+    for r in 0..rows {
+        for c in 0..cols {
+            if c + 1 < cols || (periodic && cols > 2) {
+                edges.push((site(r, c), site(r, (c + 1) % cols)));
+            }
+            if (r + c).is_multiple_of(2) && (r + 1 < rows || periodic) {
+                edges.push((site(r, c), site((r + 1) % rows, c)));
+            }
+        }
+    }
+    edges
+}
+
+// Greedily sort edges into layers where no two edges share a qubit, so the gates of each layer can
+// run in parallel, keeping the original order within each layer
+fn color_edges(edges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    let mut layers: Vec<(u64, Vec<(u32, u32)>)> = Vec::new();
+    for (a, b) in edges {
+        let mask = (1u64 << a) | (1u64 << b);
+        match layers.iter_mut().find(|(used, _)| used & mask == 0) {
+            Some((used, layer)) => {
+                *used |= mask;
+                layer.push((a, b));
+            }
+            None => layers.push((mask, vec![(a, b)])),
+        }
+    }
+    layers.into_iter().flat_map(|(_, layer)| layer).collect()
+}
@@ -1,8 +1,22 @@
 #![allow(unused)]
 
 mod circuit;
+mod cpu_simulator;
 mod gpu_context;
+mod hamiltonian;
+mod initial_state;
+mod lattice;
+mod modifiers;
+mod noise;
+mod passes;
+mod rng;
+mod run_config;
+mod sampling;
+mod schedule;
 mod shader_types;
+mod state_prep;
+mod stats;
+mod transpile;
 
 #[cfg(target_arch = "wasm32")]
 mod wasm;
@@ -1,8 +1,22 @@
 #![allow(unused)]
 
 mod circuit;
+mod cpu_simulator;
 mod gpu_context;
+mod hamiltonian;
+mod initial_state;
+mod lattice;
+mod modifiers;
+mod noise;
+mod passes;
+mod rng;
+mod run_config;
+mod sampling;
+mod schedule;
 mod shader_types;
+mod state_prep;
+mod stats;
+mod transpile;
 mod wasm;
 
 use circuit::{Circuit};
@@ -11,19 +25,78 @@ use shader_types::{ops, Op};
 #[cfg(test)]
 mod tests;
 
+// Most frequent bitstrings to print when sampling shots
+const MAX_PRINTED_COUNTS: usize = 32;
+
+/// Usage: wgpudev [circuit.crc | circuit.qir] [--shots N] [--seed S]
+///
+/// Runs the Ising 5x5 circuit if no file is given. With --shots, prints the most frequent
+/// bitstrings over N shots, else the time taken to run the circuit. The same seed gives the same
+/// shots on every run.
 fn main() {
-    let ising_str = include_str!("ising5x5.crc");
-    let circ = Circuit::from_str(ising_str).unwrap();
+    let mut path: Option<String> = None;
+    let mut shots: Option<usize> = None;
+    let mut config = run_config::RunConfig::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--shots" => {
+                let value = args.next().and_then(|v| v.parse().ok());
+                shots = Some(value.unwrap_or_else(|| exit_with_usage("--shots needs a number")));
+            }
+            "--seed" => {
+                let value = args.next().and_then(|v| v.parse().ok());
+                config.seed = value.unwrap_or_else(|| exit_with_usage("--seed needs a number"));
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => exit_with_usage(&format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let src = match &path {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|err| exit_with_usage(&format!("{}: {}", path, err))),
+        None => include_str!("ising5x5.crc").to_string(),
+    };
+    let circ = Circuit::from_str(&src).unwrap_or_else(|err| exit_with_usage(&err));
+    println!("{}\n", circ.stats());
+    if circ.qubit_count > gpu_context::MAX_QUBITS {
+        eprintln!("Qubit count too high: {}", circ.qubit_count);
+        std::process::exit(1);
+    }
+    // Collapse the RZZ layers into a few diagonal sweeps
+    let circ = passes::batch_diagonal_ops(&circ);
+    // Apply the RX layers as precomputed matrices
+    let circ = passes::fuse_1q_matrices(&circ);
 
     // Time start/end duration
     let start = std::time::Instant::now();
 
-    let result = futures::executor::block_on(async {
+    futures::executor::block_on(async {
         let mut gpu_context = gpu_context::GpuContext::new(circ).await;
+        gpu_context.set_config(config);
         gpu_context.create_resources();
-        gpu_context.run().await
+        match shots {
+            Some(shots) => {
+                let counts = gpu_context.sample(shots).await;
+                let sorted = sampling::sorted_counts(&counts);
+                for (bits, count) in sorted.iter().take(MAX_PRINTED_COUNTS) {
+                    println!("{}: {}", bits, count);
+                }
+                if sorted.len() > MAX_PRINTED_COUNTS {
+                    println!("... and {} more bitstrings", sorted.len() - MAX_PRINTED_COUNTS);
+                }
+            }
+            None => {
+                gpu_context.run().await;
+            }
+        }
     });
 
     let duration = start.elapsed();
     println!("Completed in: {:?}", duration);
 }
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\nUsage: wgpudev [circuit.crc | circuit.qir] [--shots N] [--seed S]", message);
+    std::process::exit(1);
+}
@@ -0,0 +1,218 @@
+#![allow(unused)]
+
+use std::f32::consts::FRAC_PI_2;
+
+use crate::circuit::Circuit;
+use crate::cpu_simulator::matrix_1q;
+use crate::passes::{diagonal_terms, normalize_angle, permutation_steps};
+use crate::shader_types::{ops, Complex32, Op, MAX_OP_TERMS};
+use crate::transpile::{decompose_2q, decompose_pauli_exp, matmul, zyz_angles};
+
+// Circuit constructions used by algorithms (uncomputation, amplitude amplification), shared by
+// the Circuit methods and the .crc `inv` and `ctrl` modifiers.
+
+impl Circuit {
+    /// The adjoint of the circuit: ops in reverse order, each replaced by its inverse.
+    /// Fails if the circuit measures or resets any qubit before the implicit final measurement.
+    pub fn inverse(&self) -> Result<Circuit, String> {
+        let (body, measure) = split_final_measurement(&self.ops);
+        let mut ops = inverse_ops(body)?;
+        ops.extend(measure);
+        Ok(self.with_ops(ops))
+    }
+
+    /// The circuit with every op controlled on `ctrl`, which must not be used by the circuit.
+    /// Fails if the circuit measures or resets any qubit before the implicit final measurement.
+    ///
+    /// Each op is controlled as defined here, global phase included. RZ is diag(1, e^(i * angle)),
+    /// so a controlled RZ is a controlled phase (CPHASE), not the textbook CRZ gate, which controls
+    /// diag(e^(-i * angle / 2), e^(i * angle / 2)).
+    pub fn controlled(&self, ctrl: u32) -> Result<Circuit, String> {
+        let (body, measure) = split_final_measurement(&self.ops);
+        let mut ops = controlled_ops(body, ctrl)?;
+        ops.extend(measure);
+        let mut circuit = self.with_ops(ops);
+        circuit.qubit_count = circuit.qubit_count.max(ctrl as i32 + 1);
+        if !circuit.logical_qubits.is_empty() {
+            // The control is a new qubit, so give it the next free label
+            let mut next = circuit.logical_qubits.iter().copied().max().unwrap_or(0) + 1;
+            while circuit.logical_qubits.len() < circuit.qubit_count as usize {
+                circuit.logical_qubits.push(next);
+                if !circuit.initial_logical_qubits.is_empty() {
+                    circuit.initial_logical_qubits.push(next);
+                }
+                next += 1;
+            }
+        }
+        Ok(circuit)
+    }
+}
+
+fn split_final_measurement(ops: &[Op]) -> (&[Op], Option<Op>) {
+    match ops.split_last() {
+        Some((last, body)) if last.op_id == ops::MEVERYZ => (body, Some(*last)),
+        _ => (ops, None),
+    }
+}
+
+fn check_unitary(op: &Op, modifier: &str) -> Result<(), String> {
+    match op.op_id {
+        ops::RESET | ops::MZ | ops::MRESETZ | ops::MEVERYZ => {
+            Err(format!("Cannot apply '{}' to non-unitary op '{}'", modifier, ops::name(op.op_id)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The inverse of a sequence of unitary ops.
+pub fn inverse_ops(body: &[Op]) -> Result<Vec<Op>, String> {
+    let mut ops_out = Vec::with_capacity(body.len());
+    for op in body.iter().rev() {
+        check_unitary(op, "inv")?;
+        let mut inv = *op;
+        match op.op_id {
+            ops::S => inv.op_id = ops::S_ADJ,
+            ops::S_ADJ => inv.op_id = ops::S,
+            ops::T => inv.op_id = ops::T_ADJ,
+            ops::T_ADJ => inv.op_id = ops::T,
+            ops::SX => inv.op_id = ops::SX_ADJ,
+            ops::SX_ADJ => inv.op_id = ops::SX,
+            ops::RX | ops::RY | ops::RZ | ops::RZZ => inv.angle = -op.angle,
+            ops::MCPHASE | ops::MCRX | ops::MCRY | ops::MCRZ => inv.angle = -op.angle,
+            ops::CRX | ops::CRY | ops::CRZ | ops::CPHASE | ops::RXX | ops::RYY => inv.angle = -op.angle,
+            ops::PAULI_EXP => inv.angle = -op.angle,
+            // U3(theta, phi, lambda)^-1 = U3(-theta, -lambda, -phi)
+            ops::U3 => inv = Op::with_angles(ops::U3, op.q1, 0, 0, &[-op.angle, -op.angle_at(2), -op.angle_at(1)]),
+            // ISWAP = e^(i * pi / 4 * (XX + YY)), so its inverse is a pair of rotations
+            ops::ISWAP => {
+                ops_out.push(Op::new(ops::RXX, op.q1, op.q2, 0, FRAC_PI_2));
+                inv = Op::new(ops::RYY, op.q1, op.q2, 0, FRAC_PI_2);
+            }
+            // The conjugate transpose
+            ops::MATRIX1Q => inv = Op::with_matrix_1q(op.q1, &std::array::from_fn(|idx| op.matrix_entry((idx % 2) * 2 + idx / 2).conj())),
+            ops::MATRIX2Q => inv = Op::with_matrix_2q(op.q1, op.q2, &std::array::from_fn(|idx| op.matrix_entry((idx % 4) * 4 + idx / 4).conj())),
+            ops::DIAGONAL => {
+                inv.data[0] = 0;
+                for t in 0..op.term_count() {
+                    let (mask, angle) = op.term(t);
+                    inv.push_term(mask, -angle);
+                }
+            }
+            ops::PERMUTATION => {
+                inv.data[0] = 0;
+                for t in (0..op.term_count()).rev() {
+                    let (ctrl, flip) = op.mask_pair(t);
+                    inv.push_mask_pair(ctrl, flip);
+                }
+            }
+            // The rest are self-inverse
+            _ => {}
+        }
+        ops_out.push(inv);
+    }
+    Ok(ops_out)
+}
+
+/// A sequence of ops applying `body` only when `ctrl` is set.
+///
+/// Multi-controlled ops and X/CX/CCX gain the control directly, as do RX and RY (as MCRX and MCRY).
+/// Other two qubit gates and Pauli exponentials are controlled through their decompositions, and the remaining diagonal
+/// and permutation ops become DIAGONAL or PERMUTATION ops. The remaining single qubit gates use
+/// the A * X * B * X * C decomposition with a phase on the control. As for Circuit::controlled, an
+/// op's global phase is kept, so RZ becomes a controlled phase rather than CRZ.
+pub fn controlled_ops(body: &[Op], ctrl: u32) -> Result<Vec<Op>, String> {
+    let c = 1u32 << ctrl;
+    let mut ops_out = Vec::new();
+    for op in body {
+        check_unitary(op, "ctrl")?;
+        if op.qubit_mask() & c != 0 {
+            return Err(format!("Control qubit {} is also used by '{}'", ctrl, ops::name(op.op_id)));
+        }
+
+        if ops::is_multi_controlled(op.op_id) {
+            let (ctrl_mask, ctrl_values) = op.controls();
+            ops_out.push(Op::multi_controlled(op.op_id, ctrl_mask | c, ctrl_values | c, op.q1, op.angle));
+        } else if matches!(op.op_id, ops::RX | ops::RY) {
+            let op_id = if op.op_id == ops::RX { ops::MCRX } else { ops::MCRY };
+            ops_out.push(Op::multi_controlled(op_id, c, c, op.q1, op.angle));
+        } else if let Some((gates, phase)) = decompose_2q(op).or_else(|| decompose_pauli_exp(op)) {
+            // The global phase of the decomposition becomes a phase on the control
+            ops_out.extend(controlled_ops(&gates, ctrl)?);
+            if phase != 0.0 {
+                ops_out.push(Op::new(ops::RZ, ctrl, 0, 0, phase));
+            }
+        } else if let Some(terms) = diagonal_terms(op) {
+            // A phase on odd parity of the mask, only when c is set:
+            // b_c * p = (b_c + p - (b_c ^ p)) / 2
+            let mut controlled: Vec<(u32, f32)> = Vec::new();
+            for (mask, angle) in terms {
+                for (term_mask, term_angle) in [(c, angle / 2.0), (mask, angle / 2.0), (mask | c, -angle / 2.0)] {
+                    match controlled.iter_mut().find(|(m, _)| *m == term_mask) {
+                        Some(existing) => existing.1 += term_angle,
+                        None => controlled.push((term_mask, term_angle)),
+                    }
+                }
+            }
+            for chunk in controlled.chunks(MAX_OP_TERMS) {
+                let mut diag = Op::new(ops::DIAGONAL, 0, 0, 0, 0.0);
+                chunk.iter().for_each(|&(mask, angle)| diag.push_term(mask, angle));
+                ops_out.push(diag);
+            }
+        } else if let Some(steps) = permutation_steps(op) {
+            match steps.as_slice() {
+                [] => {}
+                &[(0, flip)] if flip.is_power_of_two() => {
+                    ops_out.push(Op::new(ops::CX, ctrl, flip.trailing_zeros(), 0, 0.0));
+                }
+                &[(step_ctrl, flip)] if flip.is_power_of_two() => {
+                    ops_out.push(Op::multi_controlled(ops::MCX, step_ctrl | c, step_ctrl | c, flip.trailing_zeros(), 0.0));
+                }
+                _ => {
+                    for chunk in steps.chunks(MAX_OP_TERMS) {
+                        let mut perm = Op::new(ops::PERMUTATION, 0, 0, 0, 0.0);
+                        chunk.iter().for_each(|&(step_ctrl, flip)| perm.push_mask_pair(step_ctrl | c, flip));
+                        ops_out.push(perm);
+                    }
+                }
+            }
+        } else if let Some(m) = matrix_1q(op) {
+            ops_out.extend(controlled_1q(&m, ctrl, op.q1));
+        } else {
+            return Err(format!("Cannot control op '{}'", ops::name(op.op_id)));
+        }
+    }
+    Ok(ops_out)
+}
+
+// Controlled version of the single qubit unitary m, exact including phase.
+//
+// With m = e^(i * alpha) Rz(beta) Ry(gamma) Rz(delta) (textbook Rz), take
+// A = Rz(beta) Ry(gamma / 2), B = Ry(-gamma / 2) Rz(-(delta + beta) / 2), C = Rz((delta - beta) / 2)
+// so that A * B * C = I and A * X * B * X * C = m * e^(-i * alpha), then apply e^(i * alpha) to the
+// control. The phases RZ = diag(1, e^(i * angle)) adds over Rz cancel out over A, B and C.
+pub(crate) fn controlled_1q(m: &[Complex32; 4], ctrl: u32, target: u32) -> Vec<Op> {
+    let (beta, gamma, delta) = zyz_angles(m);
+    let rz = |q: u32, angle: f32| Op::new(ops::RZ, q, 0, 0, angle);
+    let matrix = |op: Op| matrix_1q(&op).unwrap();
+    let zyz = matmul(&matrix(rz(0, beta)), &matmul(&matrix(Op::new(ops::RY, 0, 0, 0, gamma)), &matrix(rz(0, delta))));
+    let largest = (0..4).max_by(|&a, &b| zyz[a].abs().total_cmp(&zyz[b].abs())).unwrap();
+    let alpha = m[largest].arg() - zyz[largest].arg() + (beta + delta) / 2.0;
+
+    // RY(angle) = S RX(angle) S_ADJ, and the S gates merge with the neighbouring RZ gates
+    let gates = [
+        rz(target, (delta - beta) / 2.0),
+        Op::new(ops::CX, ctrl, target, 0, 0.0),
+        rz(target, -(delta + beta) / 2.0 - FRAC_PI_2),
+        Op::new(ops::RX, target, 0, 0, -gamma / 2.0),
+        rz(target, FRAC_PI_2),
+        Op::new(ops::CX, ctrl, target, 0, 0.0),
+        rz(target, -FRAC_PI_2),
+        Op::new(ops::RX, target, 0, 0, gamma / 2.0),
+        rz(target, beta + FRAC_PI_2),
+        rz(ctrl, alpha),
+    ];
+    gates
+        .into_iter()
+        .filter(|op| !matches!(op.op_id, ops::RX | ops::RZ) || normalize_angle(op.angle).abs() > 1e-6)
+        .collect()
+}
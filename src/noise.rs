@@ -0,0 +1,134 @@
+#![allow(unused)]
+
+use std::collections::HashMap;
+
+use crate::circuit::Circuit;
+use crate::schedule::schedule;
+use crate::shader_types::{ops, Op};
+
+// Stochastic noise, simulated by quantum trajectories: each shot applies one Kraus operator of each
+// channel, chosen at random with the probability it has in that shot's state, so shot counts
+// average over the noisy runs. Shared by the GPU and CPU backends, which sample the same
+// operators for the same seed.
+
+/// A single qubit noise channel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    /// An X, Y or Z error, each with probability p / 3
+    Depolarizing(f32),
+    /// An X error with probability p
+    BitFlip(f32),
+    /// A Z error with probability p
+    PhaseFlip(f32),
+    /// Decay from |1> to |0> with probability gamma, e.g. energy relaxation (T1)
+    AmplitudeDamping(f32),
+    /// Loss of the phase between |0> and |1> at rate lambda, without decay (T2)
+    PhaseDamping(f32),
+}
+
+impl Channel {
+    // The noise op applying the channel to a qubit
+    fn op(&self, qubit: u32) -> Op {
+        let (op_id, probability) = match *self {
+            Channel::Depolarizing(p) => (ops::DEPOLARIZE, p),
+            Channel::BitFlip(p) => (ops::BIT_FLIP, p),
+            Channel::PhaseFlip(p) => (ops::PHASE_FLIP, p),
+            Channel::AmplitudeDamping(gamma) => (ops::AMPLITUDE_DAMP, gamma),
+            Channel::PhaseDamping(lambda) => (ops::PHASE_DAMP, lambda),
+        };
+        Op::new(op_id, qubit, 0, 0, probability)
+    }
+}
+
+/// Where noise channels apply in a circuit. Channels are applied in the order listed, gate noise
+/// before qubit noise.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NoiseModel {
+    /// Channels applied to each qubit of every op with the given id (e.g. ops::CX). Ids are those
+    /// of the ops as run, so after any passes: once passes::fuse_1q_matrices has turned H gates
+    /// into MATRIX1Q ops, noise keyed by ops::H no longer applies, and should be keyed by
+    /// ops::MATRIX1Q instead.
+    pub gates: HashMap<u32, Vec<Channel>>,
+    /// Channels applied to a qubit (by original label) after every op acting on it. The qubit must
+    /// be simulated by the circuit, so not an idle qubit dropped by passes::compact_qubits. Labels
+    /// are mapped to simulated qubits by the circuit's layout, so qubit noise can't be applied once
+    /// passes::reorder_qubits has inserted SWAPs changing the layout part way through: apply the
+    /// model before that pass instead.
+    pub qubits: HashMap<u32, Vec<Channel>>,
+    /// Channels applied to every qubit after each moment (see schedule), including idle ones
+    pub moments: Vec<Channel>,
+}
+
+impl NoiseModel {
+    pub fn is_noiseless(&self) -> bool {
+        self.gates.values().chain(self.qubits.values()).all(|channels| channels.is_empty()) && self.moments.is_empty()
+    }
+
+    /// Check each channel's probability is in [0, 1]
+    pub fn check(&self) -> Result<(), String> {
+        for channel in self.gates.values().chain(self.qubits.values()).flatten().chain(&self.moments) {
+            let op = channel.op(0);
+            if !(0.0..=1.0).contains(&op.angle) {
+                return Err(format!("{:?} has probability {}, expected one in [0, 1]", channel, op.angle));
+            }
+        }
+        Ok(())
+    }
+
+    /// The circuit with the model's noise ops inserted, replacing any noise already in it. With
+    /// moment noise, ops are reordered moment by moment, which doesn't change what the circuit does.
+    ///
+    /// No noise follows the measurements ending the circuit, as it can't change any result. So a
+    /// circuit measured at the end is still sampled from its final state, once per trajectory.
+    /// Errors if qubit noise is given for a qubit the circuit doesn't simulate, or for a circuit
+    /// whose layout changes part way through (see NoiseModel::qubits).
+    pub fn apply(&self, circuit: &Circuit) -> Result<Circuit, String> {
+        let noiseless: Vec<Op> = circuit.ops.iter().filter(|op| !ops::is_noise(op.op_id)).copied().collect();
+        if !circuit.initial_logical_qubits.is_empty() && self.qubits.values().any(|channels| !channels.is_empty()) {
+            return Err("Qubit noise can't follow the layout SWAPs inserted by passes::reorder_qubits, \
+                apply the noise model before reordering".to_string());
+        }
+        let mut qubit_noise: HashMap<u32, &Vec<Channel>> = HashMap::new();
+        for (&logical, channels) in &self.qubits {
+            let q = circuit.simulated_qubit(logical)
+                .ok_or_else(|| format!("Noise is given for qubit {}, which the circuit doesn't simulate", logical))?;
+            qubit_noise.insert(q, channels);
+        }
+        // The measurements ending the circuit, including the final MEVERYZ
+        let body_end = noiseless.iter()
+            .rposition(|op| !ops::is_measurement(op.op_id) && op.op_id != ops::MEVERYZ)
+            .map_or(0, |idx| idx + 1);
+        let (body, measurements) = noiseless.split_at(body_end);
+
+        // The noise ops following an op
+        let noise_after = |op: &Op| -> Vec<Op> {
+            let qubits = op.qubits();
+            let gate_noise = self.gates.get(&op.op_id).into_iter().flatten()
+                .flat_map(|channel| qubits.iter().map(|&q| channel.op(q)));
+            let qubit_noise = qubits.iter()
+                .flat_map(|q| qubit_noise.get(q).into_iter().copied().flatten().map(|channel| channel.op(*q)));
+            gate_noise.chain(qubit_noise).collect()
+        };
+
+        let mut ops_out = Vec::with_capacity(noiseless.len());
+        if self.moments.is_empty() {
+            for op in body {
+                ops_out.push(*op);
+                ops_out.extend(noise_after(op));
+            }
+        } else {
+            let body = circuit.with_ops(body.to_vec());
+            for moment in schedule(&body).moments {
+                for op in moment.iter().map(|&idx| &body.ops[idx]) {
+                    ops_out.push(*op);
+                    ops_out.extend(noise_after(op));
+                }
+                for channel in &self.moments {
+                    ops_out.extend((0..circuit.qubit_count as u32).map(|q| channel.op(q)));
+                }
+            }
+        }
+        ops_out.extend_from_slice(measurements);
+        Ok(circuit.with_ops(ops_out))
+    }
+}
@@ -0,0 +1,363 @@
+#![allow(unused)]
+
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+use std::cmp::Reverse;
+
+use crate::circuit::Circuit;
+use crate::cpu_simulator::matrix_1q;
+use crate::shader_types::{ops, Complex32, Op, MAX_OP_TERMS};
+use crate::transpile::matmul;
+
+// Circuit to circuit transformations that reduce the number of state vector sweeps on the GPU.
+
+/// Returns the phase terms for an op that is diagonal in the computational basis, or None.
+///
+/// Each term is a (mask, angle) pair contributing a phase of e^(i * angle) to every basis state
+/// with odd parity over the masked bits. Global phase is dropped, as it is in the shader.
+pub(crate) fn diagonal_terms(op: &Op) -> Option<Vec<(u32, f32)>> {
+    let q1 = 1u32 << op.q1;
+    let q2 = 1u32 << op.q2;
+    let terms = match op.op_id {
+        ops::ID => vec![],
+        ops::Z => vec![(q1, PI)],
+        ops::S => vec![(q1, FRAC_PI_2)],
+        ops::S_ADJ => vec![(q1, -FRAC_PI_2)],
+        ops::T => vec![(q1, FRAC_PI_4)],
+        ops::T_ADJ => vec![(q1, -FRAC_PI_4)],
+        ops::RZ => vec![(q1, op.angle)],
+        ops::RZZ => vec![(q1 | q2, op.angle)],
+        // b1 * b2 = (b1 + b2 - (b1 ^ b2)) / 2
+        ops::CZ => vec![(q1, FRAC_PI_2), (q2, FRAC_PI_2), (q1 | q2, -FRAC_PI_2)],
+        ops::DIAGONAL => (0..op.term_count()).map(|i| op.term(i)).collect(),
+        // e^(-i * angle * Z...Z) = e^(-i * angle) * (e^(2i * angle) on odd parity)
+        ops::PAULI_EXP if op.pauli_masks().0 == 0 => {
+            let z_mask = op.pauli_masks().1;
+            if z_mask == 0 { vec![] } else { vec![(z_mask, 2.0 * op.angle)] }
+        }
+        _ => return None,
+    };
+    Some(terms)
+}
+
+/// Returns the steps for an op that permutes the computational basis states, or None.
+///
+/// Each step is a (control mask, flip mask) pair: basis states with all the control bits set
+/// have the flip bits toggled. Steps are applied in order.
+pub(crate) fn permutation_steps(op: &Op) -> Option<Vec<(u32, u32)>> {
+    let q1 = 1u32 << op.q1;
+    let q2 = 1u32 << op.q2;
+    let q3 = 1u32 << op.q3;
+    let steps = match op.op_id {
+        ops::ID => vec![],
+        ops::X => vec![(0, q1)],
+        ops::CX => vec![(q1, q2)],
+        ops::CCX => vec![(q1 | q2, q3)],
+        ops::SWAP => vec![(q1, q2), (q2, q1), (q1, q2)],
+        ops::PERMUTATION => (0..op.term_count()).map(|i| op.mask_pair(i)).collect(),
+        ops::MCX => {
+            // Negative controls are positive controls between X gates
+            let (ctrl_mask, ctrl_values) = op.controls();
+            let negative = ctrl_mask & !ctrl_values;
+            let step = (ctrl_mask, q1);
+            if negative == 0 { vec![step] } else { vec![(0, negative), step, (0, negative)] }
+        }
+        _ => return None,
+    };
+    Some(steps)
+}
+
+/// Wrap an angle into (-pi, pi]
+pub fn normalize_angle(angle: f32) -> f32 {
+    let wrapped = angle.rem_euclid(2.0 * PI);
+    if wrapped > PI { wrapped - 2.0 * PI } else { wrapped }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LayerKind {
+    Diagonal,
+    Permutation,
+}
+
+impl LayerKind {
+    // The terms an op contributes to this kind of layer, with values stored as raw bits like in the op data
+    fn terms(self, op: &Op) -> Option<Vec<(u32, u32)>> {
+        match self {
+            LayerKind::Diagonal => diagonal_terms(op)
+                .map(|terms| terms.into_iter().map(|(mask, angle)| (mask, angle.to_bits())).collect()),
+            LayerKind::Permutation => permutation_steps(op),
+        }
+    }
+
+    // Ops the shader already runs directly, so nothing is gained by wrapping one on its own
+    fn is_native(self, op_id: u32) -> bool {
+        match self {
+            LayerKind::Diagonal => matches!(op_id, ops::RZ | ops::RZZ | ops::CZ),
+            LayerKind::Permutation => matches!(op_id, ops::X | ops::CX | ops::SWAP | ops::CCX | ops::MCX),
+        }
+    }
+}
+
+struct Layer {
+    kind: LayerKind,
+    // Position in the output ops where the layer will be placed
+    start: usize,
+    terms: Vec<(u32, u32)>,
+    first_op: Op,
+    op_count: usize,
+    // Qubits touched by other ops emitted after the layer started
+    blocked: u32,
+}
+
+impl Layer {
+    fn fits(&self, op: &Op, terms: &[(u32, u32)]) -> bool {
+        let new_terms = match self.kind {
+            // Phases on the same mask are summed, so only new masks need space
+            LayerKind::Diagonal => terms
+                .iter()
+                .filter(|(mask, _)| !self.terms.iter().any(|(m, _)| m == mask))
+                .count(),
+            LayerKind::Permutation => terms.len(),
+        };
+        op.qubit_mask() & self.blocked == 0 && self.terms.len() + new_terms <= MAX_OP_TERMS
+    }
+
+    fn add(&mut self, terms: &[(u32, u32)]) {
+        for &(mask, value) in terms {
+            match self.kind {
+                LayerKind::Diagonal => match self.terms.iter_mut().find(|(m, _)| *m == mask) {
+                    Some(term) => term.1 = (f32::from_bits(term.1) + f32::from_bits(value)).to_bits(),
+                    None => self.terms.push((mask, value)),
+                },
+                LayerKind::Permutation => {
+                    // Each step is its own inverse, so a repeated step cancels out
+                    if self.terms.last() == Some(&(mask, value)) {
+                        self.terms.pop();
+                    } else {
+                        self.terms.push((mask, value));
+                    }
+                }
+            }
+        }
+        self.op_count += 1;
+    }
+
+    fn close(self, ops_out: &mut Vec<Op>) {
+        if self.op_count == 1 && self.kind.is_native(self.first_op.op_id) {
+            ops_out[self.start] = self.first_op;
+            return;
+        }
+        let mut op = match self.kind {
+            LayerKind::Diagonal => Op::new(ops::DIAGONAL, 0, 0, 0, 0.0),
+            LayerKind::Permutation => Op::new(ops::PERMUTATION, 0, 0, 0, 0.0),
+        };
+        for (mask, value) in self.terms {
+            match self.kind {
+                LayerKind::Diagonal => {
+                    let angle = normalize_angle(f32::from_bits(value));
+                    if angle.abs() > 1e-7 {
+                        op.push_term(mask, angle);
+                    }
+                }
+                LayerKind::Permutation => op.push_mask_pair(mask, value),
+            }
+        }
+        if op.term_count() == 0 {
+            ops_out.remove(self.start);
+        } else {
+            ops_out[self.start] = op;
+        }
+    }
+}
+
+// Gathers ops of the given kind into layers. An op joins the currently open layer if none of its
+// qubits have been touched by another op since the layer started, so it can be moved back to the
+// layer's position without changing the result. The order of ops within a layer is preserved.
+fn batch_layers(circuit: &Circuit, kind: LayerKind) -> Circuit {
+    let mut ops_out: Vec<Op> = Vec::with_capacity(circuit.ops.len());
+    let mut layer: Option<Layer> = None;
+
+    for op in &circuit.ops {
+        let Some(terms) = kind.terms(op) else {
+            if let Some(layer) = layer.as_mut() {
+                layer.blocked |= op.qubit_mask();
+            }
+            ops_out.push(*op);
+            continue;
+        };
+
+        if let Some(open) = layer.as_mut().filter(|l| l.fits(op, &terms)) {
+            open.add(&terms);
+            continue;
+        }
+
+        if let Some(closed) = layer.take() {
+            closed.close(&mut ops_out);
+        }
+        // Reserve the slot for the new layer. It's filled in when the layer is closed.
+        let mut new_layer = Layer {
+            kind,
+            start: ops_out.len(),
+            terms: Vec::new(),
+            first_op: *op,
+            op_count: 0,
+            blocked: 0,
+        };
+        new_layer.add(&terms);
+        ops_out.push(*op);
+        layer = Some(new_layer);
+    }
+
+    if let Some(closed) = layer.take() {
+        closed.close(&mut ops_out);
+    }
+
+    circuit.with_ops(ops_out)
+}
+
+/// Gather runs of commuting diagonal gates (Z, S, T, RZ, RZZ, CZ and their adjoints) into DIAGONAL ops.
+///
+/// Each layer is applied by the shader as a single sweep multiplying each amplitude by a phase
+/// computed from its index bits.
+pub fn batch_diagonal_ops(circuit: &Circuit) -> Circuit {
+    batch_layers(circuit, LayerKind::Diagonal)
+}
+
+/// Gather runs of basis permuting gates (X, CX, CCX, SWAP) into PERMUTATION ops.
+///
+/// Each layer is applied as a single gather from the state vector into a second buffer, where the
+/// source index of each entry is found by running the steps of the permutation in reverse.
+pub fn batch_permutation_ops(circuit: &Circuit) -> Circuit {
+    batch_layers(circuit, LayerKind::Permutation)
+}
+
+/// Merge each run of single qubit gates on a qubit into one MATRIX1Q op.
+///
+/// The matrices are computed on the host, so the shader applies each run in one sweep without
+/// recomputing the cos and sin of rotation angles per thread.
+pub fn fuse_1q_matrices(circuit: &Circuit) -> Circuit {
+    let mut pending: Vec<Option<[Complex32; 4]>> = vec![None; circuit.qubit_count as usize];
+    let mut ops_out = Vec::with_capacity(circuit.ops.len());
+    let flush = |pending: &mut Vec<Option<[Complex32; 4]>>, mask: u32, ops_out: &mut Vec<Op>| {
+        for (q, m) in pending.iter_mut().enumerate().filter(|(q, _)| mask & (1 << q) != 0) {
+            if let Some(m) = m.take() {
+                ops_out.push(Op::with_matrix_1q(q as u32, &m));
+            }
+        }
+    };
+
+    for op in &circuit.ops {
+        if let Some(m) = matrix_1q(op).filter(|_| op.op_id != ops::ID) {
+            let q = op.q1 as usize;
+            pending[q] = Some(pending[q].map_or(m, |prev| matmul(&m, &prev)));
+        } else {
+            flush(&mut pending, op.qubit_mask(), &mut ops_out);
+            ops_out.push(*op);
+        }
+    }
+    // Only a circuit without the final measurement can have gates left over
+    flush(&mut pending, u32::MAX, &mut ops_out);
+    circuit.with_ops(ops_out)
+}
+
+/// Remove qubits no op acts on, and renumber the rest densely (keeping their order).
+///
+/// Each removed qubit halves the state vector. The original labels are kept in the circuit's
+/// logical_qubits, and the backends use them to report results over the original qubits. ID ops
+/// don't count as using a qubit and are dropped.
+pub fn compact_qubits(circuit: &Circuit) -> Circuit {
+    let ops_in: Vec<&Op> = circuit.ops.iter().filter(|op| op.op_id != ops::ID).collect();
+    let mut used = vec![false; circuit.qubit_count as usize];
+    for q in ops_in.iter().flat_map(|op| op.qubits()) {
+        used[q as usize] = true;
+    }
+
+    // Dense index for each used qubit
+    let mut dense = vec![0u32; circuit.qubit_count as usize];
+    let mut logical_qubits = Vec::new();
+    let mut initial_logical_qubits = Vec::new();
+    for q in (0..circuit.qubit_count as u32).filter(|&q| used[q as usize]) {
+        dense[q as usize] = logical_qubits.len() as u32;
+        logical_qubits.push(circuit.logical_qubit(q));
+        if !circuit.initial_logical_qubits.is_empty() {
+            initial_logical_qubits.push(circuit.initial_logical_qubit(q));
+        }
+    }
+
+    let ops_out = ops_in.iter().map(|op| op.map_qubits(|q| dense[q as usize])).collect();
+    Circuit { qubit_count: logical_qubits.len() as i32, ops: ops_out, logical_qubits, initial_logical_qubits }
+}
+
+// Minimum number of extra uses within a window for moving a qubit to a lower position to be worth a SWAP sweep
+const MIN_SWAP_GAIN: usize = 4;
+
+// Number of ops acting on each qubit
+fn qubit_usage(ops: &[Op], qubit_count: usize) -> Vec<usize> {
+    let mut counts = vec![0; qubit_count];
+    for op in ops.iter().filter(|op| op.op_id != ops::MEVERYZ) {
+        let mask = op.qubit_mask();
+        for (q, count) in counts.iter_mut().enumerate() {
+            if mask & (1 << q) != 0 {
+                *count += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// Choose a position (physical qubit) for each qubit so the most used ones have the smallest strides.
+///
+/// With a `window` of 0 the layout is fixed for the whole circuit, chosen by overall use. Otherwise
+/// the layout is chosen by use in the first `window` ops, and at the start of each following window
+/// SWAP ops are inserted to move qubits used much more in that window into lower positions. The
+/// final layout is kept in logical_qubits, so results are still reported in logical order.
+///
+/// With SWAPs the circuit ends in a different layout than it starts in, which is kept in
+/// initial_logical_qubits. An InitialState is over the simulated qubits at the start of the run, so
+/// must be given in the initial layout, and a state vector read back from a run is in the final
+/// one: InitialState::relabel moves a state between the two (see Circuit::initial_layout).
+pub fn reorder_qubits(circuit: &Circuit, window: usize) -> Circuit {
+    let n = circuit.qubit_count as usize;
+    let window = if window == 0 { circuit.ops.len().max(1) } else { window };
+    let mut windows = circuit.ops.chunks(window);
+
+    // qubit_at[p] is the circuit qubit currently at position p, and position[q] the inverse
+    let first_window = windows.next().unwrap_or(&[]);
+    let initial_usage = if window >= circuit.ops.len() { qubit_usage(&circuit.ops, n) } else { qubit_usage(first_window, n) };
+    let mut qubit_at: Vec<usize> = (0..n).collect();
+    qubit_at.sort_by_key(|&q| Reverse(initial_usage[q]));
+    let mut position = vec![0usize; n];
+    for (p, &q) in qubit_at.iter().enumerate() {
+        position[q] = p;
+    }
+
+    let initial_qubit_at = qubit_at.clone();
+
+    let mut ops_out: Vec<Op> = Vec::with_capacity(circuit.ops.len());
+    ops_out.extend(first_window.iter().map(|op| op.map_qubits(|q| position[q as usize] as u32)));
+
+    for ops_window in windows {
+        let usage = qubit_usage(ops_window, n);
+        // Selection sort of the positions by use in this window, only swapping where it's worth it
+        for p in 0..n {
+            let best = (p..n).max_by_key(|&p2| (usage[qubit_at[p2]], Reverse(p2))).unwrap();
+            let (hot, cold) = (qubit_at[best], qubit_at[p]);
+            if usage[hot] >= usage[cold] + MIN_SWAP_GAIN {
+                ops_out.push(Op::new(ops::SWAP, p as u32, best as u32, 0, 0.0));
+                qubit_at.swap(p, best);
+                position[hot] = p;
+                position[cold] = best;
+            }
+        }
+        ops_out.extend(ops_window.iter().map(|op| op.map_qubits(|q| position[q as usize] as u32)));
+    }
+
+    let logical_qubits = qubit_at.iter().map(|&q| circuit.logical_qubit(q as u32)).collect();
+    let swapped = ops_out.len() > circuit.ops.len();
+    let initial_logical_qubits = if swapped || !circuit.initial_logical_qubits.is_empty() {
+        initial_qubit_at.iter().map(|&q| circuit.initial_logical_qubit(q as u32)).collect()
+    } else {
+        Vec::new()
+    };
+    Circuit { qubit_count: circuit.qubit_count, ops: ops_out, logical_qubits, initial_logical_qubits }
+}
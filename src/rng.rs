@@ -0,0 +1,47 @@
+#![allow(unused)]
+
+// Random numbers for sampling measurement outcomes and shots.
+//
+// Philox4x32-10 (Salmon et al., "Parallel random numbers: as easy as 1, 2, 3", SC 2011) is a
+// counter-based generator: each number is a function of (key, counter) rather than the next value
+// of a stateful generator. shader.wgsl has the same implementation, so the CPU and GPU backends
+// draw the same number for the same draw of the same shot.
+
+/// Seed used until a RunConfig sets one
+pub const DEFAULT_SEED: u64 = 0x5EED;
+
+const PHILOX_M0: u32 = 0xD251_1F53;
+const PHILOX_M1: u32 = 0xCD9E_8D57;
+const PHILOX_W0: u32 = 0x9E37_79B9; // Golden ratio
+const PHILOX_W1: u32 = 0xBB67_AE85; // sqrt(3) - 1
+const PHILOX_ROUNDS: usize = 10;
+
+// (high, low) words of the 64 bit product
+fn mul_hi_lo(a: u32, b: u32) -> (u32, u32) {
+    let product = a as u64 * b as u64;
+    ((product >> 32) as u32, product as u32)
+}
+
+/// The Philox4x32-10 block for a key and counter
+pub fn philox4x32(key: [u32; 2], counter: [u32; 4]) -> [u32; 4] {
+    let [mut k0, mut k1] = key;
+    let mut c = counter;
+    for round in 0..PHILOX_ROUNDS {
+        if round > 0 {
+            k0 = k0.wrapping_add(PHILOX_W0);
+            k1 = k1.wrapping_add(PHILOX_W1);
+        }
+        let (hi0, lo0) = mul_hi_lo(PHILOX_M0, c[0]);
+        let (hi1, lo1) = mul_hi_lo(PHILOX_M1, c[2]);
+        c = [hi1 ^ c[1] ^ k0, lo1, hi0 ^ c[3] ^ k1, lo0];
+    }
+    c
+}
+
+/// A uniform random number in [0, 1) for the `index`th draw of a shot
+pub fn uniform(seed: u64, shot: u64, index: u64) -> f32 {
+    let key = [seed as u32, (seed >> 32) as u32];
+    let counter = [index as u32, (index >> 32) as u32, shot as u32, (shot >> 32) as u32];
+    // The top 24 bits fill an f32 mantissa exactly
+    (philox4x32(key, counter)[0] >> 8) as f32 / (1u32 << 24) as f32
+}
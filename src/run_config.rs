@@ -0,0 +1,53 @@
+#![allow(unused)]
+
+use crate::rng;
+
+/// Options for running a circuit, taken by both GpuContext and CpuSimulator
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunConfig {
+    /// Seeds the random numbers measurements and shots are sampled with. The same seed gives the
+    /// same outcomes on every run, and on either backend.
+    pub seed: u64,
+    /// The basis states `run` reports
+    pub results: ResultSelection,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig { seed: rng::DEFAULT_SEED, results: ResultSelection::default() }
+    }
+}
+
+/// Which basis states a run reports. When more pass the threshold than fit, the likeliest are
+/// kept, with ties going to the lower index, and the run reports how many were left out.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ResultSelection {
+    /// Basis states with probability above `threshold`, keeping at most `capacity` of them
+    Threshold { threshold: f32, capacity: usize },
+    /// Exactly the `k` likeliest basis states (or all with nonzero probability, if fewer)
+    TopK(usize),
+}
+
+impl ResultSelection {
+    /// Basis states must have a probability above this to be reported
+    pub fn threshold(&self) -> f32 {
+        match *self {
+            ResultSelection::Threshold { threshold, .. } => threshold,
+            ResultSelection::TopK(_) => 0.0,
+        }
+    }
+
+    /// Most basis states reported
+    pub fn capacity(&self) -> usize {
+        match *self {
+            ResultSelection::Threshold { capacity, .. } => capacity,
+            ResultSelection::TopK(k) => k,
+        }
+    }
+}
+
+impl Default for ResultSelection {
+    fn default() -> Self {
+        ResultSelection::Threshold { threshold: 0.01, capacity: 100 }
+    }
+}
@@ -0,0 +1,135 @@
+#![allow(unused)]
+
+use std::collections::HashMap;
+
+use crate::circuit::Circuit;
+use crate::shader_types::{ops, Result};
+
+// Reading out runs, shot sampling and marginal distributions, shared by the GPU and CPU backends.
+
+/// Most qubits a marginal distribution can be over, giving 2^20 probabilities
+pub const MAX_MARGINAL_QUBITS: usize = 20;
+
+/// What a run reports
+#[derive(Clone, Debug, Default)]
+pub struct RunOutput {
+    /// The basis states chosen by the config's ResultSelection, over the original qubit labels,
+    /// sorted by sort_results
+    pub results: Vec<Result>,
+    /// How many more basis states passed the threshold than the capacity kept
+    pub overflow: usize,
+    /// The classical results written by the measurements (0 or 1, by result index)
+    pub outcomes: Vec<u32>,
+}
+
+/// Sort results likeliest first, then by index, so the order doesn't depend on the backend's scan
+pub fn sort_results(results: &mut [Result]) {
+    results.sort_by(|a, b| b.probability.total_cmp(&a.probability).then(a.entry_idx.cmp(&b.entry_idx)));
+}
+
+/// Number of shots giving each bitstring. Bitstrings are written with bit 0 rightmost, so they
+/// read as the binary value of the result (or basis state index).
+pub type Counts = HashMap<String, usize>;
+
+/// How a circuit's shots are produced.
+///
+/// Each shot's bitstring holds the circuit's classical results, then the qubits (by original
+/// label) no measurement writes a result for, read from the final state as the implicit MEVERYZ
+/// measures them. A circuit without measurements so reads every qubit.
+pub enum Readout {
+    /// Sample the state left by the ops before `stop`. Bit i of each shot is the value of qubit
+    /// `bits[i]` in the sampled basis state, or 0 for None.
+    FinalState { stop: usize, bits: Vec<Option<u32>> },
+    /// Measurements or resets change the state partway through, so run the ops before `stop` once
+    /// per shot, read the classical results, then sample one basis state of the state it leaves for
+    /// the `bits` after them (as for FinalState).
+    PerShot { stop: usize, bits: Vec<Option<u32>> },
+    /// As for FinalState, but noise makes each shot's state different, so run the ops before
+    /// `stop` once per shot and sample one basis state from each.
+    Trajectories { stop: usize, bits: Vec<Option<u32>> },
+}
+
+impl Readout {
+    /// Circuits whose measurements are all at the end, each of a different qubit, sample the state
+    /// before them. With noise, that state is sampled once per trajectory.
+    pub fn for_circuit(circuit: &Circuit) -> Readout {
+        let noisy = circuit.ops.iter().any(|op| ops::is_noise(op.op_id));
+        let end = circuit.ops.iter().rposition(|op| op.op_id != ops::MEVERYZ).map_or(0, |idx| idx + 1);
+
+        // The qubits (by original label) read from the final state
+        let measured: Vec<u32> = circuit.ops.iter()
+            .filter(|op| ops::is_measurement(op.op_id))
+            .map(|op| circuit.logical_qubit(op.q1))
+            .collect();
+        let width = (0..circuit.qubit_count as u32).map(|q| circuit.logical_qubit(q) + 1).max().unwrap_or(0);
+        let final_bits = (0..width)
+            .filter(|label| !measured.contains(label))
+            .map(|label| circuit.simulated_qubit(label));
+
+        let mut bits = vec![None; circuit.result_count()];
+        let Some(first) = circuit.ops.iter().position(|op| ops::collapses_state(op.op_id)) else {
+            bits.extend(final_bits);
+            return if noisy { Readout::Trajectories { stop: end, bits } } else { Readout::FinalState { stop: end, bits } };
+        };
+
+        let mut terminal = true;
+        let mut measured_mask = 0u32;
+        for op in &circuit.ops[first..end] {
+            let qubit_bit = 1 << op.q1;
+            if !ops::is_measurement(op.op_id) || measured_mask & qubit_bit != 0 || bits[op.q2 as usize].is_some() {
+                terminal = false;
+                break;
+            }
+            measured_mask |= qubit_bit;
+            bits[op.q2 as usize] = Some(op.q1);
+        }
+        if !terminal {
+            return Readout::PerShot { stop: end, bits: final_bits.collect() };
+        }
+        bits.extend(final_bits);
+        if noisy { Readout::Trajectories { stop: first, bits } } else { Readout::FinalState { stop: first, bits } }
+    }
+}
+
+/// The bitstring of a sampled basis state, reading each bit from the qubit given by `bits`
+pub fn state_bitstring(entry_idx: u32, bits: &[Option<u32>]) -> String {
+    bits.iter().rev().map(|bit| match bit {
+        Some(q) if entry_idx & (1 << q) != 0 => '1',
+        _ => '0',
+    }).collect()
+}
+
+/// The bitstring of a shot run in full: its classical results, followed by the `bits` of the basis
+/// state sampled from its final state
+pub fn shot_bitstring(outcomes: &[u32], entry_idx: u32, bits: &[Option<u32>]) -> String {
+    let results: String = outcomes.iter().rev().map(|&outcome| if outcome == 1 { '1' } else { '0' }).collect();
+    state_bitstring(entry_idx, bits) + &results
+}
+
+/// The simulated qubit for each qubit (by original label) of a marginal distribution, or None for
+/// qubits the circuit doesn't simulate. Panics if there are more than MAX_MARGINAL_QUBITS or any repeat.
+pub fn marginal_qubits(circuit: &Circuit, qubits: &[u32]) -> Vec<Option<u32>> {
+    assert!(
+        qubits.len() <= MAX_MARGINAL_QUBITS,
+        "Marginals are limited to {} qubits, got {}", MAX_MARGINAL_QUBITS, qubits.len()
+    );
+    for (i, qubit) in qubits.iter().enumerate() {
+        assert!(!qubits[..i].contains(qubit), "Qubit {} appears more than once in the marginal", qubit);
+    }
+    qubits.iter().map(|&q| circuit.simulated_qubit(q)).collect()
+}
+
+/// <Z...Z> over all the qubits of a marginal distribution, e.g. <Z_i> from the marginal of qubit i,
+/// or <Z_i Z_j> from that of qubits i and j
+pub fn parity_expectation(marginal: &[f32]) -> f64 {
+    marginal.iter().enumerate()
+        .map(|(bin, &p)| if bin.count_ones() % 2 == 1 { -p as f64 } else { p as f64 })
+        .sum()
+}
+
+/// Counts as (bitstring, count) pairs, most frequent first, then in bitstring order
+pub fn sorted_counts(counts: &Counts) -> Vec<(&str, usize)> {
+    let mut sorted: Vec<(&str, usize)> = counts.iter().map(|(bits, &count)| (bits.as_str(), count)).collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    sorted
+}
@@ -0,0 +1,74 @@
+#![allow(unused)]
+
+use crate::circuit::Circuit;
+use crate::shader_types::ops;
+
+/// The ops of a circuit partitioned into moments, where each moment is a set of ops acting on
+/// disjoint qubits that could run in parallel.
+pub struct Schedule {
+    /// Indices into the circuit's ops for each moment, in circuit order
+    pub moments: Vec<Vec<usize>>,
+    /// Number of moments
+    pub depth: usize,
+    /// Longest chain of dependent multi-qubit ops, ignoring single qubit ops
+    pub two_qubit_depth: usize,
+    /// Indices into the circuit's ops along one longest chain of dependent ops
+    pub critical_path: Vec<usize>,
+}
+
+/// Schedule each op into the earliest moment after all earlier ops on its qubits (ASAP scheduling).
+///
+/// The implicit MEVERYZ op at the end of a circuit isn't scheduled, so a circuit with no explicit
+/// ops has a depth of 0.
+pub fn schedule(circuit: &Circuit) -> Schedule {
+    let qubit_count = circuit.qubit_count as usize;
+
+    let mut moments: Vec<Vec<usize>> = Vec::new();
+    // Per qubit: the first moment it's free in, the last op on it, and its multi-qubit depth so far
+    let mut ready = vec![0usize; qubit_count];
+    let mut last_op: Vec<Option<usize>> = vec![None; qubit_count];
+    let mut depth_2q = vec![0usize; qubit_count];
+    // The op that determined each op's moment, for walking back along the critical path
+    let mut predecessor: Vec<Option<usize>> = vec![None; circuit.ops.len()];
+
+    for (idx, op) in circuit.ops.iter().enumerate() {
+        if op.op_id == ops::MEVERYZ {
+            continue;
+        }
+        let qubits: Vec<usize> = op.qubits().into_iter().map(|q| q as usize).filter(|&q| q < qubit_count).collect();
+
+        let moment = qubits.iter().map(|&q| ready[q]).max().unwrap_or(0);
+        predecessor[idx] = qubits
+            .iter()
+            .filter(|&&q| ready[q] == moment && moment > 0)
+            .find_map(|&q| last_op[q]);
+
+        let is_multi_qubit = qubits.len() > 1;
+        let op_depth_2q = qubits.iter().map(|&q| depth_2q[q]).max().unwrap_or(0) + usize::from(is_multi_qubit);
+
+        for &q in &qubits {
+            ready[q] = moment + 1;
+            last_op[q] = Some(idx);
+            depth_2q[q] = op_depth_2q;
+        }
+        if moments.len() <= moment {
+            moments.resize_with(moment + 1, Vec::new);
+        }
+        moments[moment].push(idx);
+    }
+
+    let mut critical_path = Vec::new();
+    let mut current = moments.last().and_then(|last| last.first().copied());
+    while let Some(idx) = current {
+        critical_path.push(idx);
+        current = predecessor[idx];
+    }
+    critical_path.reverse();
+
+    Schedule {
+        depth: moments.len(),
+        two_qubit_depth: depth_2q.iter().copied().max().unwrap_or(0),
+        moments,
+        critical_path,
+    }
+}
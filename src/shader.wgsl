@@ -31,6 +31,35 @@ const CCX: u32     = 18;
 const MZ: u32      = 19;
 const MRESETZ: u32 = 20;
 const MEVERYZ: u32 = 21;
+const DIAGONAL: u32 = 22;
+const PERMUTATION: u32 = 23;
+const SWAP: u32    = 24;
+const MCX: u32     = 25;
+const MCZ: u32     = 26;
+const MCPHASE: u32 = 27;
+const MCRX: u32    = 28;
+const MCRY: u32    = 29;
+const MCRZ: u32    = 30;
+const ISWAP: u32   = 31;
+const CY: u32      = 32;
+const CH: u32      = 33;
+const CRX: u32     = 34;
+const CRY: u32     = 35;
+const CRZ: u32     = 36;
+const CPHASE: u32  = 37;
+const RXX: u32     = 38;
+const RYY: u32     = 39;
+const U3: u32      = 40;
+const MATRIX1Q: u32 = 41;
+const MATRIX2Q: u32 = 42;
+const PAULI_EXP: u32 = 43;
+const DEPOLARIZE: u32 = 44;
+const BIT_FLIP: u32 = 45;
+const PHASE_FLIP: u32 = 46;
+const AMPLITUDE_DAMP: u32 = 47;
+const PHASE_DAMP: u32 = 48;
+
+const OP_DATA_WORDS: u32 = 59u;
 
 struct Op {
     op_id: u32,
@@ -38,6 +67,8 @@ struct Op {
     q2: u32,
     q3: u32,
     angle: f32,
+    // Op specific data. For term lists, data[0] is the count followed by (mask, value) pairs
+    data: array<u32, OP_DATA_WORDS>,
 }
 
 struct Result {
@@ -45,6 +76,25 @@ struct Result {
     probability: f32,
 }
 
+struct Measurement {
+    outcome: u32,
+    probability: f32,
+}
+
+struct RunParams {
+    seed: vec2u,
+    shot: vec2u,
+    sample_offset: u32,
+    sample_count: u32,
+    result_threshold: f32,
+    result_capacity: u32,
+}
+
+// Words of the counters buffer: the result selection state, then a histogram of key digits
+const RESULT_COUNT: u32 = 0u;
+const CANDIDATE_COUNT: u32 = 1u;
+const COUNTER_WORDS: u32 = 272u;
+
 // ***** END IMPORTANT SECTION *****
 
 const M_PI       = 3.14159265358979323846264338327950288;  /* pi        */
@@ -66,8 +116,30 @@ var<storage, read> op: Op;
 @group(0) @binding(2)
 var<storage, read_write> results: array<Result>;
 
+// Result selection state (see count_candidates)
 @group(0) @binding(3)
-var<storage, read_write> result_idx: atomic<u32>;
+var<storage, read_write> counters: array<atomic<u32>, COUNTER_WORDS>;
+
+// Ops that can't update the state in place (e.g. permutations) write here instead.
+// The host then swaps this with stateVec for the following ops.
+@group(0) @binding(4)
+var<storage, read_write> stateVecOut: array<vec2f>;
+
+// The (P(0), P(1)) sums of each thread's chunk of the state vector for the current measurement
+@group(0) @binding(5)
+var<storage, read_write> partials: array<vec2f>;
+
+// Each mid-circuit measurement and reset of the run, indexed by op.q3
+@group(0) @binding(6)
+var<storage, read_write> measurements: array<Measurement>;
+
+// The sampled basis state for each shot of a batch, or the f32 bits of each term's expectation
+// value or marginal probability
+@group(0) @binding(7)
+var<storage, read_write> samples: array<u32>;
+
+@group(0) @binding(8)
+var<uniform> run: RunParams;
 
 // The below should all be overridden by the Rust code when creating the pipeline based on the circuit
 override WORKGROUP_SIZE_X: u32;
@@ -78,13 +150,7 @@ fn run_statevector_ops(@builtin(global_invocation_id) global_id: vec3<u32>) {
     // This will end up being a linear id of all the threads run total (including across workgroups).
     let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
 
-    // For the last op, the first thread should scan the probabilities and write the results.
-    if (op.op_id == MEVERYZ) {
-        scan_probabilities(thread_id);
-        return;
-    }
-    // TODO: MZ and MRESETZ (assume base profile with all measurements at the end of the circuit for now)
-
+    // MEVERYZ is run by the result selection kernels instead (see count_candidates)
     switch op.op_id {
         case ID {
             // No operation, just return.
@@ -94,10 +160,35 @@ fn run_statevector_ops(@builtin(global_invocation_id) global_id: vec3<u32>) {
             apply_1q_op(thread_id);
             return;
         }
-        case CX, CZ, RZZ {
+        case CX, CZ, RZZ, SWAP, ISWAP, RXX, RYY, MATRIX2Q {
             apply_2q_op(thread_id);
             return;
         }
+        case DIAGONAL {
+            apply_diagonal_op(thread_id);
+            return;
+        }
+        case PERMUTATION {
+            apply_permutation_op(thread_id);
+            return;
+        }
+        case PAULI_EXP {
+            apply_pauli_exp_op(thread_id);
+            return;
+        }
+        case RESET, MZ, MRESETZ {
+            // measure_probabilities and sample_measurement have already run for this op
+            collapse_measured_qubit(thread_id);
+            return;
+        }
+        case DEPOLARIZE, BIT_FLIP, PHASE_FLIP, AMPLITUDE_DAMP, PHASE_DAMP {
+            apply_noise(thread_id);
+            return;
+        }
+        case CCX, MCX, MCZ, MCPHASE, MCRX, MCRY, MCRZ, CY, CH, CRX, CRY, CRZ, CPHASE, U3, MATRIX1Q {
+            apply_controlled_1q_op(thread_id);
+            return;
+        }
         default {
             // TODO: Report error for unsupported op
         }
@@ -111,6 +202,11 @@ fn cplxmul(a: vec2f, b: vec2f) -> vec2f {
     );
 }
 
+// Entry of the row major matrix stored in the data of MATRIX1Q and MATRIX2Q ops
+fn matrix_entry(idx: u32) -> vec2f {
+    return vec2f(bitcast<f32>(op.data[2u * idx]), bitcast<f32>(op.data[2u * idx + 1u]));
+}
+
 fn apply_1q_op(thread_id: u32) {
     const ITERATIONS: i32 = 1 << (MAX_QUBITS_PER_THREAD - 1);
 
@@ -119,25 +215,48 @@ fn apply_1q_op(thread_id: u32) {
 
     // Find the start offset based on the thread and stride
     var offset: i32 = thread_start_iteration % stride + ((thread_start_iteration / stride) * 2 * stride);
-    let iterations: i32 = select(ITERATIONS, (1 << (QUBIT_COUNT - 1)), QUBIT_COUNT < MAX_QUBITS_PER_THREAD);
+    let iterations: i32 = select(ITERATIONS, (1 << QUBIT_COUNT) >> 1, QUBIT_COUNT < MAX_QUBITS_PER_THREAD);
 
     var coeff1: vec2f = vec2f(0.0, 0.0);
     var coeff2: vec2f = vec2f(0.0, 0.0);
 
-    // TODO: X, Y, Z, S, S_ADJ, T, T_ADJ, SX_ADJ, RY
+    // X and Y need no coefficients
     switch op.op_id {
         case SX {
             coeff1 = vec2f(0.5, 0.5);
             coeff2 = vec2f(0.5, -0.5);
         }
+        case SX_ADJ {
+            coeff1 = vec2f(0.5, -0.5);
+            coeff2 = vec2f(0.5, 0.5);
+        }
         case RX {
             coeff1 = vec2f(cos(op.angle / 2.0), 0.0);
             coeff2 = vec2f(0, -sin(op.angle / 2));
         }
+        case RY {
+            coeff1 = vec2f(cos(op.angle / 2.0), 0.0);
+            coeff2 = vec2f(sin(op.angle / 2.0), 0.0);
+        }
+        // The diagonal gates only scale the |1> entry, by coeff2
         case RZ {
-            // Coeff1 is just 1, and don't get used for Rz
             coeff2 = vec2f(cos(op.angle), sin(op.angle));
         }
+        case Z {
+            coeff2 = vec2f(-1.0, 0.0);
+        }
+        case S {
+            coeff2 = vec2f(0.0, 1.0);
+        }
+        case S_ADJ {
+            coeff2 = vec2f(0.0, -1.0);
+        }
+        case T {
+            coeff2 = vec2f(M_SQRT1_2, M_SQRT1_2);
+        }
+        case T_ADJ {
+            coeff2 = vec2f(M_SQRT1_2, -M_SQRT1_2);
+        }
         case H {
             coeff1 = vec2f(M_SQRT1_2, 0.0);
             coeff2 = vec2f(-M_SQRT1_2, 0.0);
@@ -155,19 +274,17 @@ fn apply_1q_op(thread_id: u32) {
                 stateVec[offset + stride] = stateVec[offset];
                 stateVec[offset] = entry1;
             }
-            case SX {
+            case Y {
+                // -i * entry1, and i * entry0
                 let entry0 = stateVec[offset];
-                let res0 = cplxmul(entry0, coeff1) + cplxmul(entry1, coeff2);
-                let res1 = cplxmul(entry0, coeff2) + cplxmul(entry1, coeff1);
-
-                stateVec[offset] = res0;
-                stateVec[offset + stride] = res1;
+                stateVec[offset] = vec2f(entry1.y, -entry1.x);
+                stateVec[offset + stride] = vec2f(-entry0.y, entry0.x);
             }
-            case RZ {
+            case RZ, Z, S, S_ADJ, T, T_ADJ {
                 let res1 = cplxmul(entry1, coeff2);
                 stateVec[offset + stride] = res1;
             }
-            case RX {
+            case SX, SX_ADJ, RX {
                 let entry0 = stateVec[offset];
                 let res0 = cplxmul(entry0, coeff1) + cplxmul(entry1, coeff2);
                 let res1 = cplxmul(entry0, coeff2) + cplxmul(entry1, coeff1);
@@ -175,6 +292,11 @@ fn apply_1q_op(thread_id: u32) {
                 stateVec[offset] = res0;
                 stateVec[offset + stride] = res1;
             }
+            case RY {
+                let entry0 = stateVec[offset];
+                stateVec[offset] = entry0 * coeff1.x - entry1 * coeff2.x;
+                stateVec[offset + stride] = entry0 * coeff2.x + entry1 * coeff1.x;
+            }
             case H {
                 let entry0 = stateVec[offset];
                 let res0 = cplxmul(entry0, coeff1) + cplxmul(entry1, coeff1);
@@ -199,12 +321,15 @@ fn apply_1q_op(thread_id: u32) {
 fn apply_2q_op(thread_id: u32) {
     const ITERATIONS: i32 = 1 << (MAX_QUBITS_PER_THREAD - 2); 
 
-    let iterations: i32 = select(1 << (QUBIT_COUNT - 2), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let iterations: i32 = select((1 << QUBIT_COUNT) >> 2, ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
     let start_count: i32 = i32(thread_id) * ITERATIONS;
     let end_count: i32 = start_count + iterations;
 
     // Coefficient only needed for RZZ
-    let coeff: vec2f = select(vec2f(0.0), vec2f(cos(op.angle), -sin(op.angle)), op.op_id == RZZ);
+    let coeff: vec2f = select(vec2f(0.0), vec2f(cos(op.angle), sin(op.angle)), op.op_id == RZZ);
+    // Cosine and -i * sine of the half angle for RXX and RYY
+    let half_cos: vec2f = vec2f(cos(op.angle / 2.0), 0.0);
+    let minus_i_sin: vec2f = vec2f(0.0, -sin(op.angle / 2.0));
 
     let lowQubit = select(op.q1, op.q2, op.q1 > op.q2);
     let hiQubit = select(op.q1, op.q2, op.q1 < op.q2);
@@ -239,6 +364,54 @@ fn apply_2q_op(thread_id: u32) {
                 stateVec[offset01] = cplxmul(stateVec[offset01], coeff);
                 stateVec[offset10] = cplxmul(stateVec[offset10], coeff);
             }
+            case SWAP {
+                let offset01: i32 = (i & lowMask) | ((i & midMask) << 1) | (1 << hiQubit) | ((i & hiMask) << 2);
+                let offset10: i32 = (i & lowMask) | (1 << lowQubit) | ((i & midMask) << 1) | ((i & hiMask) << 2);
+
+                let old01 = stateVec[offset01];
+                stateVec[offset01] = stateVec[offset10];
+                stateVec[offset10] = old01;
+            }
+            case ISWAP {
+                let offset01: i32 = (i & lowMask) | ((i & midMask) << 1) | (1 << hiQubit) | ((i & hiMask) << 2);
+                let offset10: i32 = (i & lowMask) | (1 << lowQubit) | ((i & midMask) << 1) | ((i & hiMask) << 2);
+
+                let old01 = stateVec[offset01];
+                stateVec[offset01] = cplxmul(vec2f(0.0, 1.0), stateVec[offset10]);
+                stateVec[offset10] = cplxmul(vec2f(0.0, 1.0), old01);
+            }
+            case RXX, RYY {
+                let offset00: i32 = (i & lowMask) | ((i & midMask) << 1) | ((i & hiMask) << 2);
+                let offset01: i32 = offset00 | (1 << hiQubit);
+                let offset10: i32 = offset00 | (1 << lowQubit);
+                let offset11: i32 = offset01 | (1 << lowQubit);
+
+                // XX and YY both swap 01 and 10 with a factor of 1. XX swaps 00 and 11 with a factor of 1, and YY with -1.
+                let even_sin = select(minus_i_sin, -minus_i_sin, op.op_id == RYY);
+                let old00 = stateVec[offset00];
+                let old01 = stateVec[offset01];
+                stateVec[offset00] = cplxmul(half_cos, old00) + cplxmul(even_sin, stateVec[offset11]);
+                stateVec[offset11] = cplxmul(half_cos, stateVec[offset11]) + cplxmul(even_sin, old00);
+                stateVec[offset01] = cplxmul(half_cos, old01) + cplxmul(minus_i_sin, stateVec[offset10]);
+                stateVec[offset10] = cplxmul(half_cos, stateVec[offset10]) + cplxmul(minus_i_sin, old01);
+            }
+            case MATRIX2Q {
+                // Basis index k has q1 as bit 0 and q2 as bit 1
+                let base: i32 = (i & lowMask) | ((i & midMask) << 1) | ((i & hiMask) << 2);
+                var offsets: array<i32, 4>;
+                var old: array<vec2f, 4>;
+                for (var k: i32 = 0; k < 4; k++) {
+                    offsets[k] = base | ((k & 1) << op.q1) | (((k >> 1) & 1) << op.q2);
+                    old[k] = stateVec[offsets[k]];
+                }
+                for (var row: u32 = 0u; row < 4u; row++) {
+                    var sum: vec2f = vec2f(0.0, 0.0);
+                    for (var col: u32 = 0u; col < 4u; col++) {
+                        sum += cplxmul(matrix_entry(row * 4u + col), old[col]);
+                    }
+                    stateVec[offsets[row]] = sum;
+                }
+            }
             default {
 
             }
@@ -246,28 +419,746 @@ fn apply_2q_op(thread_id: u32) {
     }
 }
 
-fn scan_probabilities(thread_id: u32) {
-    // Scan the chunk of the state vector assigned to this thread and for any probabilities above 1%,
-    // write the result to the results buffer and update the atomic index.
-    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD); 
+fn apply_controlled_1q_op(thread_id: u32) {
+    // Apply a 2x2 matrix to the target of each pair of entries whose control bits match the values.
+    // Multi-controlled ops store the control mask and values in data[0] and data[1]. CCX is MCX with
+    // controls q1 and q2 and target q3, the two qubit controlled gates have control q1 and target q2,
+    // and U3 and MATRIX1Q have no controls.
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD - 1);
+
+    let iterations: u32 = select((1u << QUBIT_COUNT) >> 1u, ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_count: u32 = thread_id * ITERATIONS;
+    let end_count: u32 = start_count + iterations;
+
+    var targetQubit: u32 = op.q1;
+    var ctrl_mask: u32 = op.data[0];
+    var ctrl_values: u32 = op.data[1];
+    switch op.op_id {
+        case CCX {
+            targetQubit = op.q3;
+            ctrl_mask = (1u << op.q1) | (1u << op.q2);
+            ctrl_values = ctrl_mask;
+        }
+        case CY, CH, CRX, CRY, CRZ, CPHASE {
+            targetQubit = op.q2;
+            ctrl_mask = 1u << op.q1;
+            ctrl_values = ctrl_mask;
+        }
+        case U3, MATRIX1Q {
+            ctrl_mask = 0u;
+            ctrl_values = 0u;
+        }
+        default {}
+    }
+    let stride: u32 = 1u << targetQubit;
+
+    // Row major matrix for the target, starting from the identity
+    var m00: vec2f = vec2f(1.0, 0.0);
+    var m01: vec2f = vec2f(0.0, 0.0);
+    var m10: vec2f = vec2f(0.0, 0.0);
+    var m11: vec2f = vec2f(1.0, 0.0);
+    let c = cos(op.angle / 2.0);
+    let s = sin(op.angle / 2.0);
+
+    switch op.op_id {
+        case CCX, MCX {
+            m00 = vec2f(0.0, 0.0);
+            m01 = vec2f(1.0, 0.0);
+            m10 = vec2f(1.0, 0.0);
+            m11 = vec2f(0.0, 0.0);
+        }
+        case MCZ {
+            m11 = vec2f(-1.0, 0.0);
+        }
+        case CY {
+            m00 = vec2f(0.0, 0.0);
+            m01 = vec2f(0.0, -1.0);
+            m10 = vec2f(0.0, 1.0);
+            m11 = vec2f(0.0, 0.0);
+        }
+        case CH {
+            m00 = vec2f(M_SQRT1_2, 0.0);
+            m01 = vec2f(M_SQRT1_2, 0.0);
+            m10 = vec2f(M_SQRT1_2, 0.0);
+            m11 = vec2f(-M_SQRT1_2, 0.0);
+        }
+        case U3 {
+            // Phi and lambda follow theta (the angle) in the op data
+            let phi = bitcast<f32>(op.data[0]);
+            let lambda = bitcast<f32>(op.data[1]);
+            m00 = vec2f(c, 0.0);
+            m01 = -s * vec2f(cos(lambda), sin(lambda));
+            m10 = s * vec2f(cos(phi), sin(phi));
+            m11 = c * vec2f(cos(phi + lambda), sin(phi + lambda));
+        }
+        case MATRIX1Q {
+            m00 = matrix_entry(0u);
+            m01 = matrix_entry(1u);
+            m10 = matrix_entry(2u);
+            m11 = matrix_entry(3u);
+        }
+        case MCPHASE, CPHASE {
+            m11 = vec2f(cos(op.angle), sin(op.angle));
+        }
+        case MCRX, CRX {
+            m00 = vec2f(c, 0.0);
+            m01 = vec2f(0.0, -s);
+            m10 = vec2f(0.0, -s);
+            m11 = vec2f(c, 0.0);
+        }
+        case MCRY, CRY {
+            m00 = vec2f(c, 0.0);
+            m01 = vec2f(-s, 0.0);
+            m10 = vec2f(s, 0.0);
+            m11 = vec2f(c, 0.0);
+        }
+        case MCRZ, CRZ {
+            m00 = vec2f(c, -s);
+            m11 = vec2f(c, s);
+        }
+        default {
+            // TODO: Error
+        }
+    }
+
+    for (var i: u32 = start_count; i < end_count; i++) {
+        // Insert a 0 at the target bit to get the index of the pair's first entry
+        let offset0: u32 = ((i >> targetQubit) << (targetQubit + 1u)) | (i & (stride - 1u));
+        if (offset0 & ctrl_mask) != ctrl_values {
+            continue;
+        }
+        let offset1: u32 = offset0 | stride;
+
+        let entry0 = stateVec[offset0];
+        let entry1 = stateVec[offset1];
+        stateVec[offset0] = cplxmul(m00, entry0) + cplxmul(m01, entry1);
+        stateVec[offset1] = cplxmul(m10, entry0) + cplxmul(m11, entry1);
+    }
+}
+
+fn apply_pauli_exp_op(thread_id: u32) {
+    // e^(-i * angle * P) = cos(angle) - i * sin(angle) * P, where P|b> = i^(Y count) * (-1)^(Z parity of b) |b ^ x_mask>.
+    // Each thread handles pairs (b, b ^ x_mask) from the entry without the lowest X bit. With no X
+    // or Y in the string the op is diagonal, and pairs are just neighbours over qubit 0.
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD - 1);
+
+    let iterations: u32 = select((1u << QUBIT_COUNT) >> 1u, ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_count: u32 = thread_id * ITERATIONS;
+    let end_count: u32 = start_count + iterations;
+
+    let x_mask: u32 = op.data[0];
+    let z_mask: u32 = op.data[1];
+    let pivot: u32 = select(firstTrailingBit(x_mask), 0u, x_mask == 0u);
+    let stride: u32 = 1u << pivot;
+
+    var y_phases = array<vec2f, 4>(vec2f(1.0, 0.0), vec2f(0.0, 1.0), vec2f(-1.0, 0.0), vec2f(0.0, -1.0));
+    let c: vec2f = vec2f(cos(op.angle), 0.0);
+    let coeff: vec2f = cplxmul(vec2f(0.0, -sin(op.angle)), y_phases[countOneBits(x_mask & z_mask) & 3u]);
+
+    for (var i: u32 = start_count; i < end_count; i++) {
+        let offset0: u32 = ((i >> pivot) << (pivot + 1u)) | (i & (stride - 1u));
+        let offset1: u32 = select(offset0 ^ x_mask, offset0 | stride, x_mask == 0u);
+        let sign0: f32 = select(1.0, -1.0, (countOneBits(offset0 & z_mask) & 1u) == 1u);
+        let sign1: f32 = select(1.0, -1.0, (countOneBits(offset1 & z_mask) & 1u) == 1u);
+
+        let entry0 = stateVec[offset0];
+        let entry1 = stateVec[offset1];
+        if x_mask == 0u {
+            stateVec[offset0] = cplxmul(c + sign0 * coeff, entry0);
+            stateVec[offset1] = cplxmul(c + sign1 * coeff, entry1);
+        } else {
+            stateVec[offset0] = cplxmul(c, entry0) + sign1 * cplxmul(coeff, entry1);
+            stateVec[offset1] = cplxmul(c, entry1) + sign0 * cplxmul(coeff, entry0);
+        }
+    }
+}
+
+fn apply_diagonal_op(thread_id: u32) {
+    // Each term adds its angle to the phase of every entry with odd parity over the term's mask.
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = thread_id * ITERATIONS;
+    let end_idx: u32 = start_idx + iterations;
+    let term_count: u32 = op.data[0];
+
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        var phase: f32 = 0.0;
+        for (var t: u32 = 0u; t < term_count; t++) {
+            let mask = op.data[1u + 2u * t];
+            if (countOneBits(i & mask) & 1u) == 1u {
+                phase += bitcast<f32>(op.data[2u + 2u * t]);
+            }
+        }
+        if phase != 0.0 {
+            stateVec[i] = cplxmul(stateVec[i], vec2f(cos(phase), sin(phase)));
+        }
+    }
+}
+
+fn apply_permutation_op(thread_id: u32) {
+    // Each step toggles the flip bits of indices with all the control bits set. Gather each entry
+    // from its source index, found by undoing the steps in reverse order (each step is its own inverse).
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
 
     let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
     let start_idx: u32 = thread_id * ITERATIONS;
     let end_idx: u32 = start_idx + iterations;
+    let step_count: u32 = op.data[0];
+
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        var src: u32 = i;
+        for (var t: u32 = step_count; t > 0u; t--) {
+            let ctrl = op.data[2u * t - 1u];
+            let flip = op.data[2u * t];
+            if (src & ctrl) == ctrl {
+                src ^= flip;
+            }
+        }
+        stateVecOut[i] = stateVec[src];
+    }
+}
+
+// The final MEVERYZ op reports the entries above the threshold, keeping the likeliest up to the
+// capacity. count_candidates counts the entries above the threshold, and if there are too many,
+// a radix select finds the key of the last one to keep a digit at a time (histogram_digits then
+// select_digit, for each digit). Keys are unique, ordering by probability then lower index, so
+// exactly the capacity have keys at or above it. collect_results then writes those entries out.
+
+const SELECTING: u32 = 2u;  // 1 if there are more candidates than the capacity
+const REMAINING: u32 = 3u;  // Keys still to keep at or below the prefix
+const DIGIT_PASS: u32 = 4u;
+const PREFIX_HI: u32 = 5u;  // The digits found so far
+const PREFIX_LO: u32 = 6u;
+const MASK_HI: u32 = 7u;    // Bits of the digits found so far
+const MASK_LO: u32 = 8u;
+const HISTOGRAM: u32 = 16u;
+const DIGIT_VALUES: u32 = 256u;
+const PROBABILITY_DIGITS: u32 = 4u;
+
+// Probability bits (which order like the probabilities, as they're non-negative), then the
+// inverted index so lower indices win ties
+fn result_key(idx: u32, prob: f32) -> vec2u {
+    return vec2u(bitcast<u32>(prob), ((1u << QUBIT_COUNT) - 1u) - idx);
+}
+
+// The bits of a key holding the digit of a pass, most significant first
+fn digit_mask(digit_pass: u32) -> vec2u {
+    if digit_pass < PROBABILITY_DIGITS {
+        return vec2u(0xFFu << (24u - 8u * digit_pass), 0u);
+    }
+    let index_digits = (QUBIT_COUNT + 7u) / 8u;
+    return vec2u(0u, 0xFFu << (8u * (index_digits - 1u - (digit_pass - PROBABILITY_DIGITS))));
+}
+
+fn key_digit(key: vec2u, digit_pass: u32) -> u32 {
+    let mask = digit_mask(digit_pass);
+    let bits = (key.x & mask.x) | (key.y & mask.y);
+    return bits >> countTrailingZeros(mask.x | mask.y);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X)
+fn count_candidates(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = thread_id * ITERATIONS;
+    let end_idx: u32 = start_idx + iterations;
+
+    var count: u32 = 0u;
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        let entry = stateVec[i];
+        if entry.x * entry.x + entry.y * entry.y > run.result_threshold {
+            count++;
+        }
+    }
+    if count > 0u {
+        atomicAdd(&counters[CANDIDATE_COUNT], count);
+    }
+}
+
+// Dispatched as a single thread
+@compute @workgroup_size(1)
+fn start_selection() {
+    let selecting = atomicLoad(&counters[CANDIDATE_COUNT]) > run.result_capacity;
+    atomicStore(&counters[SELECTING], select(0u, 1u, selecting));
+    atomicStore(&counters[REMAINING], run.result_capacity);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X)
+fn histogram_digits(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if atomicLoad(&counters[SELECTING]) == 0u {
+        return;
+    }
+    let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = thread_id * ITERATIONS;
+    let end_idx: u32 = start_idx + iterations;
+    let digit_pass = atomicLoad(&counters[DIGIT_PASS]);
+    let prefix = vec2u(atomicLoad(&counters[PREFIX_HI]), atomicLoad(&counters[PREFIX_LO]));
+    let mask = vec2u(atomicLoad(&counters[MASK_HI]), atomicLoad(&counters[MASK_LO]));
 
     for (var i: u32 = start_idx; i < end_idx; i++) {
-        // Calculate the probability of this entry
         let entry = stateVec[i];
         let prob = entry.x * entry.x + entry.y * entry.y;
-        if prob > 0.01 {
-            // Use atomic operations to safely write to the results buffer
-            let curr_idx = atomicAdd(&result_idx, 1);
-            if curr_idx >= arrayLength(&results) {
-                // Shouldn't happen, but just for safety
-                continue;
-            }
+        let key = result_key(i, prob);
+        if prob > run.result_threshold && all((key & mask) == prefix) {
+            atomicAdd(&counters[HISTOGRAM + key_digit(key, digit_pass)], 1u);
+        }
+    }
+}
+
+// Dispatched as a single thread
+@compute @workgroup_size(1)
+fn select_digit() {
+    if atomicLoad(&counters[SELECTING]) == 0u {
+        return;
+    }
+    // Take the digits from the top until they hold the remaining keys to keep, then carry on into
+    // the digit holding the last of them in the next pass
+    let digit_pass = atomicLoad(&counters[DIGIT_PASS]);
+    var remaining = atomicLoad(&counters[REMAINING]);
+    var digit: u32 = 0u;
+    for (var d: u32 = DIGIT_VALUES; d > 0u; d--) {
+        let count = atomicLoad(&counters[HISTOGRAM + d - 1u]);
+        if count >= remaining {
+            digit = d - 1u;
+            break;
+        }
+        remaining -= count;
+    }
+    for (var d: u32 = 0u; d < DIGIT_VALUES; d++) {
+        atomicStore(&counters[HISTOGRAM + d], 0u);
+    }
+
+    let mask = digit_mask(digit_pass);
+    let shift = countTrailingZeros(mask.x | mask.y);
+    atomicOr(&counters[PREFIX_HI], (digit << shift) & mask.x);
+    atomicOr(&counters[PREFIX_LO], (digit << shift) & mask.y);
+    atomicOr(&counters[MASK_HI], mask.x);
+    atomicOr(&counters[MASK_LO], mask.y);
+    atomicStore(&counters[REMAINING], remaining);
+    atomicStore(&counters[DIGIT_PASS], digit_pass + 1u);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X)
+fn collect_results(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = thread_id * ITERATIONS;
+    let end_idx: u32 = start_idx + iterations;
+    let selecting = atomicLoad(&counters[SELECTING]) == 1u;
+    let pivot = vec2u(atomicLoad(&counters[PREFIX_HI]), atomicLoad(&counters[PREFIX_LO]));
+
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        let entry = stateVec[i];
+        let prob = entry.x * entry.x + entry.y * entry.y;
+        if prob <= run.result_threshold {
+            continue;
+        }
+        let key = result_key(i, prob);
+        if selecting && (key.x < pivot.x || (key.x == pivot.x && key.y < pivot.y)) {
+            continue;
+        }
+        let curr_idx = atomicAdd(&counters[RESULT_COUNT], 1u);
+        if curr_idx < arrayLength(&results) {
             results[curr_idx].entry_idx = i;
             results[curr_idx].probability = prob;
         }
     }
-}
\ No newline at end of file
+}
+
+// A measurement or reset takes three dispatches: measure_probabilities sums each thread's chunk of the state
+// vector, sample_measurement reduces the sums and samples the outcome, and then the main entry point
+// collapses the state to it. Damping channels take the same three, sampling whether the qubit jumps.
+
+@compute @workgroup_size(WORKGROUP_SIZE_X)
+fn measure_probabilities(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = thread_id * ITERATIONS;
+    let end_idx: u32 = start_idx + iterations;
+    let bit: u32 = 1u << op.q1;
+
+    var sums = vec2f(0.0, 0.0);
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        let entry = stateVec[i];
+        let prob = entry.x * entry.x + entry.y * entry.y;
+        if (i & bit) == 0u {
+            sums.x += prob;
+        } else {
+            sums.y += prob;
+        }
+    }
+    partials[thread_id] = sums;
+}
+
+const REDUCE_THREADS: u32 = 32u;
+var<workgroup> reduce_sums: array<vec2f, REDUCE_THREADS>;
+
+// Total the partial sums over a single workgroup, returning the total to the first thread
+fn reduce_partials(local_idx: u32) -> vec2f {
+    // Each thread adds a strided share of the partial sums, then halve the active threads until
+    // the first holds the total.
+    var sums = vec2f(0.0, 0.0);
+    for (var i: u32 = local_idx; i < arrayLength(&partials); i += REDUCE_THREADS) {
+        sums += partials[i];
+    }
+    reduce_sums[local_idx] = sums;
+    workgroupBarrier();
+
+    for (var half: u32 = REDUCE_THREADS / 2u; half > 0u; half /= 2u) {
+        if local_idx < half {
+            reduce_sums[local_idx] += reduce_sums[local_idx + half];
+        }
+        workgroupBarrier();
+    }
+    return reduce_sums[0];
+}
+
+// Dispatched as a single workgroup
+@compute @workgroup_size(REDUCE_THREADS)
+fn sample_measurement(@builtin(local_invocation_index) local_idx: u32) {
+    let total = reduce_partials(local_idx);
+    if local_idx == 0u {
+        // A damping channel jumps with probability angle * P(1), else the qubit's |1> part decays
+        var probs = total;
+        if op.op_id == AMPLITUDE_DAMP || op.op_id == PHASE_DAMP {
+            probs = vec2f(total.x + (1.0 - op.angle) * total.y, op.angle * total.y);
+        }
+        let outcome = select(0u, 1u, uniform_random(op.q3) < probs.y / (total.x + total.y));
+        measurements[op.q3].outcome = outcome;
+        measurements[op.q3].probability = select(probs.x, probs.y, outcome == 1u);
+    }
+}
+
+fn collapse_measured_qubit(thread_id: u32) {
+    // Keep the half of each pair matching the outcome, renormalized, and zero the other. For
+    // RESET and MRESETZ an outcome of 1 is moved back to |0>.
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD - 1);
+
+    let iterations: u32 = select((1u << QUBIT_COUNT) >> 1u, ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_count: u32 = thread_id * ITERATIONS;
+    let end_count: u32 = start_count + iterations;
+    let stride: u32 = 1u << op.q1;
+
+    let outcome = measurements[op.q3].outcome;
+    let scale = 1.0 / sqrt(measurements[op.q3].probability);
+    let keep_one = outcome == 1u && op.op_id == MZ;
+
+    for (var i: u32 = start_count; i < end_count; i++) {
+        // Insert a 0 at the measured bit to get the |0> entry of the pair
+        let offset0: u32 = ((i >> op.q1) << (op.q1 + 1u)) | (i & (stride - 1u));
+        let offset1: u32 = offset0 | stride;
+        let amp = select(stateVec[offset0], stateVec[offset1], outcome == 1u) * scale;
+        stateVec[offset0] = select(amp, vec2f(0.0, 0.0), keep_one);
+        stateVec[offset1] = select(vec2f(0.0, 0.0), amp, keep_one);
+    }
+}
+
+// Apply one Kraus operator of a noise channel, with probability op.angle. The Pauli channels pick theirs
+// with the op's random draw, independent of the state. The damping channels' depend on the probability of
+// |1>, so sample_measurement has already picked theirs: a jump, which projects onto |1> (and for
+// amplitude damping moves it to |0>), or else scaling |1> by sqrt(1 - angle). Matches cpu_simulator.rs.
+fn apply_noise(thread_id: u32) {
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD - 1);
+
+    let iterations: u32 = select((1u << QUBIT_COUNT) >> 1u, ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_count: u32 = thread_id * ITERATIONS;
+    let end_count: u32 = start_count + iterations;
+    let stride: u32 = 1u << op.q1;
+    let p = op.angle;
+
+    if op.op_id == AMPLITUDE_DAMP || op.op_id == PHASE_DAMP {
+        let jump = measurements[op.q3].outcome == 1u;
+        let scale = 1.0 / sqrt(measurements[op.q3].probability);
+        let decay = select(sqrt(1.0 - p), sqrt(p), jump) * scale;
+        let to_zero = jump && op.op_id == AMPLITUDE_DAMP;
+        for (var i: u32 = start_count; i < end_count; i++) {
+            let offset0: u32 = ((i >> op.q1) << (op.q1 + 1u)) | (i & (stride - 1u));
+            let offset1: u32 = offset0 | stride;
+            let amp1 = stateVec[offset1] * decay;
+            stateVec[offset0] = select(select(stateVec[offset0] * scale, vec2f(0.0, 0.0), jump), amp1, to_zero);
+            stateVec[offset1] = select(amp1, vec2f(0.0, 0.0), to_zero);
+        }
+        return;
+    }
+
+    // Depolarizing picks X, Y or Z with probability angle / 3 each
+    let random = uniform_random(op.q3);
+    var pauli: u32 = ID;
+    switch op.op_id {
+        case BIT_FLIP {
+            pauli = select(ID, X, random < p);
+        }
+        case PHASE_FLIP {
+            pauli = select(ID, Z, random < p);
+        }
+        default {
+            pauli = select(select(select(ID, Z, random < p), Y, 3.0 * random < 2.0 * p), X, 3.0 * random < p);
+        }
+    }
+    if pauli == ID {
+        return;
+    }
+    for (var i: u32 = start_count; i < end_count; i++) {
+        let offset0: u32 = ((i >> op.q1) << (op.q1 + 1u)) | (i & (stride - 1u));
+        let offset1: u32 = offset0 | stride;
+        let amp0 = stateVec[offset0];
+        let amp1 = stateVec[offset1];
+        switch pauli {
+            case X {
+                stateVec[offset0] = amp1;
+                stateVec[offset1] = amp0;
+            }
+            case Y {
+                // (-i * amp1, i * amp0)
+                stateVec[offset0] = vec2f(amp1.y, -amp1.x);
+                stateVec[offset1] = vec2f(-amp0.y, amp0.x);
+            }
+            default {
+                stateVec[offset1] = -amp1;
+            }
+        }
+    }
+}
+
+// The expectation value of a Pauli string takes two dispatches: pauli_expectation_partials sums each
+// thread's chunk of the state vector, then reduce_expectation totals the sums. The string is passed
+// as the masks of a PAULI_EXP op, with op.q3 its index in the batch of terms.
+
+@compute @workgroup_size(WORKGROUP_SIZE_X)
+fn pauli_expectation_partials(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    // <psi|P|psi> is the sum over b of conj(psi[b ^ x_mask]) * i^(Y count) * (-1)^(Z parity of b) * psi[b]
+    let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = thread_id * ITERATIONS;
+    let end_idx: u32 = start_idx + iterations;
+    let x_mask: u32 = op.data[0];
+    let z_mask: u32 = op.data[1];
+
+    var sum = vec2f(0.0, 0.0);
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        let paired = stateVec[i ^ x_mask];
+        let sign: f32 = select(1.0, -1.0, (countOneBits(i & z_mask) & 1u) == 1u);
+        sum += sign * cplxmul(vec2f(paired.x, -paired.y), stateVec[i]);
+    }
+
+    // The total is real, so keep the real part after applying the phase
+    var y_phases = array<vec2f, 4>(vec2f(1.0, 0.0), vec2f(0.0, 1.0), vec2f(-1.0, 0.0), vec2f(0.0, -1.0));
+    partials[thread_id] = vec2f(cplxmul(y_phases[countOneBits(x_mask & z_mask) & 3u], sum).x, 0.0);
+}
+
+// Dispatched as a single workgroup
+@compute @workgroup_size(REDUCE_THREADS)
+fn reduce_expectation(@builtin(local_invocation_index) local_idx: u32) {
+    let total = reduce_partials(local_idx);
+    if local_idx == 0u {
+        samples[op.q3] = bitcast<u32>(total.x);
+    }
+}
+
+// A marginal distribution takes two dispatches for each batch of op.q2 bins from bin op.q1:
+// marginal_partials sums the probabilities of each thread's share of the bins' entries, then
+// reduce_marginal totals the sums of each bin. Kept qubits are set in op.data[0], with the lowest
+// as bit 0 of the bin.
+
+// Scatter the low bits of value to the set bits of mask, lowest first
+fn deposit_bits(value: u32, mask: u32) -> u32 {
+    var result: u32 = 0u;
+    var remaining: u32 = mask;
+    var bits: u32 = value;
+    while remaining != 0u {
+        let lowest = remaining & (~remaining + 1u);
+        if (bits & 1u) == 1u {
+            result |= lowest;
+        }
+        bits >>= 1u;
+        remaining ^= lowest;
+    }
+    return result;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X)
+fn marginal_partials(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    // Walk the batch's entries bin by bin. Where a bin has at least as many entries as a thread
+    // handles, each thread sums part of one bin into partials. Otherwise each thread sums whole
+    // bins and writes them out directly.
+    let thread_id = global_id.x + global_id.y * WORKGROUP_SIZE_X;
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+
+    let kept_mask: u32 = op.data[0];
+    let rest_mask: u32 = ((1u << QUBIT_COUNT) - 1u) & ~kept_mask;
+    let rest_bits: u32 = QUBIT_COUNT - countOneBits(kept_mask);
+    let entry_count: u32 = op.q2 << rest_bits;
+    let start_idx: u32 = thread_id * ITERATIONS;
+    if start_idx >= entry_count {
+        return;
+    }
+    let end_idx: u32 = min(start_idx + ITERATIONS, entry_count);
+    let last_rest: u32 = (1u << rest_bits) - 1u;
+
+    var sum: f32 = 0.0;
+    for (var i: u32 = start_idx; i < end_idx; i++) {
+        let bin = i >> rest_bits;
+        let rest = i & last_rest;
+        let entry = stateVec[deposit_bits(op.q1 + bin, kept_mask) | deposit_bits(rest, rest_mask)];
+        sum += entry.x * entry.x + entry.y * entry.y;
+        if rest_bits < MAX_QUBITS_PER_THREAD && rest == last_rest {
+            samples[bin] = bitcast<u32>(sum);
+            sum = 0.0;
+        }
+    }
+    if rest_bits >= MAX_QUBITS_PER_THREAD {
+        partials[thread_id] = vec2f(sum, 0.0);
+    }
+}
+
+@compute @workgroup_size(REDUCE_THREADS)
+fn reduce_marginal(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let bin = global_id.x;
+    let rest_bits: u32 = QUBIT_COUNT - countOneBits(op.data[0]);
+    if bin >= op.q2 || rest_bits < MAX_QUBITS_PER_THREAD {
+        return;
+    }
+    let per_bin: u32 = 1u << (rest_bits - MAX_QUBITS_PER_THREAD);
+    var sum: f32 = 0.0;
+    for (var i: u32 = bin * per_bin; i < (bin + 1u) * per_bin; i++) {
+        sum += partials[i].x;
+    }
+    samples[bin] = bitcast<u32>(sum);
+}
+
+// Shot sampling takes measure_probabilities to sum each thread's chunk of the state vector, then
+// scan_chunk_sums to turn the sums into a CDF over the chunks, then sample_shots for each batch.
+
+// Dispatched as a single workgroup
+@compute @workgroup_size(REDUCE_THREADS)
+fn scan_chunk_sums(@builtin(local_invocation_index) local_idx: u32) {
+    // Each thread totals a contiguous block of chunks, the first thread finds where each block
+    // starts, then each thread writes (chunk sum, running total to the end of the chunk).
+    let count = arrayLength(&partials);
+    let block = (count + REDUCE_THREADS - 1u) / REDUCE_THREADS;
+    let start = min(local_idx * block, count);
+    let end = min(start + block, count);
+
+    var sum: f32 = 0.0;
+    for (var i: u32 = start; i < end; i++) {
+        sum += partials[i].x + partials[i].y;
+    }
+    reduce_sums[local_idx] = vec2f(sum, 0.0);
+    workgroupBarrier();
+
+    if local_idx == 0u {
+        var running: f32 = 0.0;
+        for (var t: u32 = 0u; t < REDUCE_THREADS; t++) {
+            reduce_sums[t].y = running;
+            running += reduce_sums[t].x;
+        }
+    }
+    workgroupBarrier();
+
+    var running: f32 = reduce_sums[local_idx].y;
+    for (var i: u32 = start; i < end; i++) {
+        let chunk = partials[i].x + partials[i].y;
+        running += chunk;
+        partials[i] = vec2f(chunk, running);
+    }
+}
+
+const SAMPLE_THREADS: u32 = 32u;
+
+@compute @workgroup_size(SAMPLE_THREADS)
+fn sample_shots(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let shot = global_id.x;
+    if shot >= run.sample_count {
+        return;
+    }
+    let chunk_count = arrayLength(&partials);
+    let target_sum = uniform_random(run.sample_offset + shot) * partials[chunk_count - 1u].y;
+
+    // Binary search for the first chunk whose running total passes the target
+    var lo: u32 = 0u;
+    var hi: u32 = chunk_count - 1u;
+    while lo < hi {
+        let mid = (lo + hi) / 2u;
+        if partials[mid].y > target_sum {
+            hi = mid;
+        } else {
+            lo = mid + 1u;
+        }
+    }
+
+    // Then walk the chunk's entries to the one passing it, or its last nonzero entry if rounding
+    // leaves the target just past the end
+    const ITERATIONS: u32 = 1u << (MAX_QUBITS_PER_THREAD);
+    let iterations: u32 = select(1u << (QUBIT_COUNT), ITERATIONS, QUBIT_COUNT >= MAX_QUBITS_PER_THREAD);
+    let start_idx: u32 = lo * ITERATIONS;
+    var running: f32 = partials[lo].y - partials[lo].x;
+    var entry_idx: u32 = start_idx;
+    for (var i: u32 = start_idx; i < start_idx + iterations; i++) {
+        let entry = stateVec[i];
+        let prob = entry.x * entry.x + entry.y * entry.y;
+        if prob > 0.0 {
+            entry_idx = i;
+            running += prob;
+            if running > target_sum {
+                break;
+            }
+        }
+    }
+    samples[shot] = entry_idx;
+}
+
+// Philox4x32-10 counter-based random numbers, matching rng.rs
+
+const PHILOX_M0: u32 = 0xD2511F53u;
+const PHILOX_M1: u32 = 0xCD9E8D57u;
+const PHILOX_W0: u32 = 0x9E3779B9u;
+const PHILOX_W1: u32 = 0xBB67AE85u;
+const PHILOX_ROUNDS: u32 = 10u;
+
+// (high, low) words of the 64 bit product, built from 16 bit halves as WGSL has no 64 bit integers
+fn mul_hi_lo(a: u32, b: u32) -> vec2u {
+    let a_lo = a & 0xFFFFu;
+    let a_hi = a >> 16u;
+    let b_lo = b & 0xFFFFu;
+    let b_hi = b >> 16u;
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    // Can't overflow: at most (2^16 - 1)^2 + 2 * (2^16 - 1)
+    let middle = (lo_lo >> 16u) + (hi_lo & 0xFFFFu) + lo_hi;
+    let hi = a_hi * b_hi + (hi_lo >> 16u) + (middle >> 16u);
+    let lo = (middle << 16u) | (lo_lo & 0xFFFFu);
+    return vec2u(hi, lo);
+}
+
+fn philox4x32(key: vec2u, counter: vec4u) -> vec4u {
+    var k = key;
+    var c = counter;
+    for (var i: u32 = 0u; i < PHILOX_ROUNDS; i++) {
+        if i > 0u {
+            k += vec2u(PHILOX_W0, PHILOX_W1);
+        }
+        let p0 = mul_hi_lo(PHILOX_M0, c.x);
+        let p1 = mul_hi_lo(PHILOX_M1, c.z);
+        c = vec4u(p1.x ^ c.y ^ k.x, p1.y, p0.x ^ c.w ^ k.y, p0.y);
+    }
+    return c;
+}
+
+// A uniform random number in [0, 1) for the `index`th draw of the current shot
+fn uniform_random(index: u32) -> f32 {
+    let bits = philox4x32(run.seed, vec4u(index, 0u, run.shot.x, run.shot.y)).x;
+    return f32(bits >> 8u) / 16777216.0;
+}
@@ -8,7 +8,7 @@ pub const MAX_QUBITS_PER_WORKGROUP: u32 = 12;
 // Could use an enum, but this avoids some boilerplate
 pub mod ops {
     pub const ID: u32      = 0;
-    pub const RESET: u32   = 1;
+    pub const RESET: u32   = 1; // Reset q1 to |0>, by measuring it and flipping it back if it was |1>
     pub const X: u32       = 2;
     pub const Y: u32       = 3;
     pub const Z: u32       = 4;
@@ -26,11 +26,158 @@ pub mod ops {
     pub const CZ: u32      = 16;
     pub const RZZ: u32     = 17;
     pub const CCX: u32     = 18;
+    // Mid-circuit measurements of q1, with q2 the index of the classical result the outcome is
+    // written to. The GPU backend stores the op's index among the circuit's measurements (counting
+    // resets and noise, see is_stochastic) in q3.
     pub const MZ: u32      = 19;
     pub const MRESETZ: u32 = 20;
     pub const MEVERYZ: u32 = 21; // Implicit at end of circuit (for now)
+    pub const DIAGONAL: u32 = 22; // Batched run of diagonal gates (see passes::batch_diagonal_ops)
+    pub const PERMUTATION: u32 = 23; // Batched run of X/CX/CCX gates (see passes::batch_permutation_ops)
+    pub const SWAP: u32    = 24;
+    // Multi-controlled gates on the target q1. data[0] is the control mask and data[1] the value
+    // each control must have (so a clear bit is a negative control). See Op::multi_controlled.
+    pub const MCX: u32     = 25;
+    pub const MCZ: u32     = 26;
+    pub const MCPHASE: u32 = 27; // diag(1, e^(i * angle)) on the target, like RZ
+    pub const MCRX: u32    = 28;
+    pub const MCRY: u32    = 29;
+    pub const MCRZ: u32    = 30; // diag(e^(-i * angle / 2), e^(i * angle / 2)) on the target
+
+    pub const ISWAP: u32   = 31;
+    pub const CY: u32      = 32; // The first qubit is the control for CY, CH, CRX, CRY, CRZ and CPHASE
+    pub const CH: u32      = 33;
+    pub const CRX: u32     = 34;
+    pub const CRY: u32     = 35;
+    pub const CRZ: u32     = 36; // Controlled diag(e^(-i * angle / 2), e^(i * angle / 2)), like MCRZ
+    pub const CPHASE: u32  = 37; // Controlled diag(1, e^(i * angle)), like MCPHASE
+    pub const RXX: u32     = 38; // e^(-i * angle / 2 * XX)
+    pub const RYY: u32     = 39; // e^(-i * angle / 2 * YY)
+    pub const U3: u32      = 40; // U3(theta, phi, lambda) as in OpenQASM, with phi and lambda in data[0] and data[1]
+    // Explicit matrices, stored row major in data as (re, im) pairs. See Op::with_matrix_1q and Op::with_matrix_2q.
+    pub const MATRIX1Q: u32 = 41;
+    pub const MATRIX2Q: u32 = 42; // Basis states ordered with q1 as the low bit, i.e. |q2 q1>
+    // e^(-i * angle * P) for a Pauli string P, with the X mask (qubits with X or Y) in data[0] and
+    // the Z mask (qubits with Z or Y) in data[1]. See Op::pauli_exp.
+    pub const PAULI_EXP: u32 = 43;
+    // Noise channels on q1, with the error probability (or damping rate) in angle. Inserted by
+    // NoiseModel::apply, and applied by sampling one of the channel's Kraus operators each shot.
+    // Like measurements, the GPU backend numbers them in q3 for their random draws.
+    pub const DEPOLARIZE: u32 = 44; // X, Y or Z, each with probability angle / 3
+    pub const BIT_FLIP: u32 = 45;
+    pub const PHASE_FLIP: u32 = 46;
+    pub const AMPLITUDE_DAMP: u32 = 47; // Decay from |1> to |0>
+    pub const PHASE_DAMP: u32 = 48;
+
+    pub fn is_multi_controlled(op_id: u32) -> bool {
+        matches!(op_id, MCX | MCZ | MCPHASE | MCRX | MCRY | MCRZ)
+    }
+
+    /// Mid-circuit measurements, which record an outcome in the classical results
+    pub fn is_measurement(op_id: u32) -> bool {
+        matches!(op_id, MZ | MRESETZ)
+    }
+
+    /// Ops that sample an outcome and collapse the state to it: measurements, and resets, which
+    /// don't record the outcome
+    pub fn collapses_state(op_id: u32) -> bool {
+        matches!(op_id, RESET | MZ | MRESETZ)
+    }
+
+    /// Noise channels, inserted by NoiseModel::apply
+    pub fn is_noise(op_id: u32) -> bool {
+        matches!(op_id, DEPOLARIZE | BIT_FLIP | PHASE_FLIP | AMPLITUDE_DAMP | PHASE_DAMP)
+    }
+
+    /// Noise channels whose Kraus operators' probabilities depend on the state, so need the
+    /// probability of |1> summed like a measurement
+    pub fn is_damping(op_id: u32) -> bool {
+        matches!(op_id, AMPLITUDE_DAMP | PHASE_DAMP)
+    }
+
+    /// Ops drawing a random number each shot: measurements, resets and noise channels
+    pub fn is_stochastic(op_id: u32) -> bool {
+        collapses_state(op_id) || is_noise(op_id)
+    }
+
+    /// Ops on exactly two qubits, q1 and q2
+    pub fn is_two_qubit(op_id: u32) -> bool {
+        matches!(
+            op_id,
+            CX | CZ | RZZ | SWAP | ISWAP | CY | CH | CRX | CRY | CRZ | CPHASE | RXX | RYY | MATRIX2Q
+        )
+    }
+
+    /// Number of angles an op takes (see Op::with_angles)
+    pub fn angle_count(op_id: u32) -> usize {
+        match op_id {
+            RX | RY | RZ | RZZ | MCPHASE | MCRX | MCRY | MCRZ | CRX | CRY | CRZ | CPHASE | RXX | RYY | PAULI_EXP => 1,
+            U3 => 3,
+            _ => 0,
+        }
+    }
+
+    /// Lower case name of an op, as used in .crc files where it has a syntax
+    pub fn name(op_id: u32) -> &'static str {
+        match op_id {
+            ID => "id",
+            RESET => "reset",
+            X => "x",
+            Y => "y",
+            Z => "z",
+            H => "h",
+            S => "s",
+            S_ADJ => "s_adj",
+            T => "t",
+            T_ADJ => "t_adj",
+            SX => "sx",
+            SX_ADJ => "sx_adj",
+            RX => "rx",
+            RY => "ry",
+            RZ => "rz",
+            CX => "cx",
+            CZ => "cz",
+            RZZ => "rzz",
+            CCX => "ccx",
+            MZ => "mz",
+            MRESETZ => "mresetz",
+            MEVERYZ => "meveryz",
+            DIAGONAL => "diagonal",
+            PERMUTATION => "permutation",
+            SWAP => "swap",
+            MCX => "mcx",
+            MCZ => "mcz",
+            MCPHASE => "mcphase",
+            MCRX => "mcrx",
+            MCRY => "mcry",
+            MCRZ => "mcrz",
+            ISWAP => "iswap",
+            CY => "cy",
+            CH => "ch",
+            CRX => "crx",
+            CRY => "cry",
+            CRZ => "crz",
+            CPHASE => "cp",
+            RXX => "rxx",
+            RYY => "ryy",
+            U3 => "u3",
+            MATRIX1Q | MATRIX2Q => "unitary",
+            PAULI_EXP => "pauli_exp",
+            DEPOLARIZE => "depolarize",
+            BIT_FLIP => "bit_flip",
+            PHASE_FLIP => "phase_flip",
+            AMPLITUDE_DAMP => "amplitude_damp",
+            PHASE_DAMP => "phase_damp",
+            _ => "unknown",
+        }
+    }
 }
 
+// Number of u32 words of op specific data that fit after the fixed fields
+pub const OP_DATA_WORDS: usize = 59;
+// Ops holding a list of terms store the count in data[0], followed by (mask, value) pairs
+pub const MAX_OP_TERMS: usize = (OP_DATA_WORDS - 1) / 2;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 pub struct Op {
@@ -38,9 +185,270 @@ pub struct Op {
     pub q1: u32,
     pub q2: u32,
     pub q3: u32, // For ccx
-    pub angle: f32, // For rx, ry, rz, rzz
-    // Pad out to 256 butes for WebGPU dynamic buffer alignment
-    pub padding: [u8; 236],
+    pub angle: f32, // For rotations (the first angle, for ops with several)
+    // Op specific data, e.g. the terms of a diagonal layer.
+    // Also pads out to 256 bytes for WebGPU dynamic buffer alignment
+    pub data: [u32; OP_DATA_WORDS],
+}
+
+impl Op {
+    pub fn new(op_id: u32, q1: u32, q2: u32, q3: u32, angle: f32) -> Self {
+        Op { op_id, q1, q2, q3, angle, data: [0; OP_DATA_WORDS] }
+    }
+
+    /// An op taking several angles. The first is stored in `angle` and the rest at the start of `data`.
+    pub fn with_angles(op_id: u32, q1: u32, q2: u32, q3: u32, angles: &[f32]) -> Self {
+        let mut op = Op::new(op_id, q1, q2, q3, angles.first().copied().unwrap_or(0.0));
+        for (i, angle) in angles.iter().skip(1).enumerate() {
+            op.data[i] = angle.to_bits();
+        }
+        op
+    }
+
+    /// The angle at the given index, as passed to Op::with_angles
+    pub fn angle_at(&self, idx: usize) -> f32 {
+        if idx == 0 { self.angle } else { f32::from_bits(self.data[idx - 1]) }
+    }
+
+    /// A single qubit op applying the given 2x2 matrix (row major)
+    pub fn with_matrix_1q(qubit: u32, matrix: &[Complex32; 4]) -> Self {
+        let mut op = Op::new(ops::MATRIX1Q, qubit, 0, 0, 0.0);
+        op.data[..8].copy_from_slice(bytemuck::cast_slice(matrix));
+        op
+    }
+
+    /// A two qubit op applying the given 4x4 matrix (row major, with q1 as the low bit of the basis index)
+    pub fn with_matrix_2q(q1: u32, q2: u32, matrix: &[Complex32; 16]) -> Self {
+        let mut op = Op::new(ops::MATRIX2Q, q1, q2, 0, 0.0);
+        op.data[..32].copy_from_slice(bytemuck::cast_slice(matrix));
+        op
+    }
+
+    /// Entry of the matrix of a MATRIX1Q or MATRIX2Q op, in row major order
+    pub fn matrix_entry(&self, idx: usize) -> Complex32 {
+        Complex32::new(f32::from_bits(self.data[2 * idx]), f32::from_bits(self.data[2 * idx + 1]))
+    }
+
+    /// Largest deviation of M^dagger * M from the identity, for MATRIX1Q and MATRIX2Q ops (else 0)
+    pub fn unitarity_error(&self) -> f32 {
+        let dim = match self.op_id {
+            ops::MATRIX1Q => 2,
+            ops::MATRIX2Q => 4,
+            _ => return 0.0,
+        };
+        let mut error: f32 = 0.0;
+        for i in 0..dim {
+            for j in 0..dim {
+                let dot = (0..dim).fold(Complex32::ZERO, |acc, k| {
+                    acc + self.matrix_entry(k * dim + i).conj() * self.matrix_entry(k * dim + j)
+                });
+                let expected = if i == j { Complex32::ONE } else { Complex32::ZERO };
+                error = error.max((dot - expected).abs());
+            }
+        }
+        error
+    }
+
+    /// A multi-controlled gate, applied to `target` when the qubits in `ctrl_mask` match the
+    /// corresponding bits of `ctrl_values`.
+    pub fn multi_controlled(op_id: u32, ctrl_mask: u32, ctrl_values: u32, target: u32, angle: f32) -> Self {
+        debug_assert!(ops::is_multi_controlled(op_id));
+        let mut op = Op::new(op_id, target, 0, 0, angle);
+        op.data[0] = ctrl_mask;
+        op.data[1] = ctrl_values & ctrl_mask;
+        op
+    }
+
+    /// e^(-i * angle * P) for the Pauli string with the given masks: X on qubits only in the X mask,
+    /// Z on qubits only in the Z mask, and Y on qubits in both
+    pub fn pauli_exp(x_mask: u32, z_mask: u32, angle: f32) -> Self {
+        let mut op = Op::new(ops::PAULI_EXP, 0, 0, 0, angle);
+        op.data[0] = x_mask;
+        op.data[1] = z_mask;
+        op
+    }
+
+    /// The (X mask, Z mask) of a PAULI_EXP op
+    pub fn pauli_masks(&self) -> (u32, u32) {
+        (self.data[0], self.data[1])
+    }
+
+    /// The (control mask, control values) of a multi-controlled op
+    pub fn controls(&self) -> (u32, u32) {
+        (self.data[0], self.data[1])
+    }
+
+    pub fn term_count(&self) -> usize {
+        self.data[0] as usize
+    }
+
+    /// Returns the (mask, value) pair for the given term. The value is stored as raw f32 bits.
+    pub fn term(&self, idx: usize) -> (u32, f32) {
+        (self.data[1 + 2 * idx], f32::from_bits(self.data[2 + 2 * idx]))
+    }
+
+    pub fn push_term(&mut self, mask: u32, value: f32) {
+        self.push_mask_pair(mask, value.to_bits());
+    }
+
+    /// Returns a term where both halves are masks, e.g. the (control, flip) steps of a permutation.
+    pub fn mask_pair(&self, idx: usize) -> (u32, u32) {
+        (self.data[1 + 2 * idx], self.data[2 + 2 * idx])
+    }
+
+    pub fn push_mask_pair(&mut self, mask: u32, value: u32) {
+        let idx = self.term_count();
+        assert!(idx < MAX_OP_TERMS, "Too many terms for a single op");
+        self.data[1 + 2 * idx] = mask;
+        self.data[2 + 2 * idx] = value;
+        self.data[0] += 1;
+    }
+
+    /// Bit mask of the qubits this op acts on. Ops acting on the whole register return all bits set.
+    pub fn qubit_mask(&self) -> u32 {
+        match self.op_id {
+            op_id if ops::is_two_qubit(op_id) => (1 << self.q1) | (1 << self.q2),
+            ops::CCX => (1 << self.q1) | (1 << self.q2) | (1 << self.q3),
+            ops::DIAGONAL => (0..self.term_count()).fold(0, |acc, i| acc | self.term(i).0),
+            ops::PERMUTATION => (0..self.term_count()).fold(0, |acc, i| {
+                let (ctrl, flip) = self.mask_pair(i);
+                acc | ctrl | flip
+            }),
+            ops::MEVERYZ => u32::MAX,
+            op_id if ops::is_multi_controlled(op_id) => self.data[0] | (1 << self.q1),
+            ops::PAULI_EXP => self.data[0] | self.data[1],
+            _ => 1 << self.q1,
+        }
+    }
+
+    /// The qubits this op acts on, in increasing order. Unlike qubit_mask this also works for qubits
+    /// past 31, which ops naming their qubits by index can use. MEVERYZ returns no qubits.
+    pub fn qubits(&self) -> Vec<u32> {
+        let mask_qubits = |mask: u32| (0..32).filter(move |q| mask & (1 << q) != 0);
+        let mut qubits: Vec<u32> = match self.op_id {
+            op_id if ops::is_two_qubit(op_id) => vec![self.q1, self.q2],
+            ops::CCX => vec![self.q1, self.q2, self.q3],
+            ops::DIAGONAL | ops::PERMUTATION | ops::PAULI_EXP => mask_qubits(self.qubit_mask()).collect(),
+            ops::MEVERYZ => Vec::new(),
+            op_id if ops::is_multi_controlled(op_id) => mask_qubits(self.data[0]).chain([self.q1]).collect(),
+            _ => vec![self.q1],
+        };
+        qubits.sort_unstable();
+        qubits
+    }
+
+    /// Returns a copy of the op with each qubit it acts on replaced by `map(qubit)`.
+    pub fn map_qubits(&self, map: impl Fn(u32) -> u32) -> Op {
+        let map_mask = |mask: u32| (0..32).filter(|q| mask & (1 << q) != 0).fold(0, |acc, q| acc | (1 << map(q)));
+        let mut op = *self;
+        match self.op_id {
+            ops::MEVERYZ => {}
+            ops::DIAGONAL => {
+                for i in 0..self.term_count() {
+                    op.data[1 + 2 * i] = map_mask(self.data[1 + 2 * i]);
+                }
+            }
+            ops::PERMUTATION => {
+                for i in 0..2 * self.term_count() {
+                    op.data[1 + i] = map_mask(self.data[1 + i]);
+                }
+            }
+            op_id if ops::is_two_qubit(op_id) => {
+                op.q1 = map(self.q1);
+                op.q2 = map(self.q2);
+            }
+            ops::CCX => {
+                op.q1 = map(self.q1);
+                op.q2 = map(self.q2);
+                op.q3 = map(self.q3);
+            }
+            op_id if ops::is_multi_controlled(op_id) => {
+                op.q1 = map(self.q1);
+                op.data[0] = map_mask(self.data[0]);
+                op.data[1] = map_mask(self.data[1]);
+            }
+            ops::PAULI_EXP => {
+                op.data[0] = map_mask(self.data[0]);
+                op.data[1] = map_mask(self.data[1]);
+            }
+            _ => op.q1 = map(self.q1),
+        }
+        op
+    }
+}
+
+// Layout matches a vec2f state vector entry in the shader
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Pod, Zeroable)]
+pub struct Complex32 {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex32 {
+    pub const ZERO: Complex32 = Complex32 { re: 0.0, im: 0.0 };
+    pub const ONE: Complex32 = Complex32 { re: 1.0, im: 0.0 };
+    pub const I: Complex32 = Complex32 { re: 0.0, im: 1.0 };
+
+    pub fn new(re: f32, im: f32) -> Self {
+        Complex32 { re, im }
+    }
+
+    /// e^(i * angle)
+    pub fn from_phase(angle: f32) -> Self {
+        Complex32 { re: angle.cos(), im: angle.sin() }
+    }
+
+    pub fn conj(self) -> Self {
+        Complex32 { re: self.re, im: -self.im }
+    }
+
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn abs(self) -> f32 {
+        self.norm_sqr().sqrt()
+    }
+
+    pub fn arg(self) -> f32 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        Complex32 { re: self.re * factor, im: self.im * factor }
+    }
+}
+
+impl std::ops::Add for Complex32 {
+    type Output = Complex32;
+    fn add(self, rhs: Complex32) -> Complex32 {
+        Complex32 { re: self.re + rhs.re, im: self.im + rhs.im }
+    }
+}
+
+impl std::ops::Sub for Complex32 {
+    type Output = Complex32;
+    fn sub(self, rhs: Complex32) -> Complex32 {
+        Complex32 { re: self.re - rhs.re, im: self.im - rhs.im }
+    }
+}
+
+impl std::ops::Mul for Complex32 {
+    type Output = Complex32;
+    fn mul(self, rhs: Complex32) -> Complex32 {
+        Complex32 {
+            re: self.re * rhs.re - self.im * rhs.im,
+            im: self.re * rhs.im + self.im * rhs.re,
+        }
+    }
+}
+
+impl std::ops::Neg for Complex32 {
+    type Output = Complex32;
+    fn neg(self) -> Complex32 {
+        Complex32 { re: -self.re, im: -self.im }
+    }
 }
 
 #[repr(C)]
@@ -49,3 +457,27 @@ pub struct Result {
     pub entry_idx: u32,
     pub probability: f32,
 }
+
+/// The outcome of one mid-circuit measurement or reset of a run, and its (unnormalized) probability
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+pub struct Measurement {
+    pub outcome: u32,
+    pub probability: f32,
+}
+
+/// Values for the current run, in a uniform buffer. 64 bit values are split into (low, high) words.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+pub struct RunParams {
+    /// Key and shot number for the random numbers (see rng::uniform)
+    pub seed: [u32; 2],
+    pub shot: [u32; 2],
+    /// The batch of shots being sampled: the random draw of the first (its index, unless sampling
+    /// after noise), and how many
+    pub sample_offset: u32,
+    pub sample_count: u32,
+    /// Basis states above the threshold are reported, keeping the likeliest up to the capacity
+    pub result_threshold: f32,
+    pub result_capacity: u32,
+}
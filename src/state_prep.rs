@@ -0,0 +1,171 @@
+#![allow(unused)]
+
+use std::fmt;
+
+use crate::circuit::Circuit;
+use crate::cpu_simulator::apply_op;
+use crate::initial_state::InitialState;
+use crate::shader_types::{ops, Complex32, Op};
+
+// Synthesizes gate-level circuits that prepare a given state from |0...0>, for when a state can't
+// simply be uploaded (see InitialState::Amplitudes), e.g. to export to hardware or to count gates.
+//
+// This is the uniformly controlled rotation construction of Möttönen et al., "Transformation of
+// quantum states using uniformly controlled rotations" (2004). Working up from qubit 0, each qubit
+// is disentangled from the ones above it by an RY (magnitudes) and an RZ (relative phases) whose
+// angles depend on the value of the qubits above. Run in reverse, these prepare the state from
+// |0...0>. A rotation uniformly controlled by k qubits is 2^k rotations interleaved with 2^k CX
+// gates, so a general n qubit state costs up to 2^(n+1) - 2 CX gates.
+
+/// Smallest 1 - |<target|prepared>|^2 prepare_state accepts by default, allowing for the f32 angles
+/// and simulation
+pub const DEFAULT_OVERLAP_TOLERANCE: f64 = 1e-4;
+
+// Rotations smaller than this are left out of the circuit
+const EPSILON: f64 = 1e-9;
+
+/// A circuit preparing a state and what it costs
+pub struct StatePreparation {
+    pub circuit: Circuit,
+    /// |<target|prepared>|^2, from simulating the circuit on the CPU
+    pub overlap: f64,
+    pub cx_count: usize,
+    pub rotation_count: usize,
+}
+
+impl fmt::Display for StatePreparation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Qubits:            {}", self.circuit.qubit_count)?;
+        writeln!(f, "Overlap:           {}", self.overlap)?;
+        writeln!(f, "CX gates:          {}", self.cx_count)?;
+        write!(f, "Rotations:         {}", self.rotation_count)
+    }
+}
+
+/// Synthesize a circuit of RY, RZ and CX gates taking |0...0> to the state with the given
+/// amplitudes (up to a global phase), with bit i of each index the value of qubit i. The circuit
+/// is simulated to check its overlap with the state is at least 1 - `tolerance`, which takes time
+/// exponential in the qubit count.
+pub fn prepare_state(amplitudes: &[Complex32], tolerance: f64) -> Result<StatePreparation, String> {
+    if amplitudes.len() < 2 || !amplitudes.len().is_power_of_two() {
+        return Err(format!("State vector has {} amplitudes, expected a power of two", amplitudes.len()));
+    }
+    let qubit_count = amplitudes.len().trailing_zeros();
+    InitialState::Amplitudes(amplitudes.to_vec()).check(qubit_count)?;
+
+    // (magnitude, phase) of the state left once the qubits below have been disentangled
+    let mut reduced: Vec<(f64, f64)> =
+        amplitudes.iter().map(|amp| (amp.abs() as f64, amp.arg() as f64)).collect();
+    let mut levels = Vec::new();
+    for target in 0..qubit_count {
+        let pairs = reduced.len() / 2;
+        let (mut ry_angles, mut rz_angles) = (vec![0.0; pairs], vec![0.0; pairs]);
+        let mut next = vec![(0.0, 0.0); pairs];
+        let mut used = vec![true; pairs];
+        for j in 0..pairs {
+            let ((r0, p0), (r1, p1)) = (reduced[2 * j], reduced[2 * j + 1]);
+            ry_angles[j] = 2.0 * r1.atan2(r0);
+            used[j] = r0.max(r1) >= EPSILON;
+            // The phase of a zero amplitude is arbitrary, so pick it to need no rotation
+            (rz_angles[j], next[j]) = if r1 < EPSILON {
+                (0.0, (r0, p0))
+            } else if r0 < EPSILON {
+                (0.0, (r1, p1))
+            } else {
+                (p1 - p0, (r0.hypot(r1), (p0 + p1) / 2.0))
+            };
+        }
+        fill_unused_angles(&mut ry_angles, &used);
+        fill_unused_angles(&mut rz_angles, &used);
+        levels.push((target, ry_angles, rz_angles));
+        reduced = next;
+    }
+
+    // Prepare the top qubit first, then each qubit below it controlled by the ones above
+    let mut ops_out: Vec<Op> = Vec::new();
+    for (target, ry_angles, rz_angles) in levels.iter().rev() {
+        uniformly_controlled_rotation(&mut ops_out, ops::RY, *target, ry_angles);
+        uniformly_controlled_rotation(&mut ops_out, ops::RZ, *target, rz_angles);
+    }
+    let circuit = Circuit { qubit_count: qubit_count as i32, ops: ops_out, logical_qubits: Vec::new(), initial_logical_qubits: Vec::new() };
+
+    let overlap = state_overlap(&circuit, amplitudes);
+    if overlap < 1.0 - tolerance {
+        return Err(format!("Prepared state has overlap {} with the target, expected at least {}", overlap, 1.0 - tolerance));
+    }
+    let cx_count = circuit.ops.iter().filter(|op| op.op_id == ops::CX).count();
+    let rotation_count = circuit.ops.len() - cx_count;
+    Ok(StatePreparation { circuit, overlap, cx_count, rotation_count })
+}
+
+// The angle for a value of the controls the state never has doesn't matter, so if the others
+// all agree, use it there too and save the rotation its controls (e.g. for a basis state)
+fn fill_unused_angles(angles: &mut [f64], used: &[bool]) {
+    let mut used_angles = angles.iter().zip(used).filter(|(_, used)| **used).map(|(&angle, _)| angle);
+    if let Some(first) = used_angles.next()
+        && used_angles.all(|angle| (angle - first).abs() < EPSILON)
+    {
+        angles.fill(first);
+    }
+}
+
+/// |<target|prepared>|^2 for the state the circuit prepares from |0...0>
+pub fn state_overlap(circuit: &Circuit, target: &[Complex32]) -> f64 {
+    let mut state = vec![Complex32::ZERO; 1 << circuit.qubit_count];
+    state[0] = Complex32::ONE;
+    for op in &circuit.ops {
+        apply_op(&mut state, op);
+    }
+    let (re, im) = target.iter().zip(&state).fold((0.0, 0.0), |(re, im), (t, s)| {
+        let product = t.conj() * *s;
+        (re + product.re as f64, im + product.im as f64)
+    });
+    re * re + im * im
+}
+
+// Append a rotation of `target` by angles[j] when the qubits above it (target + 1 upwards) hold j.
+// Up to a global phase for RZ, which is diag(1, e^(i * angle)) rather than diag(e^(-i * angle / 2),
+// e^(i * angle / 2)), but since each rotation is applied unconditionally the extra phases don't
+// depend on the controls.
+fn uniformly_controlled_rotation(ops_out: &mut Vec<Op>, op_id: u32, target: u32, angles: &[f64]) {
+    // With the rotations in Gray code order of the controls, each CX flips the sign of the angles
+    // for the controls whose bit it changes, so rotation i is the Walsh-Hadamard transform of the
+    // angles at gray(i), over 2^k
+    let mut transformed = angles.to_vec();
+    let mut half = 1;
+    while half < transformed.len() {
+        for start in (0..transformed.len()).step_by(2 * half) {
+            for i in start..start + half {
+                let (a, b) = (transformed[i], transformed[i + half]);
+                (transformed[i], transformed[i + half]) = (a + b, a - b);
+            }
+        }
+        half *= 2;
+    }
+    if transformed.iter().all(|angle| angle.abs() < EPSILON * angles.len() as f64) {
+        return;
+    }
+
+    let control_count = angles.len().trailing_zeros();
+    for i in 0..angles.len() {
+        let angle = transformed[i ^ (i >> 1)] / angles.len() as f64;
+        if angle.abs() >= EPSILON {
+            ops_out.push(Op::new(op_id, target, 0, 0, angle as f32));
+        }
+        if control_count > 0 {
+            // gray(i) and gray(i + 1) differ in the lowest set bit of i + 1, wrapping round to the
+            // top bit at the end
+            let bit = if i + 1 == angles.len() { control_count - 1 } else { (i + 1).trailing_zeros() };
+            let control = target + 1 + bit;
+            // CXs onto the same target commute, so one matching this in the run of them since the
+            // last rotation (e.g. when rotations were skipped) cancels it
+            let run = ops_out.iter().rev().take_while(|op| op.op_id == ops::CX && op.q2 == target).count();
+            match ops_out[ops_out.len() - run..].iter().position(|op| op.q1 == control) {
+                Some(offset) => {
+                    ops_out.remove(ops_out.len() - run + offset);
+                }
+                None => ops_out.push(Op::new(ops::CX, control, target, 0, 0.0)),
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+#![allow(unused)]
+
+use std::collections::BTreeMap;
+use std::f32::consts::FRAC_PI_4;
+use std::fmt;
+
+use crate::circuit::Circuit;
+use crate::gpu_context::{SAMPLE_BATCH, TERM_BATCH};
+use crate::run_config::ResultSelection;
+use crate::schedule::schedule;
+use crate::shader_types::{ops, Measurement, Op, Result, MAX_QUBITS_PER_THREAD};
+use crate::cpu_simulator::matrix_1q;
+use crate::transpile::{decompose_2q, decompose_pauli_exp, zyz_angles};
+
+/// Precision each arbitrary rotation is synthesized to when estimating T counts
+pub const DEFAULT_ROTATION_PRECISION: f64 = 1e-6;
+
+// Angles within this of a multiple of pi/4 are treated as exact Clifford+T rotations
+const ANGLE_TOLERANCE: f32 = 1e-5;
+
+/// Summary of the gates and resources a circuit needs.
+///
+/// T counts are logical estimates: exact for T, CCX and rotations by multiples of pi/4, and for
+/// other rotations the expected T count of synthesizing them to `rotation_precision`.
+pub struct CircuitStats {
+    pub qubit_count: u32,
+    /// Number of each kind of op, by op id. The implicit MEVERYZ op isn't counted.
+    pub gate_counts: BTreeMap<u32, usize>,
+    pub total_gates: usize,
+    pub depth: usize,
+    pub two_qubit_depth: usize,
+    /// Rotations whose angle isn't a multiple of pi/4, so need approximate synthesis
+    pub arbitrary_rotations: usize,
+    pub rotation_precision: f64,
+    pub t_count: usize,
+    pub t_depth: usize,
+    /// Explicit measurements, or one per qubit if the circuit only has the implicit final MEVERYZ
+    pub measurement_count: usize,
+    /// Bytes for the state vector buffer, and for all buffers the GPU backend allocates
+    pub state_vector_bytes: u64,
+    pub gpu_memory_bytes: u64,
+}
+
+impl Circuit {
+    pub fn stats(&self) -> CircuitStats {
+        self.stats_with_precision(DEFAULT_ROTATION_PRECISION)
+    }
+
+    pub fn stats_with_precision(&self, rotation_precision: f64) -> CircuitStats {
+        let sched = schedule(self);
+        let qubit_count = self.qubit_count as u32;
+
+        let mut gate_counts = BTreeMap::new();
+        let mut arbitrary_rotations = 0;
+        let mut t_count = 0;
+        let mut measurement_count = 0;
+        let mut has_final_measurement = false;
+        // T depth so far on each qubit
+        let mut t_depths = vec![0usize; qubit_count as usize];
+
+        for op in &self.ops {
+            if op.op_id == ops::MEVERYZ {
+                has_final_measurement = true;
+                continue;
+            }
+            *gate_counts.entry(op.op_id).or_insert(0) += 1;
+            if ops::is_measurement(op.op_id) {
+                measurement_count += 1;
+            }
+
+            let cost = t_cost(op, rotation_precision);
+            arbitrary_rotations += cost.arbitrary_rotations;
+            t_count += cost.count;
+            if cost.depth > 0 {
+                let qubits = op.qubits();
+                let depth = qubits.iter().map(|&q| t_depths[q as usize]).max().unwrap_or(0) + cost.depth;
+                qubits.iter().for_each(|&q| t_depths[q as usize] = depth);
+            }
+        }
+        if measurement_count == 0 && has_final_measurement {
+            measurement_count = qubit_count as usize;
+        }
+
+        // Mirrors the buffers created in GpuContext::create_resources. Sizes saturate at u64::MAX
+        // for circuits far too large to run.
+        let entry_count = 1u64.checked_shl(qubit_count).unwrap_or(u64::MAX);
+        let state_vector_bytes = entry_count.saturating_mul(std::mem::size_of::<[f32; 2]>() as u64);
+        let needs_scratch = self.ops.iter().any(|op| op.op_id == ops::PERMUTATION);
+        let scratch_bytes = if needs_scratch { state_vector_bytes } else { std::mem::size_of::<[f32; 2]>() as u64 };
+        let ops_bytes = 2 * (self.ops.len() * std::mem::size_of::<Op>()) as u64; // Upload and private copies
+        // Results and download, sized for the default selection
+        let results_bytes = 2 * (ResultSelection::default().capacity() * std::mem::size_of::<Result>()) as u64;
+        // One probability sum per thread, each thread handling up to 2^MAX_QUBITS_PER_THREAD entries
+        let thread_count = entry_count.div_ceil(1 << MAX_QUBITS_PER_THREAD);
+        let partials_bytes = thread_count * std::mem::size_of::<[f32; 2]>() as u64;
+        let gpu_measurements = self.ops.iter().filter(|op| ops::is_stochastic(op.op_id)).count().max(1);
+        let measurements_bytes = 2 * (gpu_measurements * std::mem::size_of::<Measurement>()) as u64; // Buffer and download
+        let samples_bytes = (2 * SAMPLE_BATCH * std::mem::size_of::<u32>()) as u64; // Samples and download
+        let terms_bytes = (TERM_BATCH * std::mem::size_of::<Op>()) as u64;
+        let gpu_memory_bytes = [state_vector_bytes, scratch_bytes, ops_bytes, results_bytes, partials_bytes]
+            .into_iter()
+            .chain([measurements_bytes, samples_bytes, terms_bytes])
+            .fold(0, u64::saturating_add);
+
+        CircuitStats {
+            qubit_count,
+            total_gates: gate_counts.values().sum(),
+            gate_counts,
+            depth: sched.depth,
+            two_qubit_depth: sched.two_qubit_depth,
+            arbitrary_rotations,
+            rotation_precision,
+            t_count,
+            t_depth: t_depths.iter().copied().max().unwrap_or(0),
+            measurement_count,
+            state_vector_bytes,
+            gpu_memory_bytes,
+        }
+    }
+}
+
+#[derive(Default)]
+struct TCost {
+    count: usize,
+    depth: usize,
+    arbitrary_rotations: usize,
+}
+
+impl std::ops::AddAssign for TCost {
+    fn add_assign(&mut self, other: TCost) {
+        self.count += other.count;
+        self.depth += other.depth;
+        self.arbitrary_rotations += other.arbitrary_rotations;
+    }
+}
+
+/// Expected T count of synthesizing a Z rotation (or a parity rotation, which is one Z rotation
+/// between CNOTs) to the given precision.
+///
+/// Multiples of pi/2 are Clifford and odd multiples of pi/4 need a single T. Anything else uses
+/// the leading term of Ross-Selinger gridsynth's T count, 3 * log2(1 / precision).
+pub fn rotation_t_count(angle: f32, precision: f64) -> usize {
+    rotation_cost(angle, precision).count
+}
+
+fn rotation_cost(angle: f32, precision: f64) -> TCost {
+    let eighths = angle / FRAC_PI_4;
+    if (eighths - eighths.round()).abs() < ANGLE_TOLERANCE {
+        let odd = (eighths.round() as i64).rem_euclid(2) == 1;
+        return TCost { count: usize::from(odd), depth: usize::from(odd), arbitrary_rotations: 0 };
+    }
+    arbitrary_rotation_cost(precision)
+}
+
+fn arbitrary_rotation_cost(precision: f64) -> TCost {
+    let count = (3.0 * (1.0 / precision).log2()).ceil().max(1.0) as usize;
+    TCost { count, depth: count, arbitrary_rotations: 1 }
+}
+
+// Standard 7 T, T depth 3 Toffoli
+const TOFFOLI_COST: TCost = TCost { count: 7, depth: 3, arbitrary_rotations: 0 };
+
+fn t_cost(op: &Op, precision: f64) -> TCost {
+    match op.op_id {
+        ops::T | ops::T_ADJ => TCost { count: 1, depth: 1, arbitrary_rotations: 0 },
+        ops::RX | ops::RY | ops::RZ | ops::RZZ => rotation_cost(op.angle, precision),
+        ops::CCX => TOFFOLI_COST,
+        // Each term is a parity rotation. Terms are counted as if applied in sequence.
+        ops::DIAGONAL => {
+            let mut cost = TCost::default();
+            for t in 0..op.term_count() {
+                cost += rotation_cost(op.term(t).1, precision);
+            }
+            cost
+        }
+        ops::PERMUTATION => {
+            let mut cost = TCost::default();
+            for t in 0..op.term_count() {
+                cost += toffoli_ladder_cost(op.mask_pair(t).0.count_ones() as usize);
+            }
+            cost
+        }
+        ops::MCX | ops::MCZ => toffoli_ladder_cost(op.controls().0.count_ones() as usize),
+        // Compute the AND of the controls into an ancilla with c - 1 Toffolis (and uncompute it),
+        // then apply a singly controlled rotation, which takes two rotations
+        ops::MCPHASE | ops::MCRX | ops::MCRY | ops::MCRZ => {
+            let mut cost = TCost::default();
+            for _ in 0..2 * (op.controls().0.count_ones() as usize).saturating_sub(1) {
+                cost += TOFFOLI_COST;
+            }
+            cost += rotation_cost(op.angle / 2.0, precision);
+            cost += rotation_cost(op.angle / 2.0, precision);
+            cost
+        }
+        ops::U3 => {
+            let mut cost = TCost::default();
+            for idx in 0..3 {
+                cost += rotation_cost(op.angle_at(idx), precision);
+            }
+            cost
+        }
+        ops::MATRIX1Q => {
+            let (beta, gamma, delta) = zyz_angles(&matrix_1q(op).unwrap());
+            let mut cost = TCost::default();
+            for angle in [beta, gamma, delta] {
+                cost += rotation_cost(angle, precision);
+            }
+            cost
+        }
+        // A generic two qubit unitary takes 3 CX and 15 single qubit rotations (Vatan-Williams)
+        ops::MATRIX2Q => {
+            let mut cost = TCost::default();
+            for _ in 0..15 {
+                cost += arbitrary_rotation_cost(precision);
+            }
+            cost
+        }
+        // Costed by the gates they decompose into
+        _ => match decompose_2q(op).or_else(|| decompose_pauli_exp(op)) {
+            Some((gates, _)) => {
+                let mut cost = TCost::default();
+                for g in &gates {
+                    cost += t_cost(g, precision);
+                }
+                cost
+            }
+            None => TCost::default(),
+        },
+    }
+}
+
+// A flip (or Z) with c >= 2 controls takes 2c - 3 Toffolis, using c - 2 ancillas
+fn toffoli_ladder_cost(controls: usize) -> TCost {
+    let mut cost = TCost::default();
+    for _ in 0..(2 * controls).saturating_sub(3) {
+        cost += TOFFOLI_COST;
+    }
+    cost
+}
+
+impl fmt::Display for CircuitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Qubits:            {}", self.qubit_count)?;
+        writeln!(f, "Gates:             {}", self.total_gates)?;
+        for (&op_id, count) in &self.gate_counts {
+            writeln!(f, "  {:<16} {}", ops::name(op_id), count)?;
+        }
+        writeln!(f, "Depth:             {}", self.depth)?;
+        writeln!(f, "Two-qubit depth:   {}", self.two_qubit_depth)?;
+        writeln!(f, "Measurements:      {}", self.measurement_count)?;
+        writeln!(f, "T count:           {}", self.t_count)?;
+        writeln!(f, "T depth:           {}", self.t_depth)?;
+        writeln!(
+            f,
+            "  ({} arbitrary rotations at precision {:e})",
+            self.arbitrary_rotations, self.rotation_precision
+        )?;
+        writeln!(f, "State vector:      {}", format_bytes(self.state_vector_bytes))?;
+        write!(f, "GPU memory:        {}", format_bytes(self.gpu_memory_bytes))
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
@@ -1,6 +1,19 @@
-use crate::circuit::Circuit;
+use crate::circuit::{Circuit, DEFAULT_UNITARITY_TOLERANCE};
+use crate::cpu_simulator::{circuit_unitary, CpuSimulator};
 use crate::gpu_context::GpuContext;
-use crate::shader_types::{ops::RX, Result};
+use crate::hamiltonian::{PauliSum, PauliTerm};
+use crate::initial_state::InitialState;
+use crate::lattice::{Boundary, Interaction, Lattice, SpinModel};
+use crate::noise::{Channel, NoiseModel};
+use crate::passes;
+use crate::rng;
+use crate::run_config::{ResultSelection, RunConfig};
+use crate::sampling::{parity_expectation, Counts, Readout};
+use crate::schedule::schedule;
+use crate::shader_types::{ops, ops::RX, Complex32, Op, Result};
+use crate::state_prep::{prepare_state, state_overlap, DEFAULT_OVERLAP_TOLERANCE};
+use crate::stats::{rotation_t_count, DEFAULT_ROTATION_PRECISION};
+use crate::transpile::{check_equivalence, gate_sets, transpile};
 
 fn f32_close(a: f32, b: f32) -> bool {
     let epsilon =1e-6; // Ensure a reasonable minimum epsilon
@@ -29,7 +42,7 @@ fn run_bell() {
         gpu_context.run().await
     });
 
-    assert!(results.len() == 100, "Expected 100 results from the Bell circuit run");
+    assert!(results.len() == 2, "Expected 2 results from the Bell circuit run");
 
     let first = &results[0];
     let second = &results[1];
@@ -87,5 +100,1524 @@ fn qir_hidden_shift() {
         gpu_context.run().await
     });
 
-    assert_eq!(results.len(), 100, "Expected 100 results from the QIR circuit run");
-}
\ No newline at end of file
+    // The hidden shift is found with certainty
+    assert_eq!(results.len(), 1, "Expected a single result from the QIR circuit run");
+    assert_eq!(results[0].entry_idx, 33);
+    assert!(f32_close(results[0].probability, 1.0));
+}
+
+fn run_sorted(circ: Circuit) -> Vec<Result> {
+    let mut results = futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(circ).await;
+        gpu_context.create_resources();
+        gpu_context.run().await
+    });
+    results.retain(|r| r.probability > 0.0);
+    results.sort_by_key(|r| r.entry_idx);
+    results
+}
+
+fn assert_results_close(a: &[Result], b: &[Result]) {
+    assert_eq!(a.len(), b.len(), "Result counts differ");
+    for (ra, rb) in a.iter().zip(b) {
+        assert_eq!(ra.entry_idx, rb.entry_idx);
+        assert!((ra.probability - rb.probability).abs() < 1e-4, "Probabilities differ for entry {}", ra.entry_idx);
+    }
+}
+
+#[test]
+fn batch_diagonal_ising() {
+    let circ = Circuit::from_str(include_str!("ising5x5.crc")).unwrap();
+    let batched = passes::batch_diagonal_ops(&circ);
+
+    // Each layer of 20 RZZ gates becomes one DIAGONAL op, with the 250 RX and 25 MZ ops untouched
+    assert!(batched.ops.iter().all(|op| op.op_id != ops::RZZ));
+    assert_eq!(batched.ops.iter().filter(|op| op.op_id == ops::DIAGONAL).count(), 10);
+    assert_eq!(batched.ops.len(), 250 + 10 + 25 + 1);
+}
+
+#[test]
+fn batch_diagonal_matches_unbatched() {
+    let src = "h 0\nh 1\nh 2\nrx (0.3) 3\nrzz (0.7) 0 1\nrz (0.4) 2\ncz 1 2\nrx (1.1) 0\nrzz (-0.2) 2 3\nrz (0.9) 1\nh 0\nh 1\nh 2\n";
+    let circ = Circuit::from_str(src).unwrap();
+    let batched = passes::batch_diagonal_ops(&circ);
+
+    // The gates after the rx on qubit 0 don't touch it, so all diagonal gates join one layer
+    assert_eq!(batched.ops.len(), 10);
+    assert_eq!(batched.ops[4].op_id, ops::DIAGONAL);
+    assert_eq!(batched.ops[4].term_count(), 5);
+    assert_eq!(batched.ops[5].op_id, ops::RX);
+
+    assert_results_close(&run_sorted(circ), &run_sorted(batched));
+}
+
+#[test]
+fn rzz_matches_cx_rz_cx() {
+    // The trailing rz makes the result sensitive to the sign of the rzz angle
+    let rzz = Circuit::from_str("h 0\nh 1\nrzz (0.9) 0 1\nrz (0.6) 0\nh 0\nh 1\n").unwrap();
+    let ladder = Circuit::from_str("h 0\nh 1\ncx 0 1\nrz (0.9) 1\ncx 0 1\nrz (0.6) 0\nh 0\nh 1\n").unwrap();
+
+    assert_results_close(&run_sorted(rzz), &run_sorted(ladder));
+}
+
+fn run_cpu(circ: Circuit) -> Vec<Result> {
+    let mut results = CpuSimulator::new(circ).run();
+    results.sort_by_key(|r| r.entry_idx);
+    results
+}
+
+#[test]
+fn cpu_matches_gpu() {
+    let src = "h 0\nsx 1\nrx (0.4) 2\ncx 0 3\nrzz (0.8) 1 2\nh 3\ncz 0 2\nrz (1.3) 1\nx 2\nh 1\n";
+    let cpu = run_cpu(Circuit::from_str(src).unwrap());
+    let gpu = run_sorted(Circuit::from_str(src).unwrap());
+    assert_results_close(&cpu, &gpu);
+}
+
+#[test]
+fn cpu_matches_gpu_each_1q_gate() {
+    let gates = [
+        "x", "y", "z", "h", "s", "sdag", "t", "tdag", "sx", "sxadj", "rx (0.7)", "ry (-1.3)", "rz (2.1)",
+        "u3 (0.1, 0.2, 0.3)", "unitary [0, 1; 1, 0]",
+    ];
+    for g in gates {
+        // Different states on the two qubits the gate acts on, so every matrix entry matters
+        let src = format!("h 0\nry (0.4) 1\nrz (0.9) 1\n{g} 0\n{g} 1\nh 0\nid 2\n");
+        let mut sim = CpuSimulator::new(Circuit::from_str(&src).unwrap());
+        sim.run();
+        let gpu = futures::executor::block_on(async {
+            let mut gpu_context = GpuContext::new(Circuit::from_str(&src).unwrap()).await;
+            gpu_context.create_resources();
+            gpu_context.run().await;
+            gpu_context.state_vector().await
+        });
+        assert_states_close(sim.state(), &gpu);
+    }
+}
+
+#[test]
+fn batch_permutation_matches_unbatched() {
+    // An 11 qubit circuit so the GPU work is split over multiple threads
+    let src = "h 0\nh 1\nh 10\nx 2\nx 2\ncx 0 3\nccx 0 1 4\ncx 4 5\nh 6\nccx 3 10 7\ncx 7 8\nx 9\n";
+    let circ = Circuit::from_str(src).unwrap();
+    let batched = passes::batch_permutation_ops(&circ);
+
+    // The h on qubit 6 doesn't block the following gates, and the two x on qubit 2 cancel
+    let perms: Vec<_> = batched.ops.iter().filter(|op| op.op_id == ops::PERMUTATION).collect();
+    assert_eq!(perms.len(), 1);
+    assert_eq!(perms[0].term_count(), 6);
+    assert_eq!(batched.ops.len(), 6);
+
+    let expected = run_cpu(Circuit::from_str(src).unwrap());
+    assert_eq!(expected.len(), 16);
+    assert_results_close(&expected, &run_cpu(passes::batch_permutation_ops(&circ)));
+    assert_results_close(&expected, &run_sorted(batched));
+}
+
+fn assert_transpiles(src: &str, native: &[u32]) -> Circuit {
+    let circ = Circuit::from_str(src).unwrap();
+    let out = transpile(&circ, native).unwrap_or_else(|e| panic!("Failed to transpile '{}': {}", src, e));
+    assert!(
+        out.ops.iter().all(|op| native.contains(&op.op_id) || op.op_id == ops::MZ || op.op_id == ops::MEVERYZ),
+        "Non-native op in transpiled '{}'", src
+    );
+    assert!(check_equivalence(&circ, &out), "Transpiled '{}' is not equivalent", src);
+    out
+}
+
+#[test]
+fn transpile_each_gate() {
+    let gates = [
+        "x 0", "y 1", "z 0", "h 1", "s 0", "sdag 1", "t 0", "tdag 1", "sx 0", "sxadj 1",
+        "rx (0.7) 0", "ry (-1.3) 1", "rz (2.1) 0", "cx 0 1", "cx 1 0", "cz 0 1", "rzz (0.9) 0 1",
+        "swap 0 1", "ccx 0 1 2", "ccx 2 0 1", "iswap 0 1", "cy 1 0", "ch 0 1", "crx (0.4) 0 1",
+        "cry (0.5) 1 0", "crz (0.6) 0 1", "cp (0.7) 1 0", "rxx (0.8) 0 1", "ryy (0.9) 1 0",
+        "u3 (0.1, 0.2, 0.3) 0", "mcrx (0.4) !2 1", "pauli_exp (0.3) XZY 0 1 2", "pauli_exp (0.4) ZZ 2 0",
+    ];
+    for native in [gate_sets::SX_RZ_CZ, gate_sets::RX_RZ_RZZ, gate_sets::H_RZ_CX] {
+        for g in gates {
+            // Start from a non-trivial state on each qubit so phases matter
+            assert_transpiles(&format!("ry (0.3) 0\nry (0.5) 1\nry (0.9) 2\n{}\nmz 0\n", g), native);
+        }
+    }
+
+    // Several controls, including permutation steps with more than two
+    let gates = [
+        "mcx 0 !1 2 3", "mcz 3 0 1 2", "mcphase (0.7) !0 1 2 3", "mcrx (0.4) 0 1 !2 3", "mcry (-1.1) 3 1 0",
+        "mcrz (0.6) 1 2 3 0", "x 0\nx 1\nx 2\nmcx 0 1 2 3",
+    ];
+    for native in [gate_sets::SX_RZ_CZ, gate_sets::H_RZ_CX] {
+        for g in gates {
+            let src = format!("ry (0.3) 0\nry (0.5) 1\nry (0.9) 2\nry (1.3) 3\n{}\nmz 0\n", g);
+            assert_transpiles(&src, native);
+            let batched = passes::batch_permutation_ops(&Circuit::from_str(&src).unwrap());
+            assert!(check_equivalence(&batched, &transpile(&batched, native).unwrap()), "Transpiled '{}'", g);
+        }
+    }
+}
+
+#[test]
+fn transpile_batched_ops() {
+    let src = "h 0\nrx (0.4) 1\nx 2\ncx 0 3\nccx 0 1 2\nrzz (0.8) 1 2\nt 3\ncz 0 2\nrz (1.3) 1\nsx 2\ny 3\n";
+    let circ = Circuit::from_str(src).unwrap();
+    let batched = passes::batch_permutation_ops(&passes::batch_diagonal_ops(&circ));
+    let out = transpile(&batched, gate_sets::SX_RZ_CZ).unwrap();
+    assert!(check_equivalence(&circ, &out));
+}
+
+#[test]
+fn transpile_tidies_single_qubit_runs() {
+    // Three rotations about X merge into one, and H H cancels
+    let out = assert_transpiles("rx (0.2) 0\nrx (0.3) 0\nh 1\nh 1\nrx (0.5) 0\nrzz (0.4) 0 1\n", gate_sets::RX_RZZ);
+    assert_eq!(out.ops.len(), 3);
+    assert!(f32_close(out.ops[0].angle, 1.0));
+
+    let a = Circuit::from_str("h 0\nrz (0.3) 0\n").unwrap();
+    let b = Circuit::from_str("h 0\nrz (0.4) 0\n").unwrap();
+    assert!(!check_equivalence(&a, &b));
+
+    // An RY can't be built from RX alone, nor can the rest of a circuit needing a Z rotation
+    for src in ["ry (0.3) 0\n", "h 0\n", "cx 0 1\n", "rz (0.3) 0\n", "ccx 0 1 2\n"] {
+        let err = transpile(&Circuit::from_str(src).unwrap(), gate_sets::RX_RZZ).err().unwrap();
+        assert!(err.contains("Cannot express a single qubit unitary"), "{}: {}", src, err);
+    }
+    assert_transpiles("x 0\nsx 1\nrzz (0.4) 0 1\nsx 0\n", gate_sets::RX_RZZ);
+}
+
+#[test]
+fn transpile_clifford_t() {
+    let out = assert_transpiles("h 0\nh 1\nccx 0 1 2\ns 2\n", gate_sets::CLIFFORD_T);
+    let t_count = out.ops.iter().filter(|op| matches!(op.op_id, ops::T | ops::T_ADJ)).count();
+    assert_eq!(t_count, 7);
+
+    let circ = Circuit::from_str("rz (0.3) 0\n").unwrap();
+    assert!(transpile(&circ, gate_sets::CLIFFORD_T).is_err());
+}
+
+#[test]
+fn compact_idle_qubits() {
+    let src = "h 1\nid 2\nx 5\ncx 1 7\nrzz (0.5) 5 7\nh 5\n";
+    let circ = Circuit::from_str(src).unwrap();
+    assert_eq!(circ.qubit_count, 8);
+
+    let compacted = passes::compact_qubits(&circ);
+    assert_eq!(compacted.qubit_count, 3);
+    assert_eq!(compacted.logical_qubits, vec![1, 5, 7]);
+    assert_eq!(compacted.ops.len(), 6);
+    assert_eq!((compacted.ops[2].q1, compacted.ops[2].q2), (0, 2));
+
+    // Results are reported over the original qubit labels
+    let expected = run_cpu(Circuit::from_str(src).unwrap());
+    assert_eq!(expected.len(), 4);
+    assert_results_close(&expected, &run_cpu(passes::compact_qubits(&circ)));
+    assert_results_close(&expected, &run_sorted(compacted));
+}
+
+#[test]
+fn compact_to_one_qubit() {
+    // The GPU runs circuits compacted down to a single qubit
+    let src = "x 5\nh 5\nrz (0.3) 5\n";
+    let compacted = passes::compact_qubits(&Circuit::from_str(src).unwrap());
+    assert_eq!(compacted.qubit_count, 1);
+    assert_results_close(&run_cpu(Circuit::from_str(src).unwrap()), &run_sorted(compacted));
+}
+
+#[test]
+fn compact_qir_declared_qubits() {
+    // Declares 20 qubits, but only uses 6
+    let qir = include_str!("hidden_shift.qir").replace("\"required_num_qubits\"=\"6\"", "\"required_num_qubits\"=\"20\"");
+    let circ = Circuit::from_qir_str(&qir).expect("Failed to parse QIR");
+    assert_eq!(circ.qubit_count, 20);
+    let compacted = passes::compact_qubits(&circ);
+    assert_eq!(compacted.qubit_count, 6);
+    assert_eq!(compacted.logical_qubits, (0..6).collect::<Vec<u32>>());
+
+    // Past the 32 qubit masks
+    let qir = include_str!("hidden_shift.qir").replace("\"required_num_qubits\"=\"6\"", "\"required_num_qubits\"=\"40\"");
+    let circ = Circuit::from_qir_str(&qir).expect("Failed to parse QIR");
+    assert_eq!(circ.qubit_count, 40);
+    assert_eq!(passes::compact_qubits(&circ).qubit_count, 6);
+
+    let circ = Circuit::from_str("h 0\ncx 0 39\nrz (0.5) 35\n").unwrap();
+    let compacted = passes::compact_qubits(&circ);
+    assert_eq!(compacted.logical_qubits, vec![0, 35, 39]);
+    assert_eq!((compacted.ops[1].q1, compacted.ops[1].q2), (0, 2));
+    assert_eq!(compacted.ops[2].q1, 1);
+}
+
+#[test]
+fn reorder_static_layout() {
+    let src = "h 9\nrx (0.3) 9\ncx 9 2\nrz (0.7) 9\nh 2\nrzz (0.4) 2 9\nx 0\n";
+    let circ = Circuit::from_str(src).unwrap();
+    let reordered = passes::reorder_qubits(&circ, 0);
+
+    assert_eq!(&reordered.logical_qubits[..3], &[9, 2, 0]);
+    assert!(reordered.ops.iter().all(|op| op.op_id != ops::SWAP));
+
+    let expected = run_cpu(Circuit::from_str(src).unwrap());
+    assert_results_close(&expected, &run_cpu(passes::reorder_qubits(&circ, 0)));
+    assert_results_close(&expected, &run_sorted(reordered));
+}
+
+#[test]
+fn reorder_with_swaps() {
+    // Qubit 10 is busy in the first half, and qubit 7 in the second
+    let mut src = String::from("h 0\nh 7\nh 10\n");
+    for i in 0..6 {
+        src += &format!("rx (0.{}) 10\nrz (0.3) 10\n", i + 1);
+    }
+    src += "cx 10 7\n";
+    for i in 0..6 {
+        src += &format!("rx (0.{}) 7\nrz (0.2) 7\n", i + 1);
+    }
+    let circ = Circuit::from_str(&src).unwrap();
+    let reordered = passes::reorder_qubits(&circ, 15);
+
+    assert_eq!(reordered.logical_qubits[0], 7);
+    assert_eq!(reordered.ops.iter().filter(|op| op.op_id == ops::SWAP).count(), 1);
+
+    let expected = run_cpu(Circuit::from_str(&src).unwrap());
+    assert_results_close(&expected, &run_cpu(passes::reorder_qubits(&circ, 15)));
+
+    // Initial states are given in the initial layout, and state vectors read back in the final one
+    assert_ne!(reordered.initial_layout(), reordered.layout());
+    let labels: Vec<u32> = (0..circ.qubit_count as u32).collect();
+    let start = InitialState::Basis(0b100_1000_0001);
+    let mut plain = CpuSimulator::new(Circuit::from_str(&src).unwrap());
+    plain.set_initial_state(start.clone()).unwrap();
+    plain.run();
+    let mut sim = CpuSimulator::new(passes::reorder_qubits(&circ, 15));
+    sim.set_initial_state(start.relabel(&labels, &reordered.initial_layout()).unwrap()).unwrap();
+    sim.run();
+    let end = InitialState::Amplitudes(sim.state().to_vec()).relabel(&reordered.layout(), &labels).unwrap();
+    assert_states_close(plain.state(), &end.amplitudes(0..plain.state().len()));
+    assert_results_close(&expected, &run_sorted(reordered));
+}
+
+#[test]
+fn schedule_bell() {
+    let circ = Circuit::from_str("h 0\ncx 0 1\n").unwrap();
+    let sched = schedule(&circ);
+    assert_eq!(sched.moments, vec![vec![0], vec![1]]);
+    assert_eq!(sched.depth, 2);
+    assert_eq!(sched.two_qubit_depth, 1);
+    assert_eq!(sched.critical_path, vec![0, 1]);
+
+    // Qubits past the 32 qubit masks
+    let sched = schedule(&Circuit::from_str("h 0\nh 40\ncx 40 0\n").unwrap());
+    assert_eq!(sched.moments, vec![vec![0, 1], vec![2]]);
+}
+
+#[test]
+fn schedule_ising() {
+    let circ = Circuit::from_str(include_str!("ising5x5.crc")).unwrap();
+    let sched = schedule(&circ);
+    // An initial RX layer, then 5 steps of 4 RZZ layers and 2 RX layers, with the final MZ layer
+    // sharing a moment with the last RX ops
+    assert_eq!(sched.depth, 31);
+    assert_eq!(sched.two_qubit_depth, 20);
+    assert_eq!(sched.moments.iter().map(Vec::len).sum::<usize>(), circ.ops.len() - 1);
+
+    // Each op on the critical path is in the next moment and shares a qubit with the one before
+    assert_eq!(sched.critical_path.len(), sched.depth);
+    for (moment, pair) in sched.critical_path.windows(2).enumerate() {
+        assert!(sched.moments[moment + 1].contains(&pair[1]));
+        assert_ne!(circ.ops[pair[0]].qubit_mask() & circ.ops[pair[1]].qubit_mask(), 0);
+    }
+}
+
+#[test]
+fn stats_counts() {
+    let circ = Circuit::from_str("h 0\nt 1\ncx 0 1\nccx 0 1 2\nrz (0.3) 2\nrz (1.5707964) 0\nmz 2\n").unwrap();
+    let stats = circ.stats();
+    assert_eq!(stats.gate_counts[&ops::RZ], 2);
+    assert_eq!(stats.gate_counts[&ops::CCX], 1);
+    assert_eq!(stats.total_gates, 7);
+    assert_eq!(stats.depth, 5);
+    assert_eq!(stats.two_qubit_depth, 2);
+    assert_eq!(stats.measurement_count, 1);
+
+    // T, a Toffoli, and one arbitrary rotation (the pi/2 rotation is Clifford)
+    assert_eq!(stats.arbitrary_rotations, 1);
+    assert_eq!(stats.t_count, 1 + 7 + rotation_t_count(0.3, DEFAULT_ROTATION_PRECISION));
+    assert_eq!(stats.t_depth, 1 + 3 + rotation_t_count(0.3, DEFAULT_ROTATION_PRECISION));
+    assert_eq!(stats.state_vector_bytes, 64);
+
+    // Tighter precision needs more T gates per rotation
+    assert!(circ.stats_with_precision(1e-10).t_count > stats.t_count);
+    assert_eq!(rotation_t_count(-std::f32::consts::FRAC_PI_4, 1e-10), 1);
+
+    // Circuits too large to run still have stats
+    let stats = Circuit::from_str("h 0\nt 40\ncx 40 0\nt 70\n").unwrap().stats();
+    assert_eq!((stats.depth, stats.t_depth), (2, 1));
+    assert_eq!(stats.state_vector_bytes, u64::MAX);
+}
+
+#[test]
+fn stats_ising() {
+    let circ = Circuit::from_str(include_str!("ising5x5.crc")).unwrap();
+    let stats = circ.stats();
+    assert_eq!(stats.gate_counts[&ops::RZZ], 200);
+    assert_eq!(stats.measurement_count, 25);
+    assert_eq!(stats.state_vector_bytes, 8 << 25);
+    assert!(stats.gpu_memory_bytes > stats.state_vector_bytes);
+
+    // Batching changes the ops but not the logical cost
+    let batched = passes::batch_diagonal_ops(&circ).stats();
+    assert_eq!(batched.t_count, stats.t_count);
+    assert!(batched.to_string().contains("diagonal"));
+}
+
+fn assert_unitary_close(a: &[Vec<Complex32>], b: &[Vec<Complex32>]) {
+    for (col_a, col_b) in a.iter().zip(b) {
+        for (x, y) in col_a.iter().zip(col_b) {
+            assert!((*x - *y).abs() < 1e-4, "{:?} != {:?}", x, y);
+        }
+    }
+}
+
+#[test]
+fn inverse_undoes_circuit() {
+    let src = "h 0\ny 1\ns 0\nt 1\nsx 2\nrx (0.3) 0\nry (0.4) 1\nrz (0.5) 2\nrzz (0.6) 0 2\ncx 0 1\nccx 0 1 2\nswap 1 2\n\
+        iswap 0 2\ncy 1 0\nch 2 1\ncrx (0.1) 0 1\ncry (0.2) 1 2\ncrz (0.3) 2 0\ncp (0.4) 0 1\nrxx (0.5) 1 2\n\
+        ryy (0.6) 2 0\nu3 (0.7, 0.8, 0.9) 1\npauli_exp (0.4) YXZ 0 1 2\npauli_exp (0.5) ZIZ 0 1 2\n";
+    let circ = Circuit::from_str(src).unwrap();
+    let batched = passes::batch_permutation_ops(&passes::batch_diagonal_ops(&circ));
+    for circ in [circ, batched] {
+        let inv = circ.inverse().unwrap();
+        assert_eq!(inv.ops.last().unwrap().op_id, ops::MEVERYZ);
+        let mut round_trip = circ.ops[..circ.ops.len() - 1].to_vec();
+        round_trip.extend(inv.ops);
+        let identity = Circuit::from_str("id 0\nid 1\nid 2\n").unwrap();
+        assert_unitary_close(&circuit_unitary(&circ.with_ops(round_trip)), &circuit_unitary(&identity));
+    }
+
+    let err = Circuit::from_str("h 0\nmz 0\nx 0\n").unwrap().inverse().err().unwrap();
+    assert!(err.contains("mz"), "{}", err);
+}
+
+#[test]
+fn controlled_each_gate() {
+    let gates = [
+        "x 0", "y 0", "z 0", "h 0", "s 0", "s_adj 0", "t 0", "t_adj 0", "sx 0", "sx_adj 0",
+        "rx (0.7) 0", "ry (-1.1) 1", "rz (2.5) 0", "cx 0 1", "cz 1 0", "rzz (0.9) 0 1", "swap 0 1",
+        "ccx 0 1 2", "h 0\nrz (0.2) 1\ncx 1 2\n", "iswap 0 1", "cy 1 0", "ch 0 1", "crx (0.4) 0 1",
+        "cry (0.5) 1 0", "crz (0.6) 0 1", "cp (0.7) 1 0", "rxx (0.8) 0 1", "ryy (0.9) 1 0",
+        "u3 (0.1, 0.2, 0.3) 0", "pauli_exp (0.3) XZY 0 1 2", "pauli_exp (0.4) ZZ 0 2",
+    ];
+    for src in gates {
+        let circ = Circuit::from_str(&format!("{}\nid 2\n", src)).unwrap();
+        let controlled = circ.controlled(3).unwrap();
+        assert_eq!(controlled.qubit_count, 4);
+
+        // Identity on the first half of the basis states (control clear), the circuit on the rest
+        let body = circuit_unitary(&circ);
+        let size = body.len();
+        let expected: Vec<Vec<Complex32>> = (0..2 * size)
+            .map(|col| {
+                let mut column = vec![Complex32::ZERO; 2 * size];
+                if col < size {
+                    column[col] = Complex32::ONE;
+                } else {
+                    column[size..].copy_from_slice(&body[col - size]);
+                }
+                column
+            })
+            .collect();
+        assert_unitary_close(&circuit_unitary(&controlled), &expected);
+    }
+
+    let err = Circuit::from_str("cx 0 1\n").unwrap().controlled(1).err().unwrap();
+    assert!(err.contains("Control qubit 1"), "{}", err);
+    assert!(Circuit::from_str("x 0\nreset 0\n").unwrap().controlled(1).is_err());
+}
+
+#[test]
+fn parse_modifiers() {
+    let body = Circuit::from_str("h 0\nt 1\nrzz (0.4) 0 1\n").unwrap();
+    let expected = body.inverse().unwrap().controlled(2).unwrap();
+
+    let circ = Circuit::from_str("# comment\nctrl 2 inv {\n  h 0\n  t 1\n  rzz (0.4) 0 1\n}\n").unwrap();
+    assert_eq!(circ.qubit_count, 3);
+    assert_unitary_close(&circuit_unitary(&circ), &circuit_unitary(&expected));
+
+    // Single line modifiers and nested blocks give the same circuit
+    let nested = Circuit::from_str("ctrl 2 {\ninv rzz (0.4) 0 1\ninv {\nt 1\n}\ninv h 0\n}\n").unwrap();
+    assert_unitary_close(&circuit_unitary(&nested), &circuit_unitary(&expected));
+
+    assert!(Circuit::from_str("inv {\nmz 0\n}\n").err().unwrap().contains("Line 1"));
+    assert!(Circuit::from_str("inv {\nx 0\n").err().unwrap().contains("never closed"));
+    assert!(Circuit::from_str("x 0\n}\n").err().unwrap().contains("Line 2"));
+    assert!(Circuit::from_str("ctrl 1\n").is_err());
+
+    // Ops are controlled with their phase: RZ is diag(1, e^(i * angle)), so a controlled RZ is a
+    // controlled phase, not the textbook CRZ
+    let ctrl_rz = Circuit::from_str("ctrl 0 rz (0.9) 1\n").unwrap();
+    assert!(check_equivalence(&ctrl_rz, &Circuit::from_str("cp (0.9) 0 1\n").unwrap()));
+    assert!(!check_equivalence(&ctrl_rz, &Circuit::from_str("crz (0.9) 0 1\n").unwrap()));
+    let controlled = Circuit::from_str("rz (0.9) 1\n").unwrap().controlled(0).unwrap();
+    assert!(check_equivalence(&controlled, &Circuit::from_str("cp (0.9) 0 1\n").unwrap()));
+}
+
+#[test]
+fn controlled_runs_on_gpu() {
+    let src = "h 2\nx 1\nctrl 2 {\nh 0\nrx (0.7) 1\ncz 0 1\n}\nctrl 0 inv sx 1\n";
+    let gpu = run_sorted(Circuit::from_str(src).unwrap());
+    assert_results_close(&gpu, &run_cpu(Circuit::from_str(src).unwrap()));
+}
+
+#[test]
+fn multi_controlled_matches_decompositions() {
+    let cases = [
+        ("mcx 0 1 2", "ccx 0 1 2"),
+        ("mcx !0 1 2", "x 0\nccx 0 1 2\nx 0"),
+        ("mcz 0 !2 1", "x 2\nh 1\nccx 0 2 1\nh 1\nx 2"),
+        ("mcphase (0.3) 0 1\nid 2", "ctrl 0 rz (0.3) 1\nid 2"),
+        ("mcry (0.8) 2 0\nid 1", "ry (0.4) 0\ncx 2 0\nry (-0.4) 0\ncx 2 0\nid 1"),
+        ("mcrz (0.8) 1 2\nid 0", "rz (0.4) 2\ncx 1 2\nrz (-0.4) 2\ncx 1 2\nid 0"),
+        ("mcrx (-1.3) !1 0\nid 2", "x 1\nh 0\nmcrz (-1.3) 1 0\nh 0\nx 1\nid 2"),
+        ("ctrl 3 mcx 0 !1 2", "mcx 0 !1 3 2"),
+    ];
+    for (src, expected) in cases {
+        let circ = Circuit::from_str(src).unwrap();
+        let expected = circuit_unitary(&Circuit::from_str(expected).unwrap());
+        assert_unitary_close(&circuit_unitary(&circ), &expected);
+        let batched = passes::batch_permutation_ops(&circ);
+        assert_unitary_close(&circuit_unitary(&batched), &expected);
+    }
+
+    assert!(Circuit::from_str("mcx 0\n").is_err());
+    assert!(Circuit::from_str("mcx 0 0 1\n").is_err());
+    assert!(Circuit::from_str("mcrx 0 1\n").is_err());
+    // Controls past the 32 bit mask are rejected rather than overflowing the shift
+    assert!(Circuit::from_str("mcx 0 40 1\n").err().unwrap().contains("Line 1"));
+    assert!(Circuit::from_str("h 0\nmcz !32 0\n").err().unwrap().contains("Line 2"));
+}
+
+#[test]
+fn multi_controlled_gpu() {
+    // 12 qubits, so the state is split across threads
+    let src = "h 0\nh 1\nh 2\nh 3\nh 4\nx 6\nx 7\nx 8\nx 9\nx 10\n\
+        mcx 0 1 !5 6 7 8 9 10 11\nmcz 2 !5 11 3\nmcphase (0.7) 11 4\nmcrx (0.9) 0 !5 6 2\n\
+        mcry (1.1) 1 11 3\nmcrz (0.5) 2 4\nccx 3 4 11\n";
+    let gpu = run_sorted(Circuit::from_str(src).unwrap());
+    let cpu = run_cpu(Circuit::from_str(src).unwrap());
+    assert!(!cpu.is_empty());
+    assert_results_close(&gpu, &cpu);
+}
+
+#[test]
+fn extended_gate_matrices() {
+    let cases = [
+        ("u3 (0.3, 0.5, -0.7) 0", "rz (-0.7) 0\nry (0.3) 0\nrz (0.5) 0"),
+        ("u3(0.3,0.5,-0.7) 0", "rz (-0.7) 0\nry (0.3) 0\nrz (0.5) 0"),
+        ("crx (0.4) 1 0", "mcrx (0.4) 1 0"),
+        ("crz (0.4) 0 1", "mcrz (0.4) 0 1"),
+        ("cp (0.4) 0 1", "rz (0.2) 0\nrz (0.2) 1\nrzz (-0.2) 0 1"),
+        ("cy 0 1", "s_adj 1\ncx 0 1\ns 1"),
+        ("ch 1 0", "ry (-0.7853982) 0\ncz 1 0\nry (0.7853982) 0"),
+        ("rxx (0.6) 0 1", "h 0\nh 1\nrzz (0.6) 0 1\nh 0\nh 1"),
+        ("ryy (0.6) 0 1", "rx (1.5707964) 0\nrx (1.5707964) 1\nrzz (0.6) 0 1\nrx (-1.5707964) 0\nrx (-1.5707964) 1"),
+        ("iswap 0 1\niswap 0 1", "z 0\nz 1"),
+        ("rxx (3.1415927) 0 1", "x 0\nx 1"),
+    ];
+    for (src, expected) in cases {
+        let circ = Circuit::from_str(src).unwrap();
+        assert!(check_equivalence(&circ, &Circuit::from_str(expected).unwrap()), "{}", src);
+    }
+
+    assert!(Circuit::from_str("u3 (0.1, 0.2) 0\n").err().unwrap().contains("takes 3 angles"));
+    assert!(Circuit::from_str("cp 0 1\n").is_err());
+}
+
+#[test]
+fn extended_gates_gpu() {
+    let gates = [
+        "iswap 0 10", "cy 1 9", "ch 10 8", "crx (0.4) 2 7", "cry (0.5) 10 3", "crz (0.6) 3 0", "cp (0.7) 10 1",
+        "rxx (0.8) 4 10", "ryy (0.9) 2 5", "u3 (0.1, 0.2, 0.3) 6",
+    ];
+    for g in gates {
+        // Prepare some superposition over 11 qubits, then interfere after the gate so phases show up
+        let src = format!(
+            "h 0\nh 2\nx 10\nrx (0.6) 1\nrx (0.7) 9\nrx (0.8) 4\nrx (0.4) 6\nrx (1.1) 5\n{}\nh 10\nh 0\nh 2\nh 5\nh 4\nh 6\n",
+            g
+        );
+        let gpu = run_sorted(Circuit::from_str(&src).unwrap());
+        let cpu = run_cpu(Circuit::from_str(&src).unwrap());
+        assert!(!cpu.is_empty());
+        assert_results_close(&gpu, &cpu);
+    }
+}
+
+#[test]
+fn parse_qir_extended_gates() {
+    let qir = r#"
+define void @main() #0 {
+entry:
+    call void @__quantum__qis__h__body(%Qubit* inttoptr (i64 0 to %Qubit*))
+    call void @__quantum__qis__cphase__body(double 0.5, %Qubit* inttoptr (i64 0 to %Qubit*), %Qubit* inttoptr (i64 1 to %Qubit*))
+    call void @__quantum__qis__iswap__body(%Qubit* inttoptr (i64 1 to %Qubit*), %Qubit* inttoptr (i64 2 to %Qubit*))
+    call void @__quantum__qis__u3__body(double 0.1, double 0.2, double 0.3, %Qubit* inttoptr (i64 2 to %Qubit*))
+    ret void
+}
+
+attributes #0 = { "entry_point" "qir_profiles"="base_profile" "required_num_qubits"="3" "required_num_results"="0" }
+"#;
+    let circ = Circuit::from_qir_str(qir).unwrap();
+    let expected = Circuit::from_str("h 0\ncp (0.5) 0 1\niswap 1 2\nu3 (0.1, 0.2, 0.3) 2\n").unwrap();
+    assert_eq!(circ.ops.len(), expected.ops.len());
+    for (a, b) in circ.ops.iter().zip(&expected.ops) {
+        assert_eq!((a.op_id, a.q1, a.q2, a.angle, a.data), (b.op_id, b.q1, b.q2, b.angle, b.data));
+    }
+}
+
+#[test]
+fn matrix_ops_parse_and_check() {
+    let cases = [
+        ("unitary [0.70710678, 0.70710678; 0.70710678, -0.70710678] 0", "h 0"),
+        ("unitary [0.5+0.5i, 0.5-0.5i; 0.5-0.5i, 0.5+0.5i] 0", "sx 0"),
+        ("unitary [0, -i; 1i, 0] 1", "y 1"),
+        ("unitary [1, 0, 0, 0; 0, 0, 0, 1; 0, 0, 1, 0; 0, 1, 0, 0] 0 1", "cx 0 1"),
+        ("unitary [1, 0, 0, 0; 0, 0, 0, 1; 0, 0, 1, 0; 0, 1, 0, 0] 1 0", "cx 1 0"),
+        ("inv unitary [0.6, 0.8j; 0.8j, 0.6] 0\nunitary [0.6, 0.8j; 0.8j, 0.6] 0", "id 0"),
+        ("ctrl 1 unitary [0, -i; 1i, 0] 0", "cy 1 0"),
+    ];
+    for (src, expected) in cases {
+        let circ = Circuit::from_str(src).unwrap();
+        circ.check_unitarity(DEFAULT_UNITARITY_TOLERANCE).unwrap();
+        assert!(check_equivalence(&circ, &Circuit::from_str(expected).unwrap()), "{}", src);
+    }
+
+    let skewed = Circuit::from_str("unitary [1, 1; 0, 1] 0\n").unwrap();
+    assert!(skewed.check_unitarity(DEFAULT_UNITARITY_TOLERANCE).is_err());
+    assert!(Circuit::from_str("unitary [1, 0, 0] 0\n").err().unwrap().contains("2x2 or 4x4"));
+    assert!(Circuit::from_str("unitary [1, 0; 0, 1] 0 1\n").is_err());
+    assert!(Circuit::from_str("unitary [1, 0; 0, 1e-3-2x] 0\n").is_err());
+    assert!(Circuit::from_str("h [1, 0; 0, 1] 0\n").is_err());
+}
+
+#[test]
+fn matrix_ops_gpu() {
+    // Two qubit matrix from a small entangling circuit, applied to qubits in both orders
+    let u = circuit_unitary(&Circuit::from_str("u3 (0.3, 0.5, -0.7) 0\ncry (0.8) 0 1\nryy (0.4) 0 1\nt 1\n").unwrap());
+    let m: [Complex32; 16] = std::array::from_fn(|idx| u[idx % 4][idx / 4]);
+    for (q1, q2) in [(10, 3), (2, 9)] {
+        let prelude = "h 0\nh 2\nx 10\nrx (0.6) 1\nrx (0.7) 9\nrx (0.8) 3\n";
+        let mut circ = Circuit::from_str(prelude).unwrap();
+        // The final measurement comes back with the ops that follow
+        circ.ops.pop();
+        circ.ops.push(Op::with_matrix_2q(q1, q2, &m));
+        circ.ops.extend(Circuit::from_str("h 10\nh 3\nh 9\nh 2\n").unwrap().ops);
+        let expected = Circuit::from_str(&format!("{}u3 (0.3, 0.5, -0.7) {q1}\ncry (0.8) {q1} {q2}\nryy (0.4) {q1} {q2}\nt {q2}\nh 10\nh 3\nh 9\nh 2\n", prelude)).unwrap();
+        let cpu = run_cpu(expected);
+        assert!(!cpu.is_empty());
+        assert_results_close(&run_sorted(circ), &cpu);
+    }
+}
+
+#[test]
+fn fuse_1q_matrices_runs_unsupported_gates() {
+    // Y, S, T, RY and SX_ADJ have no kernel of their own in the shader, but run as fused matrices
+    let src = "h 0\ny 1\nry (0.4) 1\ns 0\ncx 0 10\nt 10\nsx_adj 10\nrx (0.3) 0\nh 10\ns_adj 1\nt_adj 0\nh 0\n";
+    let circ = Circuit::from_str(src).unwrap();
+    let fused = passes::fuse_1q_matrices(&circ);
+
+    // Runs before the CX, runs after it on 10 and 0, and the lone run on 1
+    let matrix_ops = fused.ops.iter().filter(|op| op.op_id == ops::MATRIX1Q).count();
+    assert_eq!(matrix_ops, 4);
+    assert_eq!(fused.ops.len(), 4 + 1 + 1);
+    assert_results_close(&run_sorted(fused), &run_cpu(circ));
+}
+
+#[test]
+fn pauli_exp_matrices() {
+    // e^(-i * angle * X) = RX(2 * angle) exactly, and the rest match up to global phase
+    let rx = circuit_unitary(&Circuit::from_str("pauli_exp (0.3) X 0\n").unwrap());
+    assert_unitary_close(&rx, &circuit_unitary(&Circuit::from_str("rx (0.6) 0\n").unwrap()));
+
+    let cases = [
+        ("pauli_exp (0.3) Y 0", "ry (0.6) 0"),
+        ("pauli_exp (0.3) ZZ 0 1", "rzz (0.6) 0 1"),
+        ("pauli_exp (0.3) XX 0 1", "rxx (0.6) 0 1"),
+        ("pauli_exp (0.3) yy 1 0", "ryy (0.6) 0 1"),
+        ("pauli_exp (0.3) XIZ 0 1 2", "h 0\ncx 0 2\nrz (0.6) 2\ncx 0 2\nh 0\nid 1"),
+        ("pauli_exp (0.3) ZYX 2 0 1", "s_adj 0\nh 0\nh 1\ncx 0 2\ncx 1 2\nrz (0.6) 2\ncx 1 2\ncx 0 2\nh 1\nh 0\ns 0"),
+        ("pauli_exp (0.3) II 0 1", "id 0\nid 1"),
+    ];
+    for (src, expected) in cases {
+        let circ = Circuit::from_str(src).unwrap();
+        assert!(check_equivalence(&circ, &Circuit::from_str(expected).unwrap()), "{}", src);
+    }
+
+    // Z strings are diagonal, so join DIAGONAL layers
+    let circ = Circuit::from_str("rz (0.1) 0\npauli_exp (0.3) ZZZ 0 1 2\nrz (0.2) 2\n").unwrap();
+    let batched = passes::batch_diagonal_ops(&circ);
+    assert_eq!(batched.ops.len(), 2);
+    assert!(check_equivalence(&circ, &batched));
+
+    assert!(Circuit::from_str("pauli_exp (0.3) XZ 0\n").err().unwrap().contains("2 letters"));
+    assert!(Circuit::from_str("pauli_exp (0.3) XZ 40 1\n").err().unwrap().contains("Line 1"));
+    assert!(Circuit::from_str("pauli_exp (0.3) XQ 0 1\n").is_err());
+    assert!(Circuit::from_str("pauli_exp (0.3) XZ 1 1\n").is_err());
+    assert!(Circuit::from_str("pauli_exp XZ 0 1\n").is_err());
+}
+
+#[test]
+fn pauli_exp_gpu() {
+    let strings = ["pauli_exp (0.4) XZZY 0 1 2 10", "pauli_exp (0.7) ZIZZ 10 4 0 2", "pauli_exp (-0.5) YY 9 3", "pauli_exp (1.1) X 6"];
+    for p in strings {
+        let src = format!(
+            "h 0\nh 2\nx 10\nrx (0.6) 1\nrx (0.7) 9\nrx (0.8) 4\nrx (0.4) 6\nrx (1.1) 3\n{}\nh 10\nh 0\nh 2\nh 3\nh 4\n",
+            p
+        );
+        let gpu = run_sorted(Circuit::from_str(&src).unwrap());
+        let cpu = run_cpu(Circuit::from_str(&src).unwrap());
+        assert!(!cpu.is_empty());
+        assert_results_close(&gpu, &cpu);
+    }
+}
+
+#[test]
+fn parse_pauli_sums() {
+    let text = PauliSum::from_str("# Transverse field Ising\n-1.0 Z0 Z1\n-0.5 X0   # field\n0.25 y3 X1\n2 I\n").unwrap();
+    let json = PauliSum::from_str("{\"Z0 Z1\": -1.0, \"X0\": -0.5, \"y3 X1\": 2.5e-1, \"I\": 2}").unwrap();
+    assert_eq!(text, json);
+    assert_eq!(text.qubit_count(), 4);
+    assert_eq!(text.terms[2], PauliTerm { coefficient: 0.25, x_mask: 0b1010, z_mask: 0b1000 });
+    assert_eq!(text.terms[2].label(), "X1 Y3");
+    assert!(text.terms[3].is_identity());
+
+    assert!(PauliSum::from_str("-1.0 Z0 Z0\n").is_err());
+    assert!(PauliSum::from_str("-1.0 Q0\n").is_err());
+    assert!(PauliSum::from_str("one Z0\n").err().unwrap().contains("Line 1"));
+    assert!(PauliSum::from_str("{\"Z0\": 1.0,}").is_err());
+    assert!(PauliSum::from_str("{\"Z0\": 1.0} x").is_err());
+}
+
+// Largest 2-norm of a column of a - b, which is at most the spectral norm of a - b
+fn column_distance(a: &[Vec<Complex32>], b: &[Vec<Complex32>]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(col_a, col_b)| col_a.iter().zip(col_b).map(|(x, y)| (*x - *y).norm_sqr() as f64).sum::<f64>().sqrt())
+        .fold(0.0, f64::max)
+}
+
+#[test]
+fn trotter_error_within_bound() {
+    let h = PauliSum::from_str("-1.0 Z0 Z1\n-1.0 Z1 Z2\n-1.0 Z2 Z3\n-0.7 X0\n-0.7 X1\n-0.7 X2\n-0.7 X3\n0.3 Y0 Y1\n").unwrap();
+    let reference = circuit_unitary(&h.trotter_circuit(0.8, 64, 4).unwrap().circuit);
+
+    let mut previous = f64::MAX;
+    for order in [1, 2, 4] {
+        let trotter = h.trotter_circuit(0.8, 4, order).unwrap();
+        let error = column_distance(&circuit_unitary(&trotter.circuit), &reference);
+        assert!(error <= trotter.error_bound + 1e-3, "Order {} error {} exceeds bound {}", order, error, trotter.error_bound);
+        assert!(error < previous, "Order {} isn't more accurate", order);
+        previous = error;
+    }
+
+    let first = h.trotter_circuit(0.8, 4, 1).unwrap();
+    assert_eq!(first.exponentials, 4 * 8);
+    assert_eq!(first.cx_count, 4 * 8);
+    assert!(first.to_string().contains("Exponentials:      32"));
+    assert!(h.trotter_circuit(0.8, 4, 3).is_err());
+    assert!(h.trotter_circuit(0.8, 0, 1).is_err());
+}
+
+#[test]
+fn trotter_commuting_terms() {
+    // Commuting terms have no Trotter error, and the halves of order 2 steps merge
+    let h = PauliSum::from_str("0.5 Z0 Z1\n0.3 Z1\n1.0 I\n").unwrap();
+    let first = h.trotter_circuit(1.0, 3, 1).unwrap();
+    let second = h.trotter_circuit(1.0, 3, 2).unwrap();
+    assert_eq!(first.error_bound, 0.0);
+    assert_eq!(second.error_bound, 0.0);
+    assert_eq!(first.exponentials, 6);
+    assert_eq!(second.exponentials, 7);
+    assert!(check_equivalence(&first.circuit, &second.circuit));
+    assert!(check_equivalence(&first.circuit, &Circuit::from_str("pauli_exp (0.5) ZZ 0 1\npauli_exp (0.3) Z 1\n").unwrap()));
+}
+
+fn spin_model(lattice: Lattice, boundary: Boundary, interaction: Interaction) -> SpinModel {
+    SpinModel { lattice, boundary, interaction, j: 1.0, h: 0.7 }
+}
+
+#[test]
+fn lattice_edges() {
+    use Boundary::{Open, Periodic};
+    let cases = [
+        (Lattice::Chain(4), Open, 4, 3),
+        (Lattice::Chain(4), Periodic, 4, 4),
+        (Lattice::Chain(2), Periodic, 2, 1),
+        (Lattice::Square(3, 3), Open, 9, 12),
+        (Lattice::Square(3, 3), Periodic, 9, 18),
+        (Lattice::Square(2, 3), Periodic, 6, 9),
+        (Lattice::Triangular(3, 3), Open, 9, 16),
+        (Lattice::Triangular(3, 3), Periodic, 9, 27),
+        (Lattice::HeavyHex(2, 4), Open, 16, 16),
+        (Lattice::HeavyHex(2, 4), Periodic, 20, 24),
+    ];
+    for (lattice, boundary, qubits, edge_count) in cases {
+        let model = spin_model(lattice, boundary, Interaction::Ising);
+        let edges = model.edges().unwrap();
+        assert_eq!(model.qubit_count(), Ok(qubits), "{:?} {:?}", lattice, boundary);
+        assert_eq!(edges.len(), edge_count, "{:?} {:?}", lattice, boundary);
+
+        let mut degrees = vec![0; qubits as usize];
+        for (i, &(a, b)) in edges.iter().enumerate() {
+            assert!(a < b && b < qubits);
+            assert!(!edges[..i].contains(&(a, b)));
+            degrees[a as usize] += 1;
+            degrees[b as usize] += 1;
+        }
+        if let Lattice::HeavyHex(..) = lattice {
+            assert!(degrees.iter().all(|&d| d <= 3));
+            if boundary == Periodic {
+                // Hexagon sites have three edges and edge qubits two
+                assert!(degrees[..8].iter().all(|&d| d == 3) && degrees[8..].iter().all(|&d| d == 2));
+            }
+        }
+    }
+
+    assert!(spin_model(Lattice::HeavyHex(3, 4), Periodic, Interaction::Ising).edges().is_err());
+    assert!(spin_model(Lattice::Square(6, 6), Open, Interaction::Ising).edges().is_err());
+    assert!(spin_model(Lattice::HeavyHex(4, 4), Periodic, Interaction::Ising).edges().is_err());
+    // Sizes that overflow, or whose edges would take too long to list
+    assert!(spin_model(Lattice::Square(1 << 16, 1 << 16), Open, Interaction::Ising).qubit_count().is_err());
+    assert!(spin_model(Lattice::HeavyHex(100_000, 40_000), Open, Interaction::Ising).edges().is_err());
+}
+
+#[test]
+fn lattice_ising_matches_checked_in_circuit() {
+    // The 5x5 file couples the same pairs
+    let file = Circuit::from_str(include_str!("ising5x5.crc")).unwrap();
+    let mut file_edges: Vec<(u32, u32)> = file.ops.iter().filter(|op| op.op_id == ops::RZZ).map(|op| (op.q1, op.q2)).take(40).collect();
+    let model = spin_model(Lattice::Square(5, 5), Boundary::Open, Interaction::Ising);
+    let mut edges = model.edges().unwrap();
+    file_edges.sort();
+    edges.sort();
+    assert_eq!(edges, file_edges);
+
+    // A step is an RX(-2 * h * dt) layer then an RZZ(-2 * J * dt) layer, as in the file
+    let model = SpinModel { j: -1.0, ..spin_model(Lattice::Square(2, 3), Boundary::Open, Interaction::Ising) };
+    let trotter = model.trotter_circuit(0.8, 2, 1).unwrap();
+    assert_eq!(trotter.exponentials, 2 * (6 + 7));
+    let step: String = (0..6).map(|q| format!("rx (-1.12) {}\n", q)).chain(
+        model.edges().unwrap().iter().map(|(a, b)| format!("rzz (1.6) {} {}\n", a, b))
+    ).collect();
+    let expected = Circuit::from_str(&step.repeat(2)).unwrap();
+    assert!(check_equivalence(&trotter.circuit, &expected));
+}
+
+#[test]
+fn lattice_models_and_observables() {
+    let heisenberg = spin_model(Lattice::Chain(6), Boundary::Periodic, Interaction::Heisenberg);
+    let h = heisenberg.hamiltonian().unwrap();
+    assert_eq!(h.terms.len(), 6 + 3 * 6);
+    assert_eq!(h.terms[0], PauliTerm { coefficient: -0.7, x_mask: 0, z_mask: 1 });
+
+    let observables = heisenberg.observables().unwrap();
+    let names: Vec<&str> = observables.iter().map(|o| o.name.as_str()).collect();
+    assert_eq!(names, ["energy", "magnetization_x", "magnetization_z", "correlation_xx", "correlation_yy", "correlation_zz"]);
+    assert_eq!(observables[2].operator.terms.len(), 6);
+    assert!((observables[2].operator.one_norm() - 1.0).abs() < 1e-6);
+
+    let xy = spin_model(Lattice::Triangular(2, 2), Boundary::Open, Interaction::XY);
+    assert_eq!(xy.observables().unwrap().len(), 5);
+
+    // The whole pipeline runs on the GPU
+    let model = spin_model(Lattice::HeavyHex(2, 2), Boundary::Open, Interaction::XY);
+    let gpu = run_sorted(model.trotter_circuit(0.2, 2, 2).unwrap().circuit);
+    assert_results_close(&gpu, &run_cpu(model.trotter_circuit(0.2, 2, 2).unwrap().circuit));
+}
+
+fn run_gpu_with_outcomes(circ: Circuit, seed: u64, shots: usize) -> Vec<(Vec<Result>, Vec<u32>)> {
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(circ).await;
+        gpu_context.set_config(RunConfig { seed, ..Default::default() });
+        gpu_context.create_resources();
+        let mut runs = Vec::new();
+        for _ in 0..shots {
+            let mut output = gpu_context.run_with_outcomes().await;
+            output.results.sort_by_key(|r| r.entry_idx);
+            runs.push((output.results, output.outcomes));
+        }
+        runs
+    })
+}
+
+fn run_cpu_with_outcomes(circ: Circuit, seed: u64, shots: usize) -> Vec<(Vec<Result>, Vec<u32>)> {
+    let mut sim = CpuSimulator::new(circ);
+    sim.set_config(RunConfig { seed, ..Default::default() });
+    (0..shots).map(|_| {
+        let mut output = sim.run_with_outcomes();
+        output.results.sort_by_key(|r| r.entry_idx);
+        (output.results, output.outcomes)
+    }).collect()
+}
+
+#[test]
+fn teleportation() {
+    // Teleport rx(0.7)|0> from qubit 0 to qubit 2. The corrections act on the measured qubits,
+    // which are classical after the measurements.
+    let src = "rx (0.7) 0\nh 1\ncx 1 2\ncx 0 1\nh 0\nmz 0\nmz 1\ncx 1 2\ncz 0 2\n";
+    let shots = 32;
+    let gpu = run_gpu_with_outcomes(Circuit::from_str(src).unwrap(), 7, shots);
+    let cpu = run_cpu_with_outcomes(Circuit::from_str(src).unwrap(), 7, shots);
+
+    let mut seen = [false; 4];
+    for ((gpu_results, gpu_outcomes), (cpu_results, cpu_outcomes)) in gpu.iter().zip(&cpu) {
+        assert_eq!(gpu_outcomes, cpu_outcomes);
+        assert_results_close(gpu_results, cpu_results);
+
+        // Qubits 0 and 1 hold the outcomes, and qubit 2 the teleported state
+        let measured = cpu_outcomes[0] | (cpu_outcomes[1] << 1);
+        seen[measured as usize] = true;
+        let (c, s) = (0.35f32.cos(), 0.35f32.sin());
+        let expected = [
+            Result { entry_idx: measured, probability: c * c },
+            Result { entry_idx: measured | 4, probability: s * s },
+        ];
+        assert_results_close(cpu_results, &expected);
+    }
+    // Each outcome is equally likely, so 32 shots should see them all
+    assert!(seen.iter().all(|&s| s), "Outcomes seen: {:?}", seen);
+}
+
+#[test]
+fn mid_circuit_measurement_gpu_matches_cpu() {
+    // Enough qubits for the probability sums to span several workgroups
+    let mut src: String = (0..13).map(|q| format!("h {}\n", q)).collect();
+    src += "cx 0 13\nmz 0\nmz 13\nrx (0.4) 13\nmresetz 5\nx 5\n";
+    src += &(1..13).filter(|&q| q != 5).map(|q| format!("mz {}\n", q)).collect::<String>();
+    let circ = Circuit::from_str(&src).unwrap();
+    assert_eq!(circ.result_count(), 14);
+
+    let shots = 4;
+    let gpu = run_gpu_with_outcomes(Circuit::from_str(&src).unwrap(), 11, shots);
+    let cpu = run_cpu_with_outcomes(circ, 11, shots);
+    for ((gpu_results, gpu_outcomes), (cpu_results, cpu_outcomes)) in gpu.iter().zip(&cpu) {
+        assert_eq!(gpu_outcomes, cpu_outcomes);
+        assert_results_close(gpu_results, cpu_results);
+        // The CX copied qubit 0 onto qubit 13 before they were measured
+        assert_eq!(cpu_outcomes[0], cpu_outcomes[1]);
+        // Qubit 5 was reset then flipped
+        assert!(cpu_results.iter().all(|r| r.entry_idx & (1 << 5) != 0));
+    }
+    assert!(gpu.windows(2).any(|pair| pair[0].1 != pair[1].1), "Every shot had the same outcomes");
+}
+
+#[test]
+fn measurement_statistics() {
+    // P(1) = sin^2(0.6) for rx(1.2), and the reset leaves qubit 0 in |0> for the second measurement
+    let circ = Circuit::from_str("rx (1.2) 0\nmresetz 0\nmz 0\n").unwrap();
+    let shots = 4000;
+    let runs = run_cpu_with_outcomes(circ, 3, shots);
+    let ones = runs.iter().filter(|(_, outcomes)| outcomes[0] == 1).count();
+    let expected = 0.6f32.sin().powi(2);
+    assert!((ones as f32 / shots as f32 - expected).abs() < 0.03, "{} ones in {} shots", ones, shots);
+    assert!(runs.iter().all(|(results, outcomes)| outcomes[1] == 0 && results[0].entry_idx == 0));
+
+    // QIR measurements write the result they name, and hidden shift measures the shift every time
+    let circ = Circuit::from_qir_str(include_str!("hidden_shift.qir")).unwrap();
+    assert_eq!(circ.result_count(), 6);
+    let (results, outcomes) = &run_gpu_with_outcomes(circ, 5, 1)[0];
+    assert_eq!(results.len(), 1);
+    assert!((results[0].probability - 1.0).abs() < 1e-4);
+    let shift: Vec<u32> = (0..6).map(|q| (results[0].entry_idx >> q) & 1).collect();
+    assert_eq!(outcomes, &shift);
+}
+
+#[test]
+fn reset_qubits() {
+    // Resetting one half of a Bell pair leaves the other half in a random basis state
+    let src = "h 0\ncx 0 1\nreset 0\n";
+    let shots = 16;
+    let gpu = run_gpu_with_outcomes(Circuit::from_str(src).unwrap(), 9, shots);
+    let cpu = run_cpu_with_outcomes(Circuit::from_str(src).unwrap(), 9, shots);
+    let mut seen = [false; 2];
+    for ((gpu_results, gpu_outcomes), (cpu_results, _)) in gpu.iter().zip(&cpu) {
+        assert!(gpu_outcomes.is_empty(), "Resets don't record outcomes");
+        assert_results_close(gpu_results, cpu_results);
+        assert_eq!(cpu_results.len(), 1);
+        assert!(matches!(cpu_results[0].entry_idx, 0 | 2));
+        seen[cpu_results[0].entry_idx as usize / 2] = true;
+    }
+    assert_eq!(seen, [true, true]);
+
+    // A qubit in a product state resets without disturbing the others, and can then be reused
+    let src = "rx (0.5) 1\nh 0\nreset 0\nx 0\nmz 0\nrx (1.5) 10\nreset 10\nreset 3\n";
+    let expected = [
+        Result { entry_idx: 1, probability: 0.25f32.cos().powi(2) },
+        Result { entry_idx: 3, probability: 0.25f32.sin().powi(2) },
+    ];
+    for (results, outcomes) in run_gpu_with_outcomes(Circuit::from_str(src).unwrap(), 1, 4)
+        .into_iter()
+        .chain(run_cpu_with_outcomes(Circuit::from_str(src).unwrap(), 1, 4))
+    {
+        assert_eq!(outcomes, [1]);
+        assert_results_close(&results, &expected);
+    }
+}
+
+fn sample_gpu(circ: Circuit, seed: u64, shots: usize) -> Counts {
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(circ).await;
+        gpu_context.set_config(RunConfig { seed, ..Default::default() });
+        gpu_context.create_resources();
+        gpu_context.sample(shots).await
+    })
+}
+
+fn sample_cpu(circ: Circuit, seed: u64, shots: usize) -> Counts {
+    let mut sim = CpuSimulator::new(circ);
+    sim.set_config(RunConfig { seed, ..Default::default() });
+    sim.sample(shots)
+}
+
+#[test]
+fn sample_final_state() {
+    // Enough qubits for the CDF to span several workgroups
+    let src = "h 0\ncx 0 13\nrx (0.9) 7\n";
+    let shots = 5000;
+    let gpu = sample_gpu(Circuit::from_str(src).unwrap(), 21, shots);
+    assert_eq!(gpu, sample_cpu(Circuit::from_str(src).unwrap(), 21, shots));
+
+    assert_eq!(gpu.values().sum::<usize>(), shots);
+    let mut keys: Vec<&str> = gpu.keys().map(|k| k.as_str()).collect();
+    keys.sort();
+    assert_eq!(keys, ["00000000000000", "00000010000000", "10000000000001", "10000010000001"]);
+    let ones: usize = gpu.iter().filter(|(bits, _)| bits.as_bytes()[13 - 7] == b'1').map(|(_, &n)| n).sum();
+    assert!((ones as f32 / shots as f32 - 0.45f32.sin().powi(2)).abs() < 0.03);
+
+    // More shots than fit in one batch
+    let shots = 70_000;
+    let gpu = sample_gpu(Circuit::from_str("h 0\nrx (0.3) 1\n").unwrap(), 2, shots);
+    assert_eq!(gpu, sample_cpu(Circuit::from_str("h 0\nrx (0.3) 1\n").unwrap(), 2, shots));
+    assert_eq!(gpu.values().sum::<usize>(), shots);
+
+    // Compacted qubits are reported by their original labels
+    let compacted = passes::compact_qubits(&Circuit::from_str("x 3\nh 5\n").unwrap());
+    let counts = sample_gpu(compacted, 1, 100);
+    assert!(counts.keys().all(|bits| bits == "001000" || bits == "101000"), "{:?}", counts);
+}
+
+#[test]
+fn sample_measured_results() {
+    // Terminal measurements sample the final state. Bitstrings are over the results, then the
+    // qubits without one (here qubit 1).
+    let src = "h 0\ncx 0 1\nx 2\nmz 2\nmz 0\n";
+    let circ = Circuit::from_str(src).unwrap();
+    assert!(matches!(Readout::for_circuit(&circ), Readout::FinalState { stop: 3, .. }));
+    let gpu = sample_gpu(circ, 4, 1000);
+    assert_eq!(gpu, sample_cpu(Circuit::from_str(src).unwrap(), 4, 1000));
+    assert_eq!(gpu.len(), 2);
+    assert!(gpu["001"] > 400 && gpu["111"] > 400);
+
+    // Measurements partway through run the circuit per shot
+    let teleport = "rx (0.7) 0\nh 1\ncx 1 2\ncx 0 1\nh 0\nmz 0\nmz 1\ncx 1 2\ncz 0 2\nmz 2\n";
+    let circ = Circuit::from_str(teleport).unwrap();
+    assert!(matches!(Readout::for_circuit(&circ), Readout::PerShot { .. }));
+    let shots = 400;
+    let gpu = sample_gpu(circ, 8, shots);
+    assert_eq!(gpu, sample_cpu(Circuit::from_str(teleport).unwrap(), 8, shots));
+    let ones: usize = gpu.iter().filter(|(bits, _)| bits.starts_with('1')).map(|(_, &n)| n).sum();
+    assert!((ones as f32 / shots as f32 - 0.35f32.sin().powi(2)).abs() < 0.06);
+
+    for src in ["h 0\nreset 0\nmz 0\n", "mz 0\nmz 0\n", "mz 0\nh 0\n"] {
+        assert!(matches!(Readout::for_circuit(&Circuit::from_str(src).unwrap()), Readout::PerShot { .. }), "{}", src);
+    }
+
+    // A reset without measurements still reads every qubit from each shot's final state. The
+    // reset leaves qubit 0 in |0>, and collapses qubit 1 with it.
+    let src = "h 0\ncx 0 1\nreset 0\nh 2\n";
+    let gpu = sample_gpu(Circuit::from_str(src).unwrap(), 5, 400);
+    assert_eq!(gpu, sample_cpu(Circuit::from_str(src).unwrap(), 5, 400));
+    assert_eq!(gpu.keys().map(String::as_str).collect::<std::collections::BTreeSet<_>>(), ["000", "010", "100", "110"].into());
+
+    // Mid-circuit measurements with a qubit only measured at the end: the result, then qubits 1 and 2
+    let src = "h 0\nmz 0\ncx 0 1\nx 2\n";
+    let gpu = sample_gpu(Circuit::from_str(src).unwrap(), 6, 400);
+    assert_eq!(gpu, sample_cpu(Circuit::from_str(src).unwrap(), 6, 400));
+    assert_eq!(gpu.keys().map(String::as_str).collect::<std::collections::BTreeSet<_>>(), ["100", "111"].into());
+    let ising = Circuit::from_str(include_str!("ising5x5.crc")).unwrap();
+    match Readout::for_circuit(&ising) {
+        Readout::FinalState { stop, bits } => {
+            assert_eq!(stop, ising.ops.len() - 26);
+            assert_eq!(bits, (0..25).map(Some).collect::<Vec<_>>());
+        }
+        _ => panic!("The Ising circuit only measures at the end"),
+    }
+}
+
+#[test]
+fn philox_known_answers() {
+    // Known answer tests from the Random123 distribution
+    assert_eq!(rng::philox4x32([0, 0], [0, 0, 0, 0]), [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8]);
+    assert_eq!(
+        rng::philox4x32([0xffffffff, 0xffffffff], [0xffffffff; 4]),
+        [0x408f276d, 0x41c83b0e, 0xa20bc7c6, 0x6d5451fd]
+    );
+    assert_eq!(
+        rng::philox4x32([0xa4093822, 0x299f31d0], [0x243f6a88, 0x85a308d3, 0x13198a2e, 0x03707344]),
+        [0xd16cfe09, 0x94fdcceb, 0x5001e420, 0x24126ea1]
+    );
+
+    // Draws are spread over [0, 1), and differ by seed, shot and index
+    let draws: Vec<f32> = (0..10_000).map(|i| rng::uniform(1, 0, i)).collect();
+    assert!(draws.iter().all(|&u| (0.0..1.0).contains(&u)));
+    let mean = draws.iter().sum::<f32>() / draws.len() as f32;
+    assert!((mean - 0.5).abs() < 0.01);
+    assert_ne!(rng::uniform(1, 0, 0), rng::uniform(2, 0, 0));
+    assert_ne!(rng::uniform(1, 0, 0), rng::uniform(1, 1, 0));
+    assert_ne!(rng::uniform(1, 0, 0), rng::uniform(1, 0, 1));
+}
+
+#[test]
+fn seeded_shots_reproducible() {
+    let src = "h 0\nh 1\nrx (0.7) 2\ncx 0 3\nmz 0\nh 0\nmz 0\nmz 1\nmz 2\nmz 3\n";
+    let shots = 200;
+    let first = sample_gpu(Circuit::from_str(src).unwrap(), 42, shots);
+    assert_eq!(first, sample_gpu(Circuit::from_str(src).unwrap(), 42, shots));
+    assert_eq!(first, sample_cpu(Circuit::from_str(src).unwrap(), 42, shots));
+    assert_ne!(first, sample_gpu(Circuit::from_str(src).unwrap(), 43, shots));
+
+    // Setting the config again restarts the shots
+    let mut sim = CpuSimulator::new(Circuit::from_str("h 0\nh 1\nh 2\n").unwrap());
+    sim.set_config(RunConfig { seed: 42, ..Default::default() });
+    let counts = sim.sample(50);
+    assert_ne!(counts, sim.sample(50));
+    sim.set_config(RunConfig { seed: 42, ..Default::default() });
+    assert_eq!(counts, sim.sample(50));
+}
+
+fn gpu_state_vector(circ: Circuit) -> Vec<Complex32> {
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(circ).await;
+        gpu_context.create_resources();
+        gpu_context.run().await;
+        gpu_context.state_vector().await
+    })
+}
+
+fn assert_states_close(a: &[Complex32], b: &[Complex32]) {
+    assert_eq!(a.len(), b.len());
+    for (x, y) in a.iter().zip(b) {
+        assert!(f32_close(x.re, y.re) && f32_close(x.im, y.im), "{:?} != {:?}", x, y);
+    }
+}
+
+#[test]
+fn read_state_vector() {
+    let src = "h 0\nsx 1\nrx (0.4) 2\ncx 0 3\nrzz (0.8) 1 2\nh 3\ncz 0 2\nrz (1.3) 1\n";
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.run();
+    assert_states_close(sim.state(), &gpu_state_vector(Circuit::from_str(src).unwrap()));
+
+    // A single permutation leaves the state in the scratch buffer
+    let src = "h 0\nh 1\nh 10\nrx (0.3) 6\ncx 0 3\nccx 0 1 4\ncx 4 5\nccx 3 10 7\nx 9\n";
+    let batched = passes::batch_permutation_ops(&Circuit::from_str(src).unwrap());
+    assert_eq!(batched.ops.iter().filter(|op| op.op_id == ops::PERMUTATION).count(), 1);
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.run();
+    assert_states_close(sim.state(), &gpu_state_vector(batched));
+}
+
+#[test]
+fn read_state_vector_range() {
+    // 2^21 entries take two staging chunks
+    let src = "h 0\ncx 0 20\nrx (0.3) 10\nrx (1.1) 19\nrz (0.6) 19\n";
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.run();
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(Circuit::from_str(src).unwrap()).await;
+        gpu_context.create_resources();
+        gpu_context.run().await;
+        assert_states_close(sim.state(), &gpu_context.state_vector().await);
+
+        let entries = (1 << 20) - 3..(1 << 20) + 1030;
+        assert_states_close(&sim.state()[entries.clone()], &gpu_context.state_vector_range(entries).await);
+        assert!(gpu_context.state_vector_range(5..5).await.is_empty());
+    });
+}
+
+
+fn gpu_expectations(circ: Circuit, operators: &[&PauliSum]) -> Vec<f64> {
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(circ).await;
+        gpu_context.create_resources();
+        gpu_context.run().await;
+        let mut values = Vec::new();
+        for operator in operators {
+            values.push(gpu_context.expectation(operator).await);
+        }
+        values
+    })
+}
+
+#[test]
+fn pauli_expectation_values() {
+    // Bell state: <ZZ> = <XX> = 1, <YY> = -1, <Z0> = <X0> = 0. Qubits 0 and 2 stay in |0>
+    let src = "h 1\ncx 1 3\nid 0\nid 2\n";
+    let operator = |text: &str| PauliSum::from_str(text).unwrap();
+    let cases = [
+        ("1.0 Z1 Z3", 1.0), ("1.0 X1 X3", 1.0), ("1.0 Y1 Y3", -1.0), ("1.0 Y1 X3", 0.0), ("1.0 Z1", 0.0),
+        ("1.0 X1", 0.0), ("1.0 Z0", 1.0), ("1.0 X2 X1 X3", 0.0), ("-0.5 Z0 Z1 Z3\n2.0 X1 X3\n0.25 I", 1.75),
+    ];
+    let operators: Vec<PauliSum> = cases.iter().map(|(text, _)| operator(text)).collect();
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.run();
+    // Compacting drops the idle qubits, so terms on them are handled without simulating them
+    let compacted = passes::compact_qubits(&Circuit::from_str(src).unwrap());
+    assert_eq!(compacted.qubit_count, 2);
+    let gpu = gpu_expectations(compacted, &operators.iter().collect::<Vec<_>>());
+    for (((text, expected), operator), gpu) in cases.iter().zip(&operators).zip(gpu) {
+        assert!((sim.expectation(operator) - expected).abs() < 1e-6, "{}", text);
+        assert!((gpu - expected).abs() < 1e-6, "{}", text);
+    }
+
+    // A 12 qubit Heisenberg evolution, with the energy and observables on both backends
+    let model = spin_model(Lattice::Square(3, 4), Boundary::Open, Interaction::Heisenberg);
+    let circ = model.trotter_circuit(0.3, 2, 2).unwrap().circuit;
+    let hamiltonian = model.hamiltonian().unwrap();
+    let observables = model.observables().unwrap();
+    let mut operators = vec![&hamiltonian];
+    operators.extend(observables.iter().map(|observable| &observable.operator));
+    let mut sim = CpuSimulator::new(model.trotter_circuit(0.3, 2, 2).unwrap().circuit);
+    sim.run();
+    for (operator, gpu) in operators.iter().zip(gpu_expectations(circ, &operators)) {
+        assert!((sim.expectation(operator) - gpu).abs() < 1e-4, "{} != {}", sim.expectation(operator), gpu);
+    }
+
+    // The state is read from the scratch buffer after a permutation
+    let src = "h 0\nh 1\nh 10\nrx (0.3) 6\ncx 0 3\nccx 0 1 4\ncx 4 5\nccx 3 10 7\nx 9\n";
+    let operator = operator("0.7 Z3 Z7\n-1.2 X0 X3 Z9\n0.4 Y6\n0.9 Z6");
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.run();
+    let gpu = gpu_expectations(passes::batch_permutation_ops(&Circuit::from_str(src).unwrap()), &[&operator]);
+    assert!((sim.expectation(&operator) - gpu[0]).abs() < 1e-5);
+}
+
+fn assert_marginals_close(a: &[f32], b: &[f32]) {
+    assert_eq!(a.len(), b.len());
+    for (x, y) in a.iter().zip(b) {
+        assert!((x - y).abs() < 1e-5, "{} != {}", x, y);
+    }
+}
+
+#[test]
+fn marginal_probabilities() {
+    let src = "h 0\nh 1\nh 10\nrx (0.3) 6\ncx 0 3\nccx 0 1 4\ncx 4 5\nccx 3 10 7\nx 9\nrx (1.1) 11\ncx 11 2\n";
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.run();
+    // Qubit 14 isn't simulated, so is always 0
+    let src = format!("{}id 14\n", src);
+    let circ = passes::batch_permutation_ops(&passes::compact_qubits(&Circuit::from_str(&src).unwrap()));
+    let cases: [&[u32]; 7] = [&[], &[3], &[9, 0], &[0, 3, 7, 10], &[6, 14], &[11, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10], &[14]];
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(circ).await;
+        gpu_context.create_resources();
+        gpu_context.run().await;
+        for qubits in cases {
+            let expected = if qubits.contains(&14) {
+                let mut expected = vec![0.0; 1 << qubits.len()];
+                let others: Vec<u32> = qubits.iter().copied().filter(|&q| q != 14).collect();
+                for (bin, p) in sim.marginal_probabilities(&others).into_iter().enumerate() {
+                    expected[bin] = p;
+                }
+                expected
+            } else {
+                sim.marginal_probabilities(qubits)
+            };
+            assert_marginals_close(&expected, &gpu_context.marginal_probabilities(qubits).await);
+        }
+
+        // Pauli Z expectations fall out of the marginals
+        let marginal = gpu_context.marginal_probabilities(&[6]).await;
+        assert_marginals_close(&marginal, &[0.15f32.cos().powi(2), 0.15f32.sin().powi(2)]);
+        for (qubits, operator) in [(&[6][..], "1.0 Z6"), (&[3, 7], "1.0 Z3 Z7"), (&[0, 9, 11], "1.0 Z0 Z9 Z11")] {
+            let marginal = gpu_context.marginal_probabilities(qubits).await;
+            let expected = sim.expectation(&PauliSum::from_str(operator).unwrap());
+            assert!((parity_expectation(&marginal) - expected).abs() < 1e-5, "{}", operator);
+        }
+    });
+
+    // Keeping 17 of 18 qubits takes two batches of bins, with two entries in each
+    let src: String = (0..18).map(|q| format!("rx ({}) {}\n", 0.1 * q as f32 + 0.2, q)).collect::<String>() + "cx 3 17\ncx 16 0\n";
+    let qubits: Vec<u32> = (0..18).filter(|&q| q != 5).collect();
+    let mut sim = CpuSimulator::new(Circuit::from_str(&src).unwrap());
+    sim.run();
+    futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(Circuit::from_str(&src).unwrap()).await;
+        gpu_context.create_resources();
+        gpu_context.run().await;
+        assert_marginals_close(&sim.marginal_probabilities(&qubits), &gpu_context.marginal_probabilities(&qubits).await);
+    });
+}
+
+#[test]
+fn result_selection() {
+    // Uniform over 13 qubits, so every entry ties at 1/8192 and the lowest indices are kept
+    let uniform: String = (0..13).map(|q| format!("h {}\n", q)).collect();
+    // Distinct rotations, so the probabilities are spread out
+    let spread: String = (0..12).map(|q| format!("rx ({}) {}\n", 0.25 + 0.11 * q as f32, q)).collect();
+    let cases = [
+        (&uniform, ResultSelection::Threshold { threshold: 1e-4, capacity: 10 }, 10, 8182),
+        (&uniform, ResultSelection::Threshold { threshold: 1e-3, capacity: 10 }, 0, 0),
+        (&uniform, ResultSelection::TopK(5), 5, 8187),
+        (&spread, ResultSelection::default(), 20, 0),
+        (&spread, ResultSelection::Threshold { threshold: 1e-3, capacity: 30 }, 30, 120),
+        (&spread, ResultSelection::TopK(40), 40, 4056),
+        (&spread, ResultSelection::TopK(5000), 4096, 0),
+    ];
+    for (src, results, count, overflow) in cases {
+        let config = RunConfig { results, ..Default::default() };
+        let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+        sim.set_config(config.clone());
+        let cpu = sim.run_with_outcomes();
+        let (gpu, again) = futures::executor::block_on(async {
+            let mut gpu_context = GpuContext::new(Circuit::from_str(src).unwrap()).await;
+            gpu_context.create_resources();
+            // Changing the capacity after creating the resources resizes the results buffer
+            gpu_context.set_config(config);
+            (gpu_context.run_with_outcomes().await, gpu_context.run_with_outcomes().await)
+        });
+
+        for output in [&cpu, &gpu] {
+            assert_eq!((output.results.len(), output.overflow), (count, overflow), "{:?}", results);
+            assert!(output.results.windows(2).all(|pair| pair[0].probability >= pair[1].probability));
+        }
+        assert_eq!(cpu.results.iter().map(|r| r.entry_idx).collect::<Vec<_>>(), gpu.results.iter().map(|r| r.entry_idx).collect::<Vec<_>>());
+        assert_eq!(gpu.results.iter().map(|r| r.entry_idx).collect::<Vec<_>>(), again.results.iter().map(|r| r.entry_idx).collect::<Vec<_>>());
+        if src == &uniform && count > 0 {
+            assert!(gpu.results.iter().map(|r| r.entry_idx).eq(0..count as u32));
+        }
+    }
+}
+
+#[test]
+fn initial_states() {
+    let h = Complex32::new(std::f32::consts::FRAC_1_SQRT_2, 0.0);
+    let c = |re: f32, im: f32| Complex32::new(re, im);
+    let first = "h 0\ncx 0 3\nrx (0.7) 2\nccx 0 2 1\n";
+    let second = "cx 3 1\nh 2\nrzz (0.4) 1 2\ncz 0 3\n";
+    let product = vec![[h, h], [Complex32::ZERO, Complex32::ONE], [c(0.6, 0.0), c(0.0, 0.8)], [h, -h]];
+    // Each case with the real parts of some amplitudes of the final state
+    let cases = [
+        // CX flips qubit 0 as qubit 1 is set
+        (InitialState::Basis(0b0110), "cx 1 0\nid 3\n", vec![(0b0111, 1.0)]),
+        // H takes qubit 0 from |+> back to |0>
+        (InitialState::Product(product.clone()), "h 0\nid 3\n", vec![(0b0010, 0.6 * std::f32::consts::FRAC_1_SQRT_2)]),
+        (InitialState::Product(product), second, vec![]),
+    ];
+    for (initial_state, src, expected) in cases {
+        let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+        sim.set_initial_state(initial_state.clone()).unwrap();
+        sim.run();
+        let gpu = futures::executor::block_on(async {
+            let mut gpu_context = GpuContext::new(Circuit::from_str(src).unwrap()).await;
+            gpu_context.set_initial_state(initial_state).unwrap();
+            gpu_context.create_resources();
+            gpu_context.run().await;
+            gpu_context.state_vector().await
+        });
+        assert_states_close(sim.state(), &gpu);
+        for (idx, re) in expected {
+            assert!(f32_close(gpu[idx].re, re), "{}: amplitude {} is {:?}", src, idx, gpu[idx]);
+        }
+    }
+
+    // Chaining two runs through the state vector matches running both halves together
+    let mut sim = CpuSimulator::new(Circuit::from_str(&format!("{}{}", first, second)).unwrap());
+    sim.run();
+    futures::executor::block_on(async {
+        let mut first_context = GpuContext::new(Circuit::from_str(first).unwrap()).await;
+        first_context.create_resources();
+        first_context.run().await;
+        let mut second_context = GpuContext::new(Circuit::from_str(second).unwrap()).await;
+        second_context.create_resources();
+        second_context.set_initial_state(InitialState::Amplitudes(first_context.state_vector().await)).unwrap();
+        second_context.run().await;
+        assert_states_close(sim.state(), &second_context.state_vector().await);
+
+        // Measuring qubit 3 of a basis state with it set always gives 1, and the unmeasured qubits
+        // after it stay 0
+        let mut measure = GpuContext::new(Circuit::from_str("mz 3\n").unwrap()).await;
+        measure.set_initial_state(InitialState::Basis(0b1000)).unwrap();
+        measure.create_resources();
+        assert_eq!(measure.sample(20).await, Counts::from([("0001".to_string(), 20)]));
+    });
+
+    // The state must fit the register and be normalized
+    let mut sim = CpuSimulator::new(Circuit::from_str(first).unwrap());
+    assert!(sim.set_initial_state(InitialState::Basis(16)).is_err());
+    assert!(sim.set_initial_state(InitialState::Product(vec![[Complex32::ONE, Complex32::ZERO]; 3])).is_err());
+    assert!(sim.set_initial_state(InitialState::Product(vec![[h, Complex32::ONE]; 4])).is_err());
+    assert!(sim.set_initial_state(InitialState::Amplitudes(vec![Complex32::ONE; 8])).is_err());
+    assert!(sim.set_initial_state(InitialState::Amplitudes(vec![c(0.3, 0.0); 16])).is_err());
+    assert!(sim.set_initial_state(InitialState::Amplitudes(vec![c(f32::NAN, 0.0); 16])).is_err());
+    assert!(sim.set_initial_state(InitialState::Product(vec![[c(f32::NAN, 0.0), Complex32::ZERO]; 4])).is_err());
+    assert!(sim.set_initial_state(InitialState::Amplitudes(vec![c(0.0, 0.25); 16])).is_ok());
+}
+
+#[test]
+fn state_preparation() {
+    let c = |re: f32, im: f32| Complex32::new(re, im);
+    for qubit_count in 1..=5 {
+        // A reproducible random state with no zero amplitudes
+        let size = 1usize << qubit_count;
+        let raw: Vec<Complex32> = (0..size as u64)
+            .map(|i| c(rng::uniform(7, i, 0) - 0.5, rng::uniform(7, i, 1) - 0.5))
+            .collect();
+        let norm = raw.iter().map(|amp| amp.norm_sqr()).sum::<f32>().sqrt();
+        let target: Vec<Complex32> = raw.iter().map(|amp| amp.scale(1.0 / norm)).collect();
+
+        let prep = prepare_state(&target, DEFAULT_OVERLAP_TOLERANCE).unwrap();
+        assert!(prep.overlap > 1.0 - DEFAULT_OVERLAP_TOLERANCE);
+        assert!(prep.cx_count <= (1 << (qubit_count + 1)) - 2, "{} qubits: {}", qubit_count, prep);
+
+        // The GPU prepares the same state once the circuit is in its native gates
+        let native = passes::fuse_1q_matrices(&transpile(&prep.circuit, gate_sets::H_RZ_CX).unwrap());
+        let gpu = gpu_state_vector(native);
+        let overlap = target.iter().zip(&gpu).fold(Complex32::ZERO, |sum, (t, s)| sum + t.conj() * *s);
+        assert!(overlap.norm_sqr() > 1.0 - 1e-3, "{} qubits: overlap {}", qubit_count, overlap.norm_sqr());
+    }
+
+    // Real non-negative amplitudes need no RZ, and a basis state needs no CX
+    let h = std::f32::consts::FRAC_1_SQRT_2;
+    let mut ghz = vec![Complex32::ZERO; 8];
+    (ghz[0], ghz[7]) = (c(h, 0.0), c(h, 0.0));
+    let prep = prepare_state(&ghz, DEFAULT_OVERLAP_TOLERANCE).unwrap();
+    assert!(prep.circuit.ops.iter().all(|op| op.op_id != ops::RZ));
+    assert!(prep.cx_count <= 6);
+    let mut basis = vec![Complex32::ZERO; 8];
+    basis[0b101] = c(0.0, 1.0);
+    let prep = prepare_state(&basis, DEFAULT_OVERLAP_TOLERANCE).unwrap();
+    assert_eq!(prep.cx_count, 0);
+    assert_eq!(prep.rotation_count, 2);
+    assert!(f32_close(state_overlap(&prep.circuit, &basis) as f32, 1.0));
+    let plus = vec![c(0.5_f32.powf(1.5), 0.0); 8];
+    let prep = prepare_state(&plus, DEFAULT_OVERLAP_TOLERANCE).unwrap();
+    assert_eq!((prep.cx_count, prep.rotation_count), (0, 3));
+
+    // The state must have a power of two amplitudes and be normalized
+    assert!(prepare_state(&[Complex32::ONE], DEFAULT_OVERLAP_TOLERANCE).is_err());
+    assert!(prepare_state(&[Complex32::ONE; 3], DEFAULT_OVERLAP_TOLERANCE).is_err());
+    assert!(prepare_state(&[Complex32::ONE; 4], DEFAULT_OVERLAP_TOLERANCE).is_err());
+}
+
+#[test]
+fn noise_models() {
+    // Gate noise follows each qubit of the gate, then qubit noise each op on the qubit
+    let circ = Circuit::from_str("h 0\ncx 0 1\n").unwrap();
+    let noise = NoiseModel {
+        gates: [(ops::CX, vec![Channel::BitFlip(0.1)])].into(),
+        qubits: [(0, vec![Channel::PhaseFlip(0.2)])].into(),
+        ..Default::default()
+    };
+    let noisy = noise.apply(&circ).unwrap();
+    let ids: Vec<u32> = noisy.ops.iter().map(|op| op.op_id).collect();
+    assert_eq!(
+        ids,
+        [ops::H, ops::PHASE_FLIP, ops::CX, ops::BIT_FLIP, ops::BIT_FLIP, ops::PHASE_FLIP, ops::MEVERYZ]
+    );
+    assert_eq!((noisy.ops[4].q1, noisy.ops[4].angle), (1, 0.1));
+    // Applying another model replaces the noise
+    assert_eq!(NoiseModel::default().apply(&noisy).unwrap().ops.len(), circ.ops.len());
+
+    // Gate noise is keyed by the ops as run, so H noise no longer applies once H is fused into a matrix
+    let noise = NoiseModel { gates: [(ops::H, vec![Channel::BitFlip(0.1)])].into(), ..Default::default() };
+    assert_eq!(noise.apply(&circ).unwrap().ops.len(), circ.ops.len() + 1);
+    let fused = passes::fuse_1q_matrices(&circ);
+    assert_eq!(noise.apply(&fused).unwrap().ops.len(), fused.ops.len());
+    let noise = NoiseModel { gates: [(ops::MATRIX1Q, vec![Channel::BitFlip(0.1)])].into(), ..Default::default() };
+    assert_eq!(noise.apply(&fused).unwrap().ops.len(), fused.ops.len() + 1);
+
+    // Qubit noise must be on a simulated qubit
+    let noise = NoiseModel { qubits: [(2, vec![Channel::PhaseFlip(0.2)])].into(), ..Default::default() };
+    assert!(noise.apply(&circ).is_err());
+    assert!(noise.apply(&passes::compact_qubits(&Circuit::from_str("h 0\nid 2\n").unwrap())).is_err());
+
+    // Qubit noise can't be mapped through the layout SWAPs of a reordered circuit, so must be applied
+    // before reordering. A layout fixed for the whole circuit is fine.
+    let mut src = String::from("h 3\n");
+    for _ in 0..6 {
+        src += "rx (0.3) 3\nrz (0.2) 3\n";
+    }
+    for _ in 0..6 {
+        src += "rx (0.3) 2\nrz (0.2) 2\n";
+    }
+    let circ = Circuit::from_str(&src).unwrap();
+    let noise = NoiseModel { qubits: [(3, vec![Channel::PhaseFlip(0.2)])].into(), ..Default::default() };
+    let swapped = passes::reorder_qubits(&circ, 12);
+    assert!(swapped.ops.iter().any(|op| op.op_id == ops::SWAP));
+    assert!(noise.apply(&swapped).is_err());
+    let gate_noise = NoiseModel { gates: [(ops::RX, vec![Channel::BitFlip(0.1)])].into(), ..Default::default() };
+    assert!(gate_noise.apply(&swapped).is_ok());
+    let fixed = noise.apply(&passes::reorder_qubits(&circ, 0)).unwrap();
+    assert!(fixed.ops.iter().filter(|op| op.op_id == ops::PHASE_FLIP).all(|op| op.q1 == 0));
+    let noisy = passes::reorder_qubits(&noise.apply(&circ).unwrap(), 12);
+    assert_eq!(noisy.ops.iter().filter(|op| op.op_id == ops::PHASE_FLIP).count(), 13);
+
+    // Moment noise follows every qubit after each moment, idle or not
+    let noise = NoiseModel { moments: vec![Channel::Depolarizing(0.01)], ..Default::default() };
+    let noisy = noise.apply(&Circuit::from_str("h 0\ncx 0 1\nh 2\n").unwrap()).unwrap();
+    let ids: Vec<u32> = noisy.ops.iter().map(|op| op.op_id).collect();
+    let d = ops::DEPOLARIZE;
+    assert_eq!(ids, [ops::H, ops::H, d, d, d, ops::CX, d, d, d, ops::MEVERYZ]);
+
+    // Noise makes each shot its own trajectory. No noise follows the final measurements, so they
+    // still sample the final state of each trajectory.
+    assert!(matches!(Readout::for_circuit(&noisy), Readout::Trajectories { stop: 9, .. }));
+    let measured = noise.apply(&Circuit::from_str("h 0\nmz 0\n").unwrap()).unwrap();
+    assert!(matches!(Readout::for_circuit(&measured), Readout::Trajectories { stop: 2, .. }));
+    let noise = NoiseModel { gates: [(ops::MZ, vec![Channel::BitFlip(0.1)])].into(), ..Default::default() };
+    let measured = noise.apply(&Circuit::from_str("h 0\nmz 0\nx 0\nmz 0\nmz 1\n").unwrap()).unwrap();
+    let ids: Vec<u32> = measured.ops.iter().map(|op| op.op_id).collect();
+    assert_eq!(ids, [ops::H, ops::MZ, ops::BIT_FLIP, ops::X, ops::MZ, ops::MZ, ops::MEVERYZ]);
+    assert!(NoiseModel { moments: vec![Channel::AmplitudeDamping(1.5)], ..Default::default() }.check().is_err());
+
+    // Each qubit's channel gives a known probability of reading 1
+    let src = "id 0\nx 1\nh 2\nh 3\nh 2\nh 3\n";
+    let noise = NoiseModel {
+        qubits: [
+            (0, vec![Channel::Depolarizing(0.3)]),   // 2/3 of errors flip |0>
+            (1, vec![Channel::AmplitudeDamping(0.25)]),
+            (2, vec![Channel::PhaseDamping(0.36)]), // H brings the coherence sqrt(1 - 0.36) back to P(0)
+            (3, vec![Channel::PhaseFlip(0.1)]),
+        ]
+        .into(),
+        ..Default::default()
+    };
+    let expected = [0.2, 0.75, 0.1, 0.1];
+    let shots = 2000;
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.set_config(RunConfig { seed: 9, ..Default::default() });
+    sim.set_noise_model(&noise).unwrap();
+    let cpu = sim.sample(shots);
+    let gpu = futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(Circuit::from_str(src).unwrap()).await;
+        gpu_context.set_config(RunConfig { seed: 9, ..Default::default() });
+        gpu_context.create_resources();
+        gpu_context.set_noise_model(&noise).unwrap();
+        gpu_context.sample(shots).await
+    });
+    assert_eq!(gpu, cpu);
+    for (q, p) in expected.into_iter().enumerate() {
+        let ones: usize = gpu.iter().filter(|(bits, _)| bits.as_bytes()[3 - q] == b'1').map(|(_, &n)| n).sum();
+        assert!((ones as f32 / shots as f32 - p).abs() < 0.03, "qubit {}: {} ones", q, ones);
+    }
+
+    // A run follows a single trajectory, the same on both backends
+    let run_gpu = futures::executor::block_on(async {
+        let mut gpu_context = GpuContext::new(Circuit::from_str(src).unwrap()).await;
+        gpu_context.set_noise_model(&noise).unwrap();
+        gpu_context.create_resources();
+        gpu_context.run().await;
+        gpu_context.state_vector().await
+    });
+    let mut sim = CpuSimulator::new(Circuit::from_str(src).unwrap());
+    sim.set_noise_model(&noise).unwrap();
+    sim.run();
+    assert_states_close(sim.state(), &run_gpu);
+}
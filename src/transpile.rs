@@ -0,0 +1,458 @@
+#![allow(unused)]
+
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+use crate::circuit::Circuit;
+use crate::cpu_simulator::{circuit_unitary, controlled_1q_matrix, matrix_1q};
+use crate::modifiers::controlled_1q;
+use crate::passes::normalize_angle;
+use crate::shader_types::{ops, Complex32, Op, MAX_OP_TERMS};
+
+// Rewrites circuits into the gate set accepted by a target (e.g. a hardware partner's native gates).
+
+/// Common native gate sets to transpile to
+pub mod gate_sets {
+    use crate::shader_types::ops;
+
+    pub const SX_RZ_CZ: &[u32] = &[ops::SX, ops::RZ, ops::CZ];
+    pub const RX_RZ_RZZ: &[u32] = &[ops::RX, ops::RZ, ops::RZZ];
+    // With no Z rotation, only circuits whose single qubit gates merge into X rotations (e.g. RX, X
+    // and SX) and whose other gates are RZZ can be expressed, so not ones with H, RZ, CX or CCX
+    pub const RX_RZZ: &[u32] = &[ops::RX, ops::RZZ];
+    pub const H_RZ_CX: &[u32] = &[ops::H, ops::RZ, ops::CX];
+    // Only circuits whose rotations are multiples of pi/4 can be expressed exactly in Clifford+T
+    pub const CLIFFORD_T: &[u32] = &[ops::H, ops::S, ops::S_ADJ, ops::T, ops::T_ADJ, ops::X, ops::Z, ops::CX];
+}
+
+// Single qubit gates with no parameters, tried in order when tidying up a merged single qubit unitary
+const FIXED_1Q_GATES: [u32; 10] = [
+    ops::X, ops::Y, ops::Z, ops::H, ops::S, ops::S_ADJ, ops::T, ops::T_ADJ, ops::SX, ops::SX_ADJ,
+];
+
+const EPSILON: f32 = 1e-5;
+
+fn is_non_unitary(op_id: u32) -> bool {
+    matches!(op_id, ops::RESET | ops::MZ | ops::MRESETZ | ops::MEVERYZ)
+}
+
+/// Rewrite a circuit so every gate is in the given native gate set.
+///
+/// Multi-qubit gates are first decomposed into single qubit gates and whichever of CZ, CX or RZZ
+/// the target supports (in that order of preference). Runs of single qubit gates on each qubit are
+/// then merged into one unitary and resynthesized with the shortest Euler decomposition the target
+/// supports. Measurement and reset ops are always kept.
+///
+/// Multi-controlled gates need no ancilla qubits, but their decomposition grows exponentially with
+/// the number of controls (a phase on k qubits becomes 2^k - 1 parity rotations).
+pub fn transpile(circuit: &Circuit, native: &[u32]) -> Result<Circuit, String> {
+    let entangler = [ops::CZ, ops::CX, ops::RZZ].into_iter().find(|g| native.contains(g));
+
+    let mut lowered: Vec<Op> = Vec::with_capacity(circuit.ops.len());
+    for op in &circuit.ops {
+        lower_op(op, native, entangler, &mut lowered)?;
+    }
+
+    let ops_out = resynthesize_1q(&lowered, native, circuit.qubit_count as u32)?;
+    Ok(circuit.with_ops(ops_out))
+}
+
+/// Check that two circuits apply the same unitary, up to global phase. Non-unitary ops are skipped.
+/// This builds the full unitary of each, so is only feasible for small circuits.
+pub fn check_equivalence(a: &Circuit, b: &Circuit) -> bool {
+    if a.qubit_count != b.qubit_count {
+        return false;
+    }
+    let (ua, ub) = (circuit_unitary(a), circuit_unitary(b));
+    let flat_a: Vec<Complex32> = ua.into_iter().flatten().collect();
+    let flat_b: Vec<Complex32> = ub.into_iter().flatten().collect();
+    proportional(&flat_a, &flat_b)
+}
+
+// True if a = e^(i * phi) * b for some phi
+fn proportional(a: &[Complex32], b: &[Complex32]) -> bool {
+    let (idx, largest) = b
+        .iter()
+        .enumerate()
+        .max_by(|(_, x), (_, y)| x.norm_sqr().total_cmp(&y.norm_sqr()))
+        .expect("Empty matrix");
+    if largest.abs() < EPSILON {
+        return false;
+    }
+    // a[idx] / b[idx]
+    let phase = (a[idx] * largest.conj()).scale(1.0 / largest.norm_sqr());
+    if (phase.abs() - 1.0).abs() > 1e-3 {
+        return false;
+    }
+    a.iter().zip(b).all(|(x, y)| (*x - phase * *y).abs() < 1e-3)
+}
+
+fn gate(op_id: u32, q1: u32, q2: u32, q3: u32, angle: f32) -> Op {
+    Op::new(op_id, q1, q2, q3, angle)
+}
+
+// Decompose an op into single qubit gates plus the target's entangler (or ops the target supports directly)
+fn lower_op(op: &Op, native: &[u32], entangler: Option<u32>, out: &mut Vec<Op>) -> Result<(), String> {
+    if is_non_unitary(op.op_id) || matrix_1q(op).is_some() || native.contains(&op.op_id) {
+        out.push(*op);
+        return Ok(());
+    }
+
+    let Some(entangler) = entangler else {
+        return Err("Target gate set has no two qubit gate (CZ, CX or RZZ)".to_string());
+    };
+    let mut lower = |op: Op| lower_op(&op, native, Some(entangler), out);
+    let (a, b, c) = (op.q1, op.q2, op.q3);
+
+    match op.op_id {
+        ops::CX => {
+            // CX = H(t) CZ H(t)
+            lower(gate(ops::H, b, 0, 0, 0.0))?;
+            lower(gate(ops::CZ, a, b, 0, 0.0))?;
+            lower(gate(ops::H, b, 0, 0, 0.0))?;
+        }
+        ops::CZ => {
+            if entangler == ops::CX {
+                lower(gate(ops::H, b, 0, 0, 0.0))?;
+                lower(gate(ops::CX, a, b, 0, 0.0))?;
+                lower(gate(ops::H, b, 0, 0, 0.0))?;
+            } else {
+                // CZ = RZ(a) RZ(b) RZZ(-pi/2), as b1 * b2 = (b1 + b2 - (b1 ^ b2)) / 2
+                lower(gate(ops::RZ, a, 0, 0, FRAC_PI_2))?;
+                lower(gate(ops::RZ, b, 0, 0, FRAC_PI_2))?;
+                lower(gate(ops::RZZ, a, b, 0, -FRAC_PI_2))?;
+            }
+        }
+        ops::RZZ => {
+            lower(gate(ops::CX, a, b, 0, 0.0))?;
+            lower(gate(ops::RZ, b, 0, 0, op.angle))?;
+            lower(gate(ops::CX, a, b, 0, 0.0))?;
+        }
+        ops::SWAP => {
+            lower(gate(ops::CX, a, b, 0, 0.0))?;
+            lower(gate(ops::CX, b, a, 0, 0.0))?;
+            lower(gate(ops::CX, a, b, 0, 0.0))?;
+        }
+        ops::CCX => {
+            // Standard 6 CX + 7 T decomposition, with a and b the controls and c the target
+            let sequence = [
+                (ops::H, c, 0), (ops::CX, b, c), (ops::T_ADJ, c, 0), (ops::CX, a, c),
+                (ops::T, c, 0), (ops::CX, b, c), (ops::T_ADJ, c, 0), (ops::CX, a, c),
+                (ops::T, b, 0), (ops::T, c, 0), (ops::H, c, 0), (ops::CX, a, b),
+                (ops::T, a, 0), (ops::T_ADJ, b, 0), (ops::CX, a, b),
+            ];
+            for (op_id, q1, q2) in sequence {
+                lower(gate(op_id, q1, q2, 0, 0.0))?;
+            }
+        }
+        ops::DIAGONAL => {
+            // Each parity term is a CX ladder onto the highest qubit of the mask around an RZ
+            for t in 0..op.term_count() {
+                let (mask, angle) = op.term(t);
+                let qubits: Vec<u32> = (0..32).filter(|q| mask & (1 << q) != 0).collect();
+                let (&last, rest) = qubits.split_last().expect("Empty diagonal term");
+                for &q in rest {
+                    lower(gate(ops::CX, q, last, 0, 0.0))?;
+                }
+                lower(gate(ops::RZ, last, 0, 0, angle))?;
+                for &q in rest.iter().rev() {
+                    lower(gate(ops::CX, q, last, 0, 0.0))?;
+                }
+            }
+        }
+        ops::PERMUTATION => {
+            for t in 0..op.term_count() {
+                let (ctrl, flip) = op.mask_pair(t);
+                let controls: Vec<u32> = (0..32).filter(|q| ctrl & (1 << q) != 0).collect();
+                for target in (0..32).filter(|q| flip & (1 << q) != 0) {
+                    match controls[..] {
+                        [] => lower(gate(ops::X, target, 0, 0, 0.0))?,
+                        [c1] => lower(gate(ops::CX, c1, target, 0, 0.0))?,
+                        [c1, c2] => lower(gate(ops::CCX, c1, c2, target, 0.0))?,
+                        _ => lower(Op::multi_controlled(ops::MCX, ctrl, ctrl, target, 0.0))?,
+                    }
+                }
+            }
+        }
+        ops::ISWAP | ops::RXX | ops::RYY | ops::CY | ops::CH | ops::CRX | ops::CRY | ops::CRZ | ops::CPHASE => {
+            let (gates, _) = decompose_2q(op).expect("Missing two qubit decomposition");
+            for g in gates {
+                lower(g)?;
+            }
+        }
+        op_id if ops::is_multi_controlled(op_id) => {
+            let (ctrl_mask, ctrl_values) = op.controls();
+            // Negative controls are positive controls between X gates
+            let negative: Vec<u32> = (0..32).filter(|q| ctrl_mask & !ctrl_values & (1 << q) != 0).collect();
+            for &q in &negative {
+                lower(gate(ops::X, q, 0, 0, 0.0))?;
+            }
+            let ctrl = 31 - ctrl_mask.leading_zeros();
+            let others = ctrl_mask & !(1 << ctrl);
+            match op_id {
+                ops::MCX if others == 0 => lower(gate(ops::CX, ctrl, a, 0, 0.0))?,
+                ops::MCZ if others == 0 => lower(gate(ops::CZ, ctrl, a, 0, 0.0))?,
+                ops::MCX => {
+                    lower(gate(ops::H, a, 0, 0, 0.0))?;
+                    lower(Op::multi_controlled(ops::MCZ, ctrl_mask, ctrl_mask, a, 0.0))?;
+                    lower(gate(ops::H, a, 0, 0, 0.0))?;
+                }
+                ops::MCZ | ops::MCPHASE if others != 0 => {
+                    // A phase when every qubit of the mask is set, as parity phases over its subsets:
+                    // b_1 * ... * b_m = sum over non-empty subsets S of (-1)^(|S| - 1) * parity(S) / 2^(m - 1)
+                    let mask = ctrl_mask | (1 << a);
+                    let angle = if op_id == ops::MCZ { PI } else { op.angle };
+                    let scale = angle / (1u32 << (mask.count_ones() - 1)) as f32;
+                    let mut terms = Vec::new();
+                    let mut subset = mask;
+                    while subset != 0 {
+                        let sign = if subset.count_ones() % 2 == 1 { 1.0 } else { -1.0 };
+                        terms.push((subset, sign * scale));
+                        subset = (subset - 1) & mask;
+                    }
+                    for chunk in terms.chunks(MAX_OP_TERMS) {
+                        let mut diag = gate(ops::DIAGONAL, 0, 0, 0, 0.0);
+                        chunk.iter().for_each(|&(term_mask, term_angle)| diag.push_term(term_mask, term_angle));
+                        lower(diag)?;
+                    }
+                }
+                _ => {
+                    // The single control decomposition, with its CX gates and the phase on the control
+                    // conditioned on the other controls too
+                    let (_, _, _, m) = controlled_1q_matrix(op).unwrap();
+                    for g in controlled_1q(&m, ctrl, a) {
+                        match g.op_id {
+                            ops::CX if others != 0 => lower(Op::multi_controlled(ops::MCX, ctrl_mask, ctrl_mask, a, 0.0))?,
+                            ops::RZ if g.q1 == ctrl && others != 0 => {
+                                lower(Op::multi_controlled(ops::MCPHASE, others, others, ctrl, g.angle))?
+                            }
+                            _ => lower(g)?,
+                        }
+                    }
+                }
+            }
+            for &q in &negative {
+                lower(gate(ops::X, q, 0, 0, 0.0))?;
+            }
+        }
+        ops::PAULI_EXP => {
+            let (gates, _) = decompose_pauli_exp(op).unwrap();
+            for g in gates {
+                lower(g)?;
+            }
+        }
+        ops::MATRIX2Q => return Err("Cannot decompose explicit two qubit matrices".to_string()),
+        other => return Err(format!("Cannot decompose op {}", other)),
+    }
+    Ok(())
+}
+
+/// Decomposition of a PAULI_EXP op into basis changes, a CX ladder and an RZ, as (ops, global phase).
+///
+/// Each X becomes Z under H and each Y under H * S_ADJ, and e^(-i * angle * Z...Z) is a CX ladder onto
+/// the last qubit around e^(-i * angle * Z) = e^(-i * angle) * RZ(2 * angle).
+pub(crate) fn decompose_pauli_exp(op: &Op) -> Option<(Vec<Op>, f32)> {
+    if op.op_id != ops::PAULI_EXP {
+        return None;
+    }
+    let (x_mask, z_mask) = op.pauli_masks();
+    let qubits: Vec<u32> = (0..32).filter(|q| (x_mask | z_mask) & (1 << q) != 0).collect();
+    let Some((&last, rest)) = qubits.split_last() else {
+        return Some((vec![], -op.angle));
+    };
+
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    for &q in &qubits {
+        let (x, z) = (x_mask & (1 << q) != 0, z_mask & (1 << q) != 0);
+        if x && z {
+            before.extend([gate(ops::S_ADJ, q, 0, 0, 0.0), gate(ops::H, q, 0, 0, 0.0)]);
+            after.extend([gate(ops::H, q, 0, 0, 0.0), gate(ops::S, q, 0, 0, 0.0)]);
+        } else if x {
+            before.push(gate(ops::H, q, 0, 0, 0.0));
+            after.push(gate(ops::H, q, 0, 0, 0.0));
+        }
+    }
+    let ladder: Vec<Op> = rest.iter().map(|&q| gate(ops::CX, q, last, 0, 0.0)).collect();
+
+    let mut gates = before;
+    gates.extend(ladder.iter().copied());
+    gates.push(gate(ops::RZ, last, 0, 0, 2.0 * op.angle));
+    gates.extend(ladder.iter().rev().copied());
+    gates.extend(after);
+    Some((gates, -op.angle))
+}
+
+/// Exact decompositions of the two qubit gates beyond CX, CZ, RZZ and SWAP, as (ops, global phase).
+/// ISWAP, RXX, RYY, CY and CH use CX, CZ, RZZ and single qubit gates, and the singly controlled
+/// rotations become multi-controlled ops with one control.
+pub(crate) fn decompose_2q(op: &Op) -> Option<(Vec<Op>, f32)> {
+    let (a, b, theta) = (op.q1, op.q2, op.angle);
+    let one_qubit = |op_id: u32, q: u32| gate(op_id, q, 0, 0, 0.0);
+    let controlled = |op_id: u32| Op::multi_controlled(op_id, 1 << a, 1 << a, b, theta);
+    // e^(-i * angle / 2 * XX) = H H e^(-i * angle / 2 * ZZ) H H, and RZZ = e^(i * angle / 2) e^(-i * angle / 2 * ZZ)
+    let rxx = |angle: f32| {
+        let h = [one_qubit(ops::H, a), one_qubit(ops::H, b)];
+        [&h[..], &[gate(ops::RZZ, a, b, 0, angle)], &h[..]].concat()
+    };
+    // YY = (S S) XX (S_ADJ S_ADJ)
+    let ryy = |angle: f32| {
+        let s_adj = [one_qubit(ops::S_ADJ, a), one_qubit(ops::S_ADJ, b)];
+        let s = [one_qubit(ops::S, a), one_qubit(ops::S, b)];
+        [&s_adj[..], &rxx(angle), &s[..]].concat()
+    };
+    let decomposition = match op.op_id {
+        ops::RXX => (rxx(theta), -theta / 2.0),
+        ops::RYY => (ryy(theta), -theta / 2.0),
+        // ISWAP = e^(i * pi / 4 * (XX + YY)), and XX and YY commute
+        ops::ISWAP => ([rxx(-FRAC_PI_2), ryy(-FRAC_PI_2)].concat(), FRAC_PI_2),
+        // Y = S X S_ADJ
+        ops::CY => (vec![one_qubit(ops::S_ADJ, b), gate(ops::CX, a, b, 0, 0.0), one_qubit(ops::S, b)], 0.0),
+        // H = RY(pi / 4) Z RY(-pi / 4)
+        ops::CH => (
+            vec![gate(ops::RY, b, 0, 0, -FRAC_PI_4), gate(ops::CZ, a, b, 0, 0.0), gate(ops::RY, b, 0, 0, FRAC_PI_4)],
+            0.0,
+        ),
+        ops::CRX => (vec![controlled(ops::MCRX)], 0.0),
+        ops::CRY => (vec![controlled(ops::MCRY)], 0.0),
+        ops::CRZ => (vec![controlled(ops::MCRZ)], 0.0),
+        ops::CPHASE => (vec![controlled(ops::MCPHASE)], 0.0),
+        _ => return None,
+    };
+    Some(decomposition)
+}
+
+pub(crate) fn matmul(a: &[Complex32; 4], b: &[Complex32; 4]) -> [Complex32; 4] {
+    [
+        a[0] * b[0] + a[1] * b[2],
+        a[0] * b[1] + a[1] * b[3],
+        a[2] * b[0] + a[3] * b[2],
+        a[2] * b[1] + a[3] * b[3],
+    ]
+}
+
+// Merge runs of single qubit gates and resynthesize them into the native gate set
+fn resynthesize_1q(lowered: &[Op], native: &[u32], qubit_count: u32) -> Result<Vec<Op>, String> {
+    let mut ops_out: Vec<Op> = Vec::with_capacity(lowered.len());
+    let mut pending: Vec<Option<[Complex32; 4]>> = vec![None; qubit_count as usize];
+
+    let flush = |q: u32, pending: &mut Vec<Option<[Complex32; 4]>>, ops_out: &mut Vec<Op>| {
+        if let Some(m) = pending[q as usize].take() {
+            ops_out.extend(synthesize_1q(&m, q, native)?);
+        }
+        Ok::<(), String>(())
+    };
+
+    for op in lowered {
+        if let Some(m) = matrix_1q(op) {
+            let prev = pending[op.q1 as usize].unwrap_or(matrix_1q(&gate(ops::ID, 0, 0, 0, 0.0)).unwrap());
+            pending[op.q1 as usize] = Some(matmul(&m, &prev));
+            continue;
+        }
+        let mask = op.qubit_mask();
+        for q in (0..qubit_count).filter(|q| mask & (1 << q) != 0) {
+            flush(q, &mut pending, &mut ops_out)?;
+        }
+        ops_out.push(*op);
+    }
+    for q in 0..qubit_count {
+        flush(q, &mut pending, &mut ops_out)?;
+    }
+    Ok(ops_out)
+}
+
+// Angles (beta, gamma, delta) such that m is proportional to RZ(beta) RY(gamma) RZ(delta), with RZ = diag(1, e^(i * angle))
+pub(crate) fn zyz_angles(m: &[Complex32; 4]) -> (f32, f32, f32) {
+    let (a, b, c, d) = (m[0], m[1], m[2], m[3]);
+    let gamma = 2.0 * c.abs().atan2(a.abs());
+    if c.abs() < EPSILON {
+        (0.0, 0.0, d.arg() - a.arg())
+    } else if a.abs() < EPSILON {
+        (0.0, PI, (-b).arg() - c.arg())
+    } else {
+        (c.arg() - a.arg(), gamma, (-b).arg() - a.arg())
+    }
+}
+
+// Express a phase rotation using the target's phase gates, for targets without RZ
+fn phase_gates(angle: f32, q: u32, native: &[u32]) -> Vec<Op> {
+    if native.contains(&ops::RZ) {
+        return vec![gate(ops::RZ, q, 0, 0, angle)];
+    }
+    let eighths = angle / FRAC_PI_4;
+    if (eighths - eighths.round()).abs() > 1e-4 {
+        return vec![gate(ops::RZ, q, 0, 0, angle)];
+    }
+    let gates: &[u32] = match (eighths.round() as i32).rem_euclid(8) {
+        1 => &[ops::T],
+        2 => &[ops::S],
+        3 => &[ops::S, ops::T],
+        4 => &[ops::Z],
+        5 => &[ops::Z, ops::T],
+        6 => &[ops::S_ADJ],
+        7 => &[ops::T_ADJ],
+        _ => &[],
+    };
+    gates.iter().map(|&op_id| gate(op_id, q, 0, 0, 0.0)).collect()
+}
+
+// Drop zero rotations and rewrite rotations the target can't run directly, where possible
+fn tidy(candidate: Vec<Op>, native: &[u32]) -> Vec<Op> {
+    let mut tidied = Vec::with_capacity(candidate.len());
+    for op in candidate {
+        let is_rotation = matches!(op.op_id, ops::RX | ops::RY | ops::RZ);
+        let angle = normalize_angle(op.angle);
+        if is_rotation && angle.abs() < EPSILON {
+            continue;
+        }
+        match op.op_id {
+            ops::RZ => tidied.extend(phase_gates(angle, op.q1, native)),
+            ops::RX if !native.contains(&ops::RX) && (angle - FRAC_PI_2).abs() < EPSILON => {
+                tidied.push(gate(ops::SX, op.q1, 0, 0, 0.0));
+            }
+            ops::RX if !native.contains(&ops::RX) && (angle + FRAC_PI_2).abs() < EPSILON => {
+                tidied.push(gate(ops::SX_ADJ, op.q1, 0, 0, 0.0));
+            }
+            _ if is_rotation => tidied.push(gate(op.op_id, op.q1, 0, 0, angle)),
+            _ => tidied.push(op),
+        }
+    }
+    tidied
+}
+
+fn synthesize_1q(m: &[Complex32; 4], q: u32, native: &[u32]) -> Result<Vec<Op>, String> {
+    let identity = matrix_1q(&gate(ops::ID, q, 0, 0, 0.0)).unwrap();
+    if proportional(m, &identity) {
+        return Ok(vec![]);
+    }
+    for op_id in FIXED_1Q_GATES.into_iter().filter(|g| native.contains(g)) {
+        let fixed = gate(op_id, q, 0, 0, 0.0);
+        if proportional(m, &matrix_1q(&fixed).unwrap()) {
+            return Ok(vec![fixed]);
+        }
+    }
+
+    let rz = |angle: f32| gate(ops::RZ, q, 0, 0, angle);
+    let rx = |angle: f32| gate(ops::RX, q, 0, 0, angle);
+    let ry = |angle: f32| gate(ops::RY, q, 0, 0, angle);
+    let h = gate(ops::H, q, 0, 0, 0.0);
+    let sx = gate(ops::SX, q, 0, 0, 0.0);
+
+    // Each candidate is in circuit order, so the rightmost matrix factor comes first
+    let (beta, gamma, delta) = zyz_angles(m);
+    // RY(gamma) = S RX(gamma) S_ADJ
+    let zxz = vec![rz(delta - FRAC_PI_2), rx(gamma), rz(beta + FRAC_PI_2)];
+    let hz = vec![rz(delta - FRAC_PI_2), h, rz(gamma), h, rz(beta + FRAC_PI_2)];
+    let zsx = vec![rz(delta), sx, rz(gamma + PI), sx, rz(beta + PI)];
+    let zyz = vec![rz(delta), ry(gamma), rz(beta)];
+    // H RZ H = RX and H RY H = RY(-gamma), so decompose H m H and conjugate back
+    let hmh = matmul(&matrix_1q(&h).unwrap(), &matmul(m, &matrix_1q(&h).unwrap()));
+    let (beta_x, gamma_x, delta_x) = zyz_angles(&hmh);
+    let xyx = vec![rx(delta_x), ry(-gamma_x), rx(beta_x)];
+
+    [zxz, zyz, xyx, zsx, hz]
+        .into_iter()
+        .map(|candidate| tidy(candidate, native))
+        .filter(|candidate| candidate.iter().all(|op| native.contains(&op.op_id)))
+        .min_by_key(|candidate| candidate.len())
+        .ok_or_else(|| format!("Cannot express a single qubit unitary on qubit {} in the target gate set", q))
+}
@@ -1,17 +1,43 @@
-use crate::circuit::{Circuit};
+use crate::circuit::{Circuit, DEFAULT_UNITARITY_TOLERANCE};
 use crate::shader_types::ops;
 use crate::gpu_context::GpuContext;
+use crate::passes;
+use crate::run_config::RunConfig;
+use crate::sampling::sorted_counts;
 use crate::shader_types::Result;
 
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::js_sys;
 
+/// Run the circuit, returning [entry_idx, probability] pairs for the likeliest basis states, or if
+/// `shots` is given, [bitstring, count] pairs for the sampled shots (most frequent first). Shots
+/// are reproducible for a given `seed`.
 #[wasm_bindgen]
-pub async fn run(code: &str) -> Vec<JsValue> {
-    let circ = Circuit::from_str(&code).expect("Failed to parse circuit");
+pub async fn run(code: &str, shots: Option<u32>, seed: Option<u64>) -> Vec<JsValue> {
+    let circ = Circuit::from_str(code).expect("Failed to parse circuit");
+    circ.check_unitarity(DEFAULT_UNITARITY_TOLERANCE).expect("Invalid circuit");
+    let circ = passes::compact_qubits(&circ);
+    let circ = passes::batch_diagonal_ops(&circ);
+    let circ = passes::batch_permutation_ops(&circ);
+    let circ = passes::fuse_1q_matrices(&circ);
 
     let mut gpu_context = GpuContext::new(circ).await;
+    if let Some(seed) = seed {
+        gpu_context.set_config(RunConfig { seed, ..Default::default() });
+    }
     gpu_context.create_resources();
+
+    if let Some(shots) = shots {
+        let counts = gpu_context.sample(shots as usize).await;
+        let return_val = js_sys::Array::new();
+        for (bits, count) in sorted_counts(&counts) {
+            let js_tuple = js_sys::Array::new();
+            js_tuple.push(&JsValue::from(bits));
+            js_tuple.push(&JsValue::from(count as u32));
+            return_val.push(&js_tuple);
+        }
+        return return_val.to_vec();
+    }
     let results = gpu_context.run().await;
 
     // Convert results to a JS value of an array, with elements being an array (tuple) of entry_idx and probability.
@@ -25,3 +51,12 @@ pub async fn run(code: &str) -> Vec<JsValue> {
     }
     return_val.to_vec()
 }
+
+/// A printable report of the gate counts and resources needed by the circuit, as written.
+#[wasm_bindgen]
+pub fn stats(code: &str) -> String {
+    match Circuit::from_str(code) {
+        Ok(circ) => circ.stats().to_string(),
+        Err(err) => err,
+    }
+}